@@ -0,0 +1,231 @@
+//! 比较工作窃取调度器和原来共用一个 `Mutex<mpsc::Receiver>` 的线程池的吞吐量
+//!
+//! 运行：`cargo bench -p a20_webserver --bench scheduler`
+//!
+//! 两种负载：
+//! - flat：主线程提交大量很短的任务
+//! - nested：每个任务在池内再提交若干子任务
+//!
+//! 各跑 1、4、8、32 个 worker，输出每秒完成的任务数。
+//! 单核机器上两者差不多，核数越多，共用一把锁的差距越明显。
+
+use a20_webserver::ThreadPool;
+
+use std::hint::black_box;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const FLAT_JOBS: usize = 200_000;
+const NESTED_PARENTS: usize = 2_000;
+const NESTED_CHILDREN: usize = 100;
+const ROUNDS: usize = 3;
+
+/// 原来的线程池，只去掉了每个任务的打印，避免测到的是 stdout 的锁
+mod legacy {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    enum Message {
+        NewJob(Job),
+        Terminate,
+    }
+
+    type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    pub struct ThreadPool {
+        workers: Vec<Option<thread::JoinHandle<()>>>,
+        sender: Mutex<mpsc::Sender<Message>>,
+    }
+
+    impl ThreadPool {
+        pub fn new(size: usize) -> ThreadPool {
+            let (sender, receiver) = mpsc::channel::<Message>();
+            let receiver = Arc::new(Mutex::new(receiver));
+
+            let workers = (0..size)
+                .map(|_| {
+                    let receiver = Arc::clone(&receiver);
+                    Some(thread::spawn(move || loop {
+                        let message = receiver.lock().unwrap().recv().unwrap();
+                        match message {
+                            Message::NewJob(job) => job(),
+                            Message::Terminate => break,
+                        }
+                    }))
+                })
+                .collect();
+
+            ThreadPool {
+                workers,
+                sender: Mutex::new(sender),
+            }
+        }
+
+        pub fn execute<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+        {
+            let sender = self.sender.lock().unwrap();
+            sender.send(Message::NewJob(Box::new(f))).unwrap();
+        }
+    }
+
+    impl Drop for ThreadPool {
+        fn drop(&mut self) {
+            let sender = self.sender.lock().unwrap();
+            for _ in &self.workers {
+                sender.send(Message::Terminate).unwrap();
+            }
+            drop(sender);
+
+            for worker in &mut self.workers {
+                if let Some(thread) = worker.take() {
+                    thread.join().unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// 两种线程池的共同接口
+trait Pool: Send + Sync + 'static {
+    fn submit(&self, job: Box<dyn FnOnce() + Send + 'static>);
+}
+
+impl Pool for ThreadPool {
+    fn submit(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        self.execute(job).unwrap();
+    }
+}
+
+impl Pool for legacy::ThreadPool {
+    fn submit(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        self.execute(job);
+    }
+}
+
+/// 计数到 0 时唤醒等待的线程
+struct Latch {
+    remaining: Mutex<usize>,
+    done: Condvar,
+}
+
+impl Latch {
+    fn new(count: usize) -> Arc<Latch> {
+        Arc::new(Latch {
+            remaining: Mutex::new(count),
+            done: Condvar::new(),
+        })
+    }
+
+    fn count_down(&self) {
+        let mut remaining = self.remaining.lock().unwrap();
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut remaining = self.remaining.lock().unwrap();
+        while *remaining > 0 {
+            remaining = self.done.wait(remaining).unwrap();
+        }
+    }
+}
+
+/// 一个很短的任务
+fn work(seed: usize) {
+    let mut x = seed as u64;
+    for _ in 0..64 {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(1);
+    }
+    black_box(x);
+}
+
+fn flat<P: Pool>(pool: &Arc<P>) -> usize {
+    let latch = Latch::new(FLAT_JOBS);
+    for i in 0..FLAT_JOBS {
+        let latch = Arc::clone(&latch);
+        pool.submit(Box::new(move || {
+            work(i);
+            latch.count_down();
+        }));
+    }
+    latch.wait();
+    FLAT_JOBS
+}
+
+fn nested<P: Pool>(pool: &Arc<P>) -> usize {
+    let latch = Latch::new(NESTED_PARENTS * NESTED_CHILDREN);
+    for i in 0..NESTED_PARENTS {
+        let latch = Arc::clone(&latch);
+        let inner = Arc::clone(pool);
+        pool.submit(Box::new(move || {
+            for j in 0..NESTED_CHILDREN {
+                let latch = Arc::clone(&latch);
+                inner.submit(Box::new(move || {
+                    work(i + j);
+                    latch.count_down();
+                }));
+            }
+        }));
+    }
+    latch.wait();
+    NESTED_PARENTS * (NESTED_CHILDREN + 1)
+}
+
+/// 跑 ROUNDS 轮，返回最好的一轮的吞吐量（任务数/秒）
+fn measure<P: Pool>(pool: P, workload: Workload<P>) -> f64 {
+    let pool = Arc::new(pool);
+    let mut best = Duration::MAX;
+    let mut jobs = 0;
+
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        jobs = workload(&pool);
+        best = best.min(start.elapsed());
+    }
+
+    // 任务里持有的 Arc 可能还没释放完，不能让线程池在 worker 线程上 drop
+    while Arc::strong_count(&pool) > 1 {
+        std::thread::yield_now();
+    }
+    jobs as f64 / best.as_secs_f64()
+}
+
+type Workload<P> = fn(&Arc<P>) -> usize;
+
+fn main() {
+    let workloads: [(&str, Workload<legacy::ThreadPool>, Workload<ThreadPool>); 2] = [
+        ("flat", flat::<legacy::ThreadPool>, flat::<ThreadPool>),
+        ("nested", nested::<legacy::ThreadPool>, nested::<ThreadPool>),
+    ];
+
+    // 线程池 drop 时会打印日志，结果放到最后一起输出
+    let mut results = Vec::new();
+    for (name, legacy_workload, stealing_workload) in workloads.iter() {
+        for &workers in &[1, 4, 8, 32] {
+            let legacy = measure(legacy::ThreadPool::new(workers), *legacy_workload);
+            let stealing = measure(ThreadPool::new(workers), *stealing_workload);
+            results.push((name, workers, legacy, stealing));
+        }
+    }
+
+    println!();
+    println!(
+        "{:<8} {:>7} {:>18} {:>18} {:>8}",
+        "workload", "workers", "mutex-receiver/s", "work-stealing/s", "speedup"
+    );
+    for (name, workers, legacy, stealing) in results {
+        println!(
+            "{:<8} {:>7} {:>18.0} {:>18.0} {:>7.2}x",
+            name,
+            workers,
+            legacy,
+            stealing,
+            stealing / legacy
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! 访问日志
+//!
+//! 每个处理完的请求记一行，格式可以是 Common Log Format、Combined Log Format 或 JSON。
+//! worker 只把记录放进一个有界的通道，格式化和写出都在后台线程中完成；
+//! 通道满时记录被丢弃并计数，worker 不会因为日志而阻塞。
+
+use crate::date::{clf_time, rfc3339_time};
+use crate::logger::json_escape;
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// 通道中最多积压多少条记录
+const CHANNEL_CAPACITY: usize = 8192;
+
+/// 访问日志的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessLogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    #[default]
+    Common,
+    /// Common 之后再加上 `"Referer" "User-Agent"`
+    Combined,
+    /// 每行一个 JSON 对象，还包含处理耗时
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessLogFormat::Common => "common",
+            AccessLogFormat::Combined => "combined",
+            AccessLogFormat::Json => "json",
+        }
+    }
+}
+
+/// 访问日志写到哪里
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+/// 按大小轮转日志文件
+///
+/// 文件写满 `max_size` 字节后改名为 `access.log.1`，原来的 `.1` 改名为 `.2`，依此类推，
+/// 最多保留 `keep` 个旧文件。`max_size` 为 0 表示不轮转。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    /// 默认不轮转，开启轮转时保留 5 个旧文件
+    fn default() -> Rotation {
+        Rotation {
+            max_size: 0,
+            keep: 5,
+        }
+    }
+}
+
+/// 一个请求的访问记录
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) client: Option<IpAddr>,
+    /// 通过认证的用户名
+    pub(crate) user: Option<String>,
+    pub(crate) time: SystemTime,
+    pub(crate) method: String,
+    /// 路径和查询字符串
+    pub(crate) target: String,
+    pub(crate) version: &'static str,
+    pub(crate) status: u16,
+    /// 写出的响应体字节数
+    pub(crate) bytes: u64,
+    pub(crate) referer: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) duration: Duration,
+}
+
+impl Entry {
+    /// 格式化成一行，不含换行符
+    fn format(&self, format: AccessLogFormat) -> String {
+        let client = self
+            .client
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let mut line = String::new();
+
+        match format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let bytes = match self.bytes {
+                    0 => "-".to_string(),
+                    n => n.to_string(),
+                };
+                let _ = write!(
+                    line,
+                    "{} - {} [{}] \"{} {} {}\" {} {}",
+                    client,
+                    clf_escape(self.user.as_deref().unwrap_or("-")),
+                    clf_time(self.time),
+                    self.method,
+                    clf_escape(&self.target),
+                    self.version,
+                    self.status,
+                    bytes
+                );
+                if format == AccessLogFormat::Combined {
+                    let _ = write!(
+                        line,
+                        " \"{}\" \"{}\"",
+                        clf_escape(self.referer.as_deref().unwrap_or("-")),
+                        clf_escape(self.user_agent.as_deref().unwrap_or("-"))
+                    );
+                }
+            }
+            AccessLogFormat::Json => {
+                let optional = |value: &Option<String>| match value {
+                    Some(v) => format!("\"{}\"", json_escape(v)),
+                    None => "null".to_string(),
+                };
+                let _ = write!(
+                    line,
+                    "{{\"time\":\"{}\",\"client\":\"{}\",\"user\":{},\"method\":\"{}\",\
+                     \"path\":\"{}\",\"version\":\"{}\",\"status\":{},\"bytes\":{},\"referer\":{},\
+                     \"user_agent\":{},\"duration_ms\":{:.3}}}",
+                    rfc3339_time(self.time),
+                    client,
+                    optional(&self.user),
+                    json_escape(&self.method),
+                    json_escape(&self.target),
+                    self.version,
+                    self.status,
+                    self.bytes,
+                    optional(&self.referer),
+                    optional(&self.user_agent),
+                    self.duration.as_secs_f64() * 1000.0
+                );
+            }
+        }
+        line
+    }
+}
+
+/// 访问日志，写出在后台线程中进行
+///
+/// drop 时等后台线程写完已经收到的记录。
+pub struct AccessLog {
+    sender: Option<SyncSender<Entry>>,
+    dropped: AtomicU64,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// 打开日志，文件无法打开时立即返回错误
+    pub fn new(
+        target: LogTarget,
+        format: AccessLogFormat,
+        rotation: Rotation,
+    ) -> io::Result<AccessLog> {
+        let output = match target {
+            LogTarget::Stdout => Output::Stdout(io::stdout()),
+            LogTarget::File(path) => Output::File(RotatingFile::open(path, rotation)?),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        let thread = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || run(receiver, format, output))?;
+
+        Ok(AccessLog {
+            sender: Some(sender),
+            dropped: AtomicU64::new(0),
+            thread: Some(thread),
+        })
+    }
+
+    /// 记录一个请求，后台线程跟不上时直接丢弃
+    pub(crate) fn log(&self, entry: Entry) {
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(entry) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 因为后台线程跟不上而丢弃的记录数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // 先关闭通道，后台线程写完剩下的记录后退出
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 后台线程：每收到一批记录写一次，批次之间 flush
+fn run(receiver: Receiver<Entry>, format: AccessLogFormat, mut output: Output) {
+    while let Ok(entry) = receiver.recv() {
+        let mut result = output.write_line(&entry.format(format));
+        while let Ok(entry) = receiver.try_recv() {
+            result = result.and_then(|_| output.write_line(&entry.format(format)));
+        }
+        if let Err(e) = result.and_then(|_| output.flush()) {
+            eprintln!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+enum Output {
+    Stdout(io::Stdout),
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => writeln!(stdout.lock(), "{}", line),
+            Output::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.writer.flush(),
+        }
+    }
+}
+
+/// 超过大小后自动轮转的文件
+struct RotatingFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    rotation: Rotation,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            writer: BufWriter::new(file),
+            size,
+            rotation,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        // 空文件不轮转，单行超过上限时也照样写进去
+        if self.rotation.max_size > 0 && self.size > 0 && self.size + len > self.rotation.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.rotation.keep).rev() {
+                rename_if_exists(&numbered(&self.path, i), &numbered(&self.path, i + 1))?;
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// `access.log` 的第 n 个旧文件 `access.log.n`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// 引号内的字段转义双引号、反斜杠和控制字符
+fn clf_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> Entry {
+        Entry {
+            client: Some("10.0.0.7".parse().unwrap()),
+            user: None,
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: "GET".to_string(),
+            target: "/search?q=\"rust\"".to_string(),
+            version: "HTTP/1.1",
+            status: 200,
+            bytes: 2326,
+            referer: Some("http://example.com/".to_string()),
+            user_agent: None,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_common_combined_and_json() {
+        let entry = entry();
+        assert_eq!(
+            entry.format(AccessLogFormat::Common),
+            r#"10.0.0.7 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=\"rust\" HTTP/1.1" 200 2326"#
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            r#"10.0.0.7 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=\"rust\" HTTP/1.1" 200 2326 "http://example.com/" "-""#
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Json),
+            r#"{"time":"2000-10-10T13:55:36.000Z","client":"10.0.0.7","user":null,"method":"GET","path":"/search?q=\"rust\"","version":"HTTP/1.1","status":200,"bytes":2326,"referer":"http://example.com/","user_agent":null,"duration_ms":1.500}"#
+        );
+    }
+
+    #[test]
+    fn logs_authenticated_users() {
+        let entry = Entry {
+            user: Some("ann \"a\"".to_string()),
+            ..entry()
+        };
+        assert!(entry
+            .format(AccessLogFormat::Common)
+            .starts_with(r#"10.0.0.7 - ann \"a\" [10/Oct/2000"#));
+        assert!(entry
+            .format(AccessLogFormat::Json)
+            .contains(r#""client":"10.0.0.7","user":"ann \"a\"","method""#));
+    }
+
+    #[test]
+    fn rotates_files_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rotate.log");
+        let line = entry().format(AccessLogFormat::Common);
+        let rotation = Rotation {
+            // 刚好放得下两行
+            max_size: 2 * (line.len() as u64 + 1),
+            keep: 2,
+        };
+
+        let log = AccessLog::new(
+            LogTarget::File(path.clone()),
+            AccessLogFormat::Common,
+            rotation,
+        )
+        .unwrap();
+        for _ in 0..7 {
+            log.log(entry());
+        }
+        drop(log);
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&numbered(&path, 1)), 2);
+        assert_eq!(lines(&numbered(&path, 2)), 2);
+        assert!(!numbered(&path, 3).exists());
+    }
+}
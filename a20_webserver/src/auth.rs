@@ -0,0 +1,487 @@
+//! HTTP Basic 认证
+//!
+//! 用户和密码保存在 htpasswd 格式的文件中，每行一个 `用户名:哈希`，
+//! 空行和 `#` 开头的行被忽略。支持两种哈希：
+//!
+//! - bcrypt（`$2y$`、`$2a$`、`$2b$`），即 `htpasswd -B` 生成的文件可以直接使用；
+//! - [`hash_password`] 生成的 `$pbkdf2-sha256$<迭代次数>$<盐>$<哈希>`
+//!   （盐和哈希为不带填充的 base64）。这是本库自己的格式，Apache 等其他
+//!   实现不认识它。
+//!
+//! 其他格式（`$apr1$`、SHA1、crypt 和明文）会在加载时报错。
+//!
+//! ```no_run
+//! use a20_webserver::auth::{BasicAuth, Htpasswd};
+//! use a20_webserver::http::Request;
+//! use a20_webserver::middleware::Stack;
+//! use a20_webserver::response::Response;
+//! use a20_webserver::router::Router;
+//!
+//! let users = Htpasswd::load("users.htpasswd").unwrap();
+//! let admin = Stack::new(|req: &mut Request| {
+//!     Response::text(200, &format!("hello {}", req.user.as_deref().unwrap_or("")))
+//! })
+//! .with(BasicAuth::new("admin", users));
+//! let router = Router::new().get("/admin", admin);
+//! ```
+
+use crate::http::Request;
+use crate::middleware::{Middleware, Next};
+use crate::response::Response;
+
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// [`hash_password`] 使用的 PBKDF2 迭代次数
+pub const DEFAULT_ITERATIONS: u32 = 10_000;
+
+const SCHEME: &str = "pbkdf2-sha256";
+
+const BCRYPT_PREFIXES: [&str; 3] = ["2a", "2b", "2y"];
+
+/// 加盐的密码哈希
+enum PasswordHash {
+    Pbkdf2 {
+        iterations: u32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    /// `htpasswd -B` 生成的 `$2y$`，以及 `$2a$`、`$2b$` 格式，原样保存
+    Bcrypt { cost: u32, hash: String },
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Result<PasswordHash, &'static str> {
+        let parts: Vec<&str> = s.split('$').collect();
+        if parts.len() == 4 && parts[0].is_empty() && BCRYPT_PREFIXES.contains(&parts[1]) {
+            return PasswordHash::parse_bcrypt(s, &parts);
+        }
+        if parts.len() != 5 || !parts[0].is_empty() || parts[1] != SCHEME {
+            return Err(
+                "unsupported hash, expected bcrypt ($2y$) or $pbkdf2-sha256$<iterations>$<salt>$<hash>",
+            );
+        }
+        let iterations = match parts[2].parse() {
+            Ok(n) if n > 0 => n,
+            _ => return Err("invalid iteration count"),
+        };
+        let salt = STANDARD_NO_PAD
+            .decode(parts[3])
+            .map_err(|_| "invalid salt")?;
+        let hash = STANDARD_NO_PAD
+            .decode(parts[4])
+            .map_err(|_| "invalid hash")?;
+        if hash.is_empty() {
+            return Err("invalid hash");
+        }
+
+        Ok(PasswordHash::Pbkdf2 {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn parse_bcrypt(s: &str, parts: &[&str]) -> Result<PasswordHash, &'static str> {
+        let cost = match parts[2].parse() {
+            Ok(n) if parts[2].len() == 2 && (4..=31).contains(&n) => n,
+            _ => return Err("invalid bcrypt cost"),
+        };
+        // 22 个字符的盐加 31 个字符的哈希，bcrypt 自己的 base64 字母表
+        let valid = parts[3].len() == 53
+            && parts[3]
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/');
+        if !valid {
+            return Err("invalid bcrypt hash");
+        }
+
+        Ok(PasswordHash::Bcrypt {
+            cost,
+            hash: s.to_string(),
+        })
+    }
+
+    /// 不同的哈希中，方案和代价都相同的计算耗时相同
+    fn cost(&self) -> Cost {
+        match self {
+            PasswordHash::Pbkdf2 { iterations, .. } => Cost::Pbkdf2(*iterations),
+            PasswordHash::Bcrypt { cost, .. } => Cost::Bcrypt(*cost),
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Pbkdf2 {
+                iterations,
+                salt,
+                hash: expected,
+            } => {
+                let mut hash = vec![0; expected.len()];
+                derive(password, salt, *iterations, &mut hash);
+                constant_time_eq(&hash, expected)
+            }
+            PasswordHash::Bcrypt { hash, .. } => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordHash::Pbkdf2 {
+                iterations,
+                salt,
+                hash,
+            } => write!(
+                f,
+                "${}${}${}${}",
+                SCHEME,
+                iterations,
+                STANDARD_NO_PAD.encode(salt),
+                STANDARD_NO_PAD.encode(hash)
+            ),
+            PasswordHash::Bcrypt { hash, .. } => f.write_str(hash),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Cost {
+    Pbkdf2(u32),
+    Bcrypt(u32),
+}
+
+impl Cost {
+    /// 与这个代价的真实哈希耗时相同、但不会匹配任何密码的哈希
+    fn dummy(self) -> PasswordHash {
+        match self {
+            Cost::Pbkdf2(iterations) => PasswordHash::Pbkdf2 {
+                iterations,
+                salt: vec![0; 16],
+                hash: vec![0; 32],
+            },
+            Cost::Bcrypt(cost) => PasswordHash::Bcrypt {
+                cost,
+                hash: bcrypt::hash_with_salt("", cost, [0; 16])
+                    .expect("bcrypt cost is checked when parsing")
+                    .format_for_version(bcrypt::Version::TwoY),
+            },
+        }
+    }
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32, out: &mut [u8]) {
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, out);
+}
+
+/// 长度相同时比较所有字节，耗时不随第一个不同的位置变化
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 用随机的盐生成 htpasswd 文件中的密码哈希
+///
+/// 生成的是本库自己的 pbkdf2 格式，需要其他服务器也能读的文件时用 `htpasswd -B`。
+pub fn hash_password(password: &str) -> String {
+    hash_password_with(password, DEFAULT_ITERATIONS)
+}
+
+/// 与 hash_password 相同，但指定迭代次数，至少为 1
+pub fn hash_password_with(password: &str, iterations: u32) -> String {
+    let mut salt = vec![0; 16];
+    getrandom::getrandom(&mut salt).expect("failed to read random bytes from the OS");
+    let mut hash = vec![0; 32];
+    let iterations = iterations.max(1);
+    derive(password, &salt, iterations, &mut hash);
+
+    PasswordHash::Pbkdf2 {
+        iterations,
+        salt,
+        hash,
+    }
+    .to_string()
+}
+
+/// 读取 htpasswd 文件时的错误
+#[derive(Debug)]
+pub enum HtpasswdError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// 第 line 行（从 1 开始）格式不对
+    InvalidLine {
+        line: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for HtpasswdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HtpasswdError::Io { path, error } => {
+                write!(f, "cannot read {}: {}", path.display(), error)
+            }
+            HtpasswdError::InvalidLine { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl Error for HtpasswdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HtpasswdError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// htpasswd 文件中的全部用户
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+    /// 用户不存在时也计算一次哈希，让响应时间不暴露用户名是否存在，
+    /// 代价和文件中最常见的哈希相同
+    dummy: PasswordHash,
+}
+
+impl Htpasswd {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Htpasswd, HtpasswdError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| HtpasswdError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Htpasswd::parse(&text)
+    }
+
+    /// 解析 htpasswd 文件的内容
+    pub fn parse(text: &str) -> Result<Htpasswd, HtpasswdError> {
+        let mut users = HashMap::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let invalid = |reason: &str| HtpasswdError::InvalidLine {
+                line: i + 1,
+                reason: reason.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected `user:hash`"))?;
+            if user.is_empty() {
+                return Err(invalid("empty user name"));
+            }
+            let hash = PasswordHash::parse(hash).map_err(invalid)?;
+            if users.insert(user.to_string(), hash).is_some() {
+                return Err(invalid(&format!("duplicate user `{}`", user)));
+            }
+        }
+
+        // 按文件中最常见的方案和代价计算，没有用户时按 hash_password 的默认值
+        let mut counts = HashMap::new();
+        for hash in users.values() {
+            *counts.entry(hash.cost()).or_insert(0) += 1;
+        }
+        let dummy = counts
+            .into_iter()
+            .max_by_key(|&(_, count)| count)
+            .map_or(Cost::Pbkdf2(DEFAULT_ITERATIONS), |(cost, _)| cost)
+            .dummy();
+
+        Ok(Htpasswd { users, dummy })
+    }
+
+    /// 用户存在且密码正确
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                self.dummy.verify(password);
+                false
+            }
+        }
+    }
+}
+
+/// HTTP Basic 认证的中间件
+///
+/// 认证通过后把用户名放进 `Request::user`，否则回应 401 和 `WWW-Authenticate`。
+/// 密码以明文传输，应该只在 HTTPS 之后或可信的内网中使用。
+pub struct BasicAuth {
+    realm: String,
+    users: Arc<Htpasswd>,
+}
+
+impl BasicAuth {
+    /// users 可以是 `Htpasswd` 或多个路由共用的 `Arc<Htpasswd>`
+    pub fn new<U: Into<Arc<Htpasswd>>>(realm: &str, users: U) -> BasicAuth {
+        BasicAuth {
+            realm: realm.to_string(),
+            users: users.into(),
+        }
+    }
+
+    fn challenge(&self) -> Response {
+        let realm = self.realm.replace(['"', '\\'], "");
+        Response::text(401, "401 Unauthorized").with_header(
+            "WWW-Authenticate",
+            &format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+        )
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        match credentials(request) {
+            Some((user, password)) if self.users.verify(&user, &password) => {
+                request.user = Some(user);
+                next.run(request)
+            }
+            _ => self.challenge(),
+        }
+    }
+}
+
+/// 从 `Authorization: Basic ...` 中取出用户名和密码
+fn credentials(request: &Request) -> Option<(String, String)> {
+    let header = request.header("authorization")?.trim();
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Stack;
+    use crate::router::Handler;
+
+    fn get(authorization: Option<&str>) -> Request {
+        let mut raw = "GET /admin HTTP/1.1\r\n".to_string();
+        if let Some(value) = authorization {
+            raw.push_str(&format!("Authorization: {}\r\n", value));
+        }
+        raw.push_str("\r\n");
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn hashes_are_salted_and_verifiable() {
+        let a = hash_password_with("secret", 10);
+        let b = hash_password_with("secret", 10);
+        assert!(a.starts_with("$pbkdf2-sha256$10$"));
+        assert_ne!(a, b);
+
+        let users = Htpasswd::parse(&format!("# admins\n\nann:{}\nbob:{}\n", a, b)).unwrap();
+        assert!(users.verify("ann", "secret"));
+        assert!(users.verify("bob", "secret"));
+        assert!(!users.verify("ann", "Secret"));
+        assert!(!users.verify("carl", "secret"));
+    }
+
+    // 代价为 4 的 bcrypt 哈希，密码是 secret
+    const BCRYPT: &str = "$2y$04$yg8.FrAxqzge/n8wLy5TlOgRRlZ4CGT0ddtRSzeb8ABW2/CBxk29u";
+
+    #[test]
+    fn accepts_bcrypt_hashes() {
+        let text = format!("ann:{}\nbob:{}", BCRYPT, BCRYPT.replacen("2y", "2b", 1));
+        let users = Htpasswd::parse(&text).unwrap();
+        assert!(users.verify("ann", "secret"));
+        assert!(users.verify("bob", "secret"));
+        assert!(!users.verify("ann", "Secret"));
+        assert!(!users.verify("carl", "secret"));
+        assert_eq!(users.users["ann"].to_string(), BCRYPT);
+    }
+
+    #[test]
+    fn dummy_hash_matches_the_most_common_cost() {
+        let pbkdf2 = hash_password_with("x", 3);
+        let text = format!("ann:{}\nbob:{}\ncarl:{}", BCRYPT, BCRYPT, pbkdf2);
+        let users = Htpasswd::parse(&text).unwrap();
+        assert!(users.dummy.cost() == Cost::Bcrypt(4));
+        assert!(bcrypt::verify("", &users.dummy.to_string()).unwrap());
+
+        let users = Htpasswd::parse(&format!("ann:{}", pbkdf2)).unwrap();
+        assert!(users.dummy.cost() == Cost::Pbkdf2(3));
+        let users = Htpasswd::parse("").unwrap();
+        assert!(users.dummy.cost() == Cost::Pbkdf2(DEFAULT_ITERATIONS));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let hash = hash_password_with("x", 1);
+        let cases = [
+            ("ann", "line 1: expected `user:hash`"),
+            ("ann:$apr1$abc$def", "line 1: unsupported hash"),
+            (
+                "ann:$pbkdf2-sha256$0$AAAA$AAAA",
+                "line 1: invalid iteration count",
+            ),
+            ("ann:$pbkdf2-sha256$1$!!$AAAA", "line 1: invalid salt"),
+            ("ann:$2y$4$abc", "line 1: invalid bcrypt cost"),
+            ("ann:$2y$32$abc", "line 1: invalid bcrypt cost"),
+            ("ann:$2y$04$abc", "line 1: invalid bcrypt hash"),
+        ];
+        for (text, expected) in cases.iter() {
+            let err = Htpasswd::parse(text).err().unwrap().to_string();
+            assert!(err.starts_with(expected), "{}", err);
+        }
+        let err = Htpasswd::parse(&format!("ann:{}\nann:{}", hash, hash))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2: duplicate user `ann`");
+    }
+
+    #[test]
+    fn guards_routes_with_basic_auth() {
+        let users = Htpasswd::parse(&format!("ann:{}", hash_password_with("pa:ss", 10))).unwrap();
+        let handler =
+            Stack::new(|req: &mut Request| Response::text(200, req.user.as_deref().unwrap_or("")))
+                .with(BasicAuth::new("tools \"x\"", users));
+
+        let mut request = get(Some(&basic("ann", "pa:ss")));
+        let response = handler.handle(&mut request);
+        assert_eq!(response.status, 200);
+        assert_eq!(request.user.as_deref(), Some("ann"));
+
+        let rejected = [
+            None,
+            Some(basic("ann", "wrong")),
+            Some(basic("bob", "pa:ss")),
+            Some("Bearer abc".to_string()),
+            Some("Basic !!!".to_string()),
+        ];
+        for authorization in rejected.iter() {
+            let response = handler.handle(&mut get(authorization.as_deref()));
+            assert_eq!(response.status, 401);
+            assert_eq!(
+                response.header("www-authenticate"),
+                Some("Basic realm=\"tools x\", charset=\"UTF-8\"")
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::logger::{self, Logger};
+use crate::{
+    CancellationToken, LogFormat, LogLevel, OverflowPolicy, PoolCreationError, ThreadPool,
+};
+
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// 线程池的构建器
+///
+/// 线程池启动时创建 `min_threads` 个 worker，任务积压时逐个增加到 `max_threads`，
+/// 多出来的 worker 空闲超过 `keep_alive` 后退出。
+///
+/// ```
+/// use a20_webserver::ThreadPool;
+/// use std::time::Duration;
+///
+/// let pool = ThreadPool::builder()
+///     .min_threads(2)
+///     .max_threads(8)
+///     .keep_alive(Duration::from_secs(30))
+///     .name("http")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    pub(crate) min_threads: usize,
+    pub(crate) max_threads: usize,
+    pub(crate) keep_alive: Duration,
+    pub(crate) name: String,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) logger: Logger,
+    pub(crate) token: Option<CancellationToken>,
+}
+
+impl ThreadPoolBuilder {
+    /// 默认最少 1 个、最多 CPU 核数个 worker，空闲 60 秒退出，队列不限长度
+    pub fn new() -> ThreadPoolBuilder {
+        let cpus = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+
+        ThreadPoolBuilder {
+            min_threads: 1,
+            max_threads: cpus,
+            keep_alive: Duration::from_secs(60),
+            name: "worker".to_string(),
+            stack_size: None,
+            queue_capacity: None,
+            overflow_policy: OverflowPolicy::Block,
+            logger: logger::stdout_logger(LogFormat::Text),
+            token: None,
+        }
+    }
+
+    /// 始终保留的 worker 数
+    pub fn min_threads(mut self, n: usize) -> ThreadPoolBuilder {
+        self.min_threads = n;
+        self
+    }
+
+    /// worker 数的上限
+    pub fn max_threads(mut self, n: usize) -> ThreadPoolBuilder {
+        self.max_threads = n;
+        self
+    }
+
+    /// 固定 worker 数，相当于同时设置 min_threads 和 max_threads
+    pub fn threads(self, n: usize) -> ThreadPoolBuilder {
+        self.min_threads(n).max_threads(n)
+    }
+
+    /// 超出 min_threads 的 worker 空闲多久后退出
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// 线程名前缀，线程名为 "{name}-{id}"，会出现在 ps 和 panic 信息中
+    pub fn name(mut self, name: &str) -> ThreadPoolBuilder {
+        self.name = name.to_string();
+        self
+    }
+
+    /// worker 线程的栈大小，单位为字节
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// 任务队列的容量，不设置时不限长度
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// 队列满时的处理方式，默认为 `OverflowPolicy::Block`
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// 接收线程池日志的回调，默认把 Info 及以上的日志打印到标准输出
+    ///
+    /// 通过 `Server::pool` 传给服务器时，服务器自己的消息（handler 附带的错误、
+    /// 收到关闭信号、连接出错等）也写到这里。传入 `|_, _| {}` 可以关闭日志。
+    ///
+    /// ```
+    /// use a20_webserver::{LogLevel, ThreadPool};
+    ///
+    /// let pool = ThreadPool::builder()
+    ///     .logger(|level, message| {
+    ///         if level >= LogLevel::Warn {
+    ///             eprintln!("[{}] {}", level, message);
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn logger<F>(mut self, logger: F) -> ThreadPoolBuilder
+    where
+        F: Fn(LogLevel, fmt::Arguments) + Send + Sync + 'static,
+    {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// 使用默认的标准输出日志，按 format 格式输出，会替换掉 `logger` 设置的回调
+    pub fn log_format(mut self, format: LogFormat) -> ThreadPoolBuilder {
+        self.logger = logger::stdout_logger(format);
+        self
+    }
+
+    /// 线程池使用的取消信号，默认为每个线程池新建一个
+    ///
+    /// 传入外部的 token 后，在外部取消它和调用 `ThreadPool::shutdown_now` 一样会通知所有任务。
+    pub fn cancellation_token(mut self, token: CancellationToken) -> ThreadPoolBuilder {
+        self.token = Some(token);
+        self
+    }
+
+    /// 按当前设置创建线程池
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.max_threads == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.min_threads > self.max_threads {
+            return Err(PoolCreationError::MinExceedsMax {
+                min: self.min_threads,
+                max: self.max_threads,
+            });
+        }
+
+        ThreadPool::from_builder(self)
+    }
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("min_threads", &self.min_threads)
+            .field("max_threads", &self.max_threads)
+            .field("keep_alive", &self.keep_alive)
+            .field("name", &self.name)
+            .field("stack_size", &self.stack_size)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("overflow_policy", &self.overflow_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
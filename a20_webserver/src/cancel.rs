@@ -0,0 +1,262 @@
+use crate::{lock, ExecuteError, ThreadPool};
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// 协作式取消的信号
+///
+/// 克隆出来的 token 共享同一个状态，任何一个调用 `cancel` 之后所有克隆都能看到。
+/// 长时间运行的任务应该定期检查 `is_cancelled`，或者用 `wait_timeout` 代替 `thread::sleep`。
+///
+/// ```
+/// use a20_webserver::CancellationToken;
+/// use std::time::Duration;
+///
+/// let token = CancellationToken::new();
+/// let worker = token.clone();
+///
+/// token.cancel();
+/// // 已经被取消，立即返回 true
+/// assert!(worker.wait_timeout(Duration::from_secs(60)));
+/// ```
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// 只用来配合 Condvar 等待
+    lock: Mutex<()>,
+    cancelled_changed: Condvar,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// 发出取消信号，唤醒所有正在 `wait_timeout` 的线程，重复调用没有影响
+    pub fn cancel(&self) {
+        let _guard = lock(&self.inner.lock);
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.cancelled_changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// 最多等待 timeout，被取消时提前返回
+    ///
+    /// 返回 true 表示已经被取消，false 表示等满了 timeout。
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut guard = lock(&self.inner.lock);
+
+        while !self.is_cancelled() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = self
+                .inner
+                .cancelled_changed
+                .wait_timeout(guard, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    /// 返回一个守卫，守卫被丢弃时取消这个 token
+    ///
+    /// 适合把 token 和某个作用域绑在一起：作用域因为返回或 panic 结束时，
+    /// 用这个 token 的任务都会收到取消信号。
+    pub fn drop_guard(self) -> DropGuard {
+        DropGuard { token: Some(self) }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// `CancellationToken::drop_guard` 返回的守卫，被丢弃时取消 token
+#[derive(Debug)]
+pub struct DropGuard {
+    token: Option<CancellationToken>,
+}
+
+impl DropGuard {
+    /// 解除守卫，不再取消 token，并把 token 交还给调用方
+    pub fn disarm(mut self) -> CancellationToken {
+        self.token.take().unwrap()
+    }
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            token.cancel();
+        }
+    }
+}
+
+impl ThreadPool {
+    /// 线程池的取消信号，线程池 drop 或调用 `shutdown_now` 时被取消
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shared.token.clone()
+    }
+
+    /// 执行一个接收取消信号的任务
+    ///
+    /// 任务拿到的是线程池的 token，线程池关闭时任务可以据此尽快结束，不用等它自然跑完。
+    pub fn execute_with_token<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce(CancellationToken) + Send + 'static,
+    {
+        let token = self.cancellation_token();
+        self.execute(move || f(token))
+    }
+
+    /// 立即关闭线程池
+    ///
+    /// 取消线程池的 token 和所有定时任务，不再接受新任务，并丢弃所有还没开始执行的任务，
+    /// 返回丢弃的任务数。正在执行的任务会收到取消信号，但要由它们自己决定何时结束；
+    /// 线程池 drop 时仍然会等待它们。
+    pub fn shutdown_now(&self) -> usize {
+        // 先关闭队列再停定时器：定时器线程可能正阻塞在满了的队列上等空位，
+        // 要靠 close 唤醒它，否则 stop 会一直等下去
+        self.shared.queue.close();
+
+        // 先取走排队的任务再发取消信号，免得被取消的任务结束后 worker 又拿到新任务。
+        // 任务在这里被释放，JobHandle 会得到 JoinError::Cancelled
+        let discarded = self.shared.queue.drain();
+        self.shared.token.cancel();
+        self.timer.stop();
+
+        let count = discarded.len();
+        drop(discarded);
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn wait_timeout_returns_early_when_cancelled() {
+        let token = CancellationToken::new();
+        assert!(!token.wait_timeout(Duration::from_millis(10)));
+
+        let other = token.clone();
+        let waiter = thread::spawn(move || {
+            let start = Instant::now();
+            (other.wait_timeout(Duration::from_secs(30)), start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(20));
+        token.cancel();
+
+        let (cancelled, waited) = waiter.join().unwrap();
+        assert!(cancelled);
+        assert!(waited < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn drop_guard_cancels_unless_disarmed() {
+        let token = CancellationToken::new();
+        let kept = token.clone().drop_guard().disarm();
+        assert!(!kept.is_cancelled());
+
+        {
+            let _guard = token.clone().drop_guard();
+        }
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn shutdown_now_cancels_running_jobs_and_discards_queued_ones() {
+        let pool = ThreadPool::new(1);
+        let (started, ready) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute_with_token(move |token| {
+            started.send(()).unwrap();
+            // 相当于 /sleep，被取消时提前结束
+            let cancelled = token.wait_timeout(Duration::from_secs(30));
+            tx.send(cancelled).unwrap();
+        })
+        .unwrap();
+        ready.recv().unwrap();
+
+        let queued = pool.spawn(|| 1).unwrap();
+        let start = Instant::now();
+        assert_eq!(pool.shutdown_now(), 1);
+
+        assert!(rx.recv().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(queued.join(), Err(crate::JoinError::Cancelled));
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::ShuttingDown));
+    }
+
+    #[test]
+    fn shutdown_does_not_wait_for_a_timer_blocked_on_a_full_queue() {
+        for drop_pool in [false, true] {
+            let pool = ThreadPool::bounded(1, 1, crate::OverflowPolicy::Block).unwrap();
+            let (started, ready) = mpsc::channel();
+            pool.execute_with_token(move |token| {
+                started.send(()).unwrap();
+                token.wait_timeout(Duration::from_secs(30));
+            })
+            .unwrap();
+            ready.recv().unwrap();
+            pool.execute(|| {}).unwrap();
+
+            // 队列已满，定时器线程提交任务时被阻塞
+            pool.execute_at_fixed_rate(Duration::from_millis(1), || {})
+                .unwrap();
+            thread::sleep(Duration::from_millis(50));
+
+            let (done, finished) = mpsc::channel();
+            thread::spawn(move || {
+                if drop_pool {
+                    drop(pool);
+                } else {
+                    pool.shutdown_now();
+                }
+                done.send(()).unwrap();
+            });
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[test]
+    fn dropping_the_pool_cancels_its_token() {
+        let pool = ThreadPool::new(1);
+        let token = pool.cancellation_token();
+        let (started, ready) = mpsc::channel();
+
+        pool.execute_with_token(move |token| {
+            started.send(()).unwrap();
+            token.wait_timeout(Duration::from_secs(30));
+        })
+        .unwrap();
+        ready.recv().unwrap();
+
+        let start = Instant::now();
+        drop(pool);
+        assert!(token.is_cancelled());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
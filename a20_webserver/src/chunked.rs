@@ -0,0 +1,515 @@
+//! `Transfer-Encoding: chunked` 的编码和解码
+//!
+//! 长度事先不知道的响应体通过 [`ChunkedBody`] 一块一块地生成，写出时由 [`ChunkedWriter`]
+//! 加上分块的格式；请求体的解码在解析请求时自动完成，trailer 放在 `Request::trailers` 中。
+
+use crate::http::{insert_header, parse_header_line, Limits, ParseError, MAX_LINE_LEN};
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+
+/// 分块响应体的数据来源
+///
+/// 闭包 `FnMut(&mut Vec<u8>) -> io::Result<bool>` 也实现了这个 trait，
+/// 适合逐条生成的输出，例如搜索结果或日志。
+/// `next_chunk` 总是在线程池的 worker 上调用，可以阻塞等待数据，等待期间占用一个 worker；
+/// 它 panic 时只关闭这一个连接。
+///
+/// ```no_run
+/// use a20_webserver::response::Response;
+///
+/// let mut lines = vec!["first\n", "second\n"].into_iter();
+/// let response = Response::new(200).with_chunked(move |buf: &mut Vec<u8>| {
+///     match lines.next() {
+///         Some(line) => {
+///             buf.extend_from_slice(line.as_bytes());
+///             Ok(true)
+///         }
+///         None => Ok(false),
+///     }
+/// });
+/// ```
+pub trait ChunkedBody: Send {
+    /// 把下一块数据追加到 buf 中，没有更多数据时返回 `Ok(false)`
+    ///
+    /// 返回 false 时 buf 中追加的数据仍然会被发送。每一块都会立即发给客户端。
+    fn next_chunk(&mut self, buf: &mut Vec<u8>) -> io::Result<bool>;
+
+    /// 响应体发送完之后附加的 trailer，默认没有
+    ///
+    /// 按规范应该在响应的 `Trailer` 头中事先列出这些名字。
+    fn trailers(&mut self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+impl<F> ChunkedBody for F
+where
+    F: FnMut(&mut Vec<u8>) -> io::Result<bool> + Send,
+{
+    fn next_chunk(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        self(buf)
+    }
+}
+
+/// 把 reader 中的数据按读到的大小分块发送，用于长度未知的流
+pub(crate) struct ReaderChunks<R> {
+    pub(crate) reader: R,
+}
+
+impl<R: Read + Send> ChunkedBody for ReaderChunks<R> {
+    fn next_chunk(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let start = buf.len();
+        buf.resize(start + 8 * 1024, 0);
+        let result = self.reader.read(&mut buf[start..]);
+        buf.truncate(start + *result.as_ref().unwrap_or(&0));
+        Ok(result? > 0)
+    }
+}
+
+/// 以分块格式写出数据：每次 `write` 成为一块，`finish` 写出结束块和 trailer
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    /// 写出长度为 0 的结束块和 trailer，返回内部的 writer
+    pub fn finish(mut self, trailers: &[(String, String)]) -> io::Result<W> {
+        let mut end = Vec::new();
+        encode_last(trailers, &mut end);
+        self.inner.write_all(&end)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // 空块表示结束，不能在这里写出
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut chunk = Vec::with_capacity(buf.len() + 20);
+        encode_chunk(buf, &mut chunk);
+        self.inner.write_all(&chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 把一块数据加上分块格式追加到 out，data 不能为空
+pub(crate) fn encode_chunk(data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// 结束块和 trailer
+pub(crate) fn encode_last(trailers: &[(String, String)], out: &mut Vec<u8>) {
+    out.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+/// 分块请求体连同分块格式和 trailer 最多的字节数
+///
+/// 分块扩展虽然被忽略，也要先读进来；每块只带一个字节数据、却带着很长扩展的请求体
+/// 解码后不大，原始数据却可以是 max_body_size 的许多倍，所以原始数据也要有上限。
+fn max_encoded_size(limits: &Limits) -> usize {
+    limits
+        .max_body_size
+        .saturating_mul(2)
+        .saturating_add(limits.max_header_size)
+        .saturating_add(MAX_LINE_LEN)
+}
+
+/// 解码分块的请求体，返回数据和 trailer
+///
+/// 数据还没收完时返回 `ParseError::IncompleteBody`，解码后的长度超过
+/// `limits.max_body_size` 或原始数据超过它的两倍左右时返回 `ParseError::BodyTooLarge`，
+/// trailer 和请求头一样受个数和大小的限制。
+pub(crate) fn read_chunked<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<(Vec<u8>, HashMap<String, String>), ParseError> {
+    let mut body = Vec::new();
+    let trailers = read_chunked_into(reader, limits, |data| {
+        body.extend_from_slice(data);
+        Ok(())
+    })?;
+    Ok((body, trailers))
+}
+
+/// 与 read_chunked 相同，但解码出的数据不放在内存中，而是逐段交给 sink，只返回 trailer
+pub(crate) fn read_chunked_into<R, F>(
+    reader: &mut R,
+    limits: &Limits,
+    mut sink: F,
+) -> Result<HashMap<String, String>, ParseError>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> Result<(), ParseError>,
+{
+    let mut decoded = 0;
+    // 已经读到的原始字节数，行尾按 CRLF 计
+    let mut raw = 0usize;
+    let max_raw = max_encoded_size(limits);
+
+    loop {
+        let line = read_line(reader)?;
+        raw = raw.saturating_add(line.len() + 2);
+        if raw > max_raw {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let size = chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_size - decoded {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        // 不按声明的大小预先分配，只随实际收到的数据一段一段地读
+        let mut remaining = size;
+        while remaining > 0 {
+            let data = reader.fill_buf()?;
+            if data.is_empty() {
+                return Err(ParseError::IncompleteBody);
+            }
+            let n = data.len().min(remaining);
+            sink(&data[..n])?;
+            reader.consume(n);
+            remaining -= n;
+        }
+        decoded += size;
+        if !read_line(reader)?.is_empty() {
+            return Err(ParseError::InvalidChunk);
+        }
+        raw = raw.saturating_add(size + 2);
+    }
+
+    let mut trailers = Trailers::default();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(trailers.headers);
+        }
+        trailers.push(&line, limits)?;
+    }
+}
+
+/// 可以分多次传入数据的分块请求体解码器，供事件循环使用
+///
+/// 块大小和 trailer 只在整行到达后解码，块中的数据到多少解码多少，
+/// 已经解码的数据不会再解码一遍，请求体分很多次到达时也只花线性的时间。
+#[derive(Default)]
+pub(crate) struct ChunkedDecoder {
+    /// 解码出来、还没有被 take_body 取走的数据
+    body: Vec<u8>,
+    /// 已经解码的数据总数
+    decoded: usize,
+    /// 已经用掉的原始字节数
+    raw: usize,
+    state: Decoding,
+    trailers: HashMap<String, String>,
+}
+
+#[derive(Default)]
+enum Decoding {
+    /// 等待块大小所在的一行
+    #[default]
+    Size,
+    /// 当前块还剩多少数据
+    Data(usize),
+    /// 等待块数据之后的 CRLF
+    DataEnd,
+    /// 读到结束块之后收集 trailer
+    Trailers(Trailers),
+    Done,
+}
+
+impl ChunkedDecoder {
+    /// 解码 data 开头尽可能多的内容，返回用掉的字节数
+    ///
+    /// data 是上次用掉的部分之后收到的全部数据，调用方可以随即丢掉用掉的部分。
+    /// 还没有结束时，data 中剩下的部分也算作请求体的原始数据，一起受上限的约束。
+    pub(crate) fn decode(&mut self, data: &[u8], limits: &Limits) -> Result<usize, ParseError> {
+        let mut used = 0;
+
+        loop {
+            let rest = &data[used..];
+            match &mut self.state {
+                Decoding::Done => break,
+                Decoding::Data(remaining) => {
+                    let n = rest.len().min(*remaining);
+                    if n == 0 {
+                        break;
+                    }
+                    self.body.extend_from_slice(&rest[..n]);
+                    self.decoded += n;
+                    used += n;
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        self.state = Decoding::DataEnd;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let (line, line_len) = match split_line(rest)? {
+                Some(line) => line,
+                None => break,
+            };
+            used += line_len;
+            match &mut self.state {
+                Decoding::Size => {
+                    let size = chunk_size(&line)?;
+                    if size == 0 {
+                        self.state = Decoding::Trailers(Trailers::default());
+                    } else if size > limits.max_body_size - self.decoded {
+                        return Err(ParseError::BodyTooLarge);
+                    } else {
+                        self.state = Decoding::Data(size);
+                    }
+                }
+                Decoding::DataEnd if line.is_empty() => self.state = Decoding::Size,
+                Decoding::DataEnd => return Err(ParseError::InvalidChunk),
+                Decoding::Trailers(_) if line.is_empty() => {
+                    if let Decoding::Trailers(trailers) = mem::take(&mut self.state) {
+                        self.state = Decoding::Done;
+                        self.trailers = trailers.headers;
+                    }
+                }
+                Decoding::Trailers(trailers) => trailers.push(&line, limits)?,
+                Decoding::Data(_) | Decoding::Done => unreachable!("handled above"),
+            }
+        }
+
+        self.raw = self.raw.saturating_add(used);
+        let pending = if self.is_done() { 0 } else { data.len() - used };
+        if self.raw.saturating_add(pending) > max_encoded_size(limits) {
+            return Err(ParseError::BodyTooLarge);
+        }
+        Ok(used)
+    }
+
+    /// 结束块和 trailer 都已经读完
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, Decoding::Done)
+    }
+
+    /// 取走到目前为止解码出来的数据
+    pub(crate) fn take_body(&mut self) -> Vec<u8> {
+        mem::take(&mut self.body)
+    }
+
+    /// 还没有取走的数据和 trailer
+    pub(crate) fn finish(self) -> (Vec<u8>, HashMap<String, String>) {
+        (self.body, self.trailers)
+    }
+}
+
+/// 收集 trailer，和请求头一样受个数和大小的限制
+#[derive(Default)]
+struct Trailers {
+    headers: HashMap<String, String>,
+    count: usize,
+    size: usize,
+}
+
+impl Trailers {
+    fn push(&mut self, line: &str, limits: &Limits) -> Result<(), ParseError> {
+        self.count += 1;
+        self.size += line.len() + 2;
+        if self.count > limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        if self.size > limits.max_header_size {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header_line(line)?;
+        insert_header(&mut self.headers, name, value);
+        Ok(())
+    }
+}
+
+/// 块大小所在的一行，分块扩展（`;name=value`）没有用到，直接忽略
+fn chunk_size(line: &str) -> Result<usize, ParseError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    parse_size(size).ok_or(ParseError::InvalidChunk)
+}
+
+/// 块大小是十六进制数，`from_str_radix` 会接受 "+1" 这样的写法，这里先检查
+fn parse_size(s: &str) -> Option<usize> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// 读取一行（不含行尾的 CRLF），数据不完整时返回 `ParseError::IncompleteBody`
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, ParseError> {
+    let mut buf = Vec::new();
+    let n = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut buf)?;
+
+    if buf.last() != Some(&b'\n') {
+        return Err(if n > MAX_LINE_LEN {
+            ParseError::InvalidChunk
+        } else {
+            ParseError::IncompleteBody
+        });
+    }
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    String::from_utf8(buf).map_err(|_| ParseError::InvalidChunk)
+}
+
+/// data 开头的一行（不含行尾）和它连同行尾的长度，还没有完整的一行时返回 None
+fn split_line(data: &[u8]) -> Result<Option<(String, usize)>, ParseError> {
+    let window = &data[..data.len().min(MAX_LINE_LEN + 1)];
+    let end = match window.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if data.len() > MAX_LINE_LEN => return Err(ParseError::InvalidChunk),
+        None => return Ok(None),
+    };
+    let line = data[..end].strip_suffix(b"\r").unwrap_or(&data[..end]);
+    let line = String::from_utf8(line.to_vec()).map_err(|_| ParseError::InvalidChunk)?;
+    Ok(Some((line, end + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 用 read_chunked 解码，同时检查 ChunkedDecoder 逐字节收到数据时结果相同
+    fn decode(
+        raw: &str,
+        limits: &Limits,
+    ) -> Result<(Vec<u8>, HashMap<String, String>), ParseError> {
+        let result = read_chunked(&mut raw.as_bytes(), limits);
+
+        let raw = raw.as_bytes();
+        let mut decoder = ChunkedDecoder::default();
+        let mut used = 0;
+        let mut incremental = Err(ParseError::IncompleteBody);
+        for end in 0..=raw.len() {
+            match decoder.decode(&raw[used..end], limits) {
+                Ok(n) => used += n,
+                Err(e) => {
+                    incremental = Err(e);
+                    break;
+                }
+            }
+            if decoder.is_done() {
+                incremental = Ok(std::mem::take(&mut decoder).finish());
+                break;
+            }
+        }
+        assert_eq!(format!("{:?}", incremental), format!("{:?}", result));
+        result
+    }
+
+    #[test]
+    fn writes_chunks_and_trailers() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello, ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"chunked world").unwrap();
+        let out = writer
+            .finish(&[("X-Checksum".to_string(), "abc".to_string())])
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "7\r\nhello, \r\nd\r\nchunked world\r\n0\r\nX-Checksum: abc\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn decodes_chunks_extensions_and_trailers() {
+        let limits = Limits::default();
+        let (body, trailers) = decode(
+            "5;name=value\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\nX-Sum: 1\r\n\r\n",
+            &limits,
+        )
+        .unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(trailers.get("x-sum").map(String::as_str), Some("1"));
+
+        // 大写的十六进制
+        let (body, _) = decode("A\r\n0123456789\r\n0\r\n\r\n", &limits).unwrap();
+        assert_eq!(body.len(), 10);
+    }
+
+    #[test]
+    fn rejects_bad_or_incomplete_chunks() {
+        let limits = Limits::default();
+        for raw in ["", "5\r\nhel", "5\r\nhello", "5\r\nhello\r\n0\r\n", "5\r"].iter() {
+            assert!(
+                matches!(decode(raw, &limits), Err(ParseError::IncompleteBody)),
+                "{:?}",
+                raw
+            );
+        }
+        for raw in ["x\r\n", "+5\r\nhello\r\n", "5\r\nhelloX\r\n0\r\n\r\n"].iter() {
+            assert!(
+                matches!(decode(raw, &limits), Err(ParseError::InvalidChunk)),
+                "{:?}",
+                raw
+            );
+        }
+
+        let small = Limits {
+            max_body_size: 8,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode("5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n", &small),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            decode("ffffffffffffffffff\r\n", &small),
+            Err(ParseError::InvalidChunk)
+        ));
+
+        // 数据不多，但每块都带着很长的扩展
+        let extension = "x".repeat(MAX_LINE_LEN - 10);
+        let raw = format!("1;{}\r\na\r\n", extension).repeat(6) + "0\r\n\r\n";
+        assert!(matches!(
+            decode(&raw, &small),
+            Err(ParseError::BodyTooLarge)
+        ));
+        let (body, _) = read_chunked(&mut raw.as_bytes(), &Limits::default()).unwrap();
+        assert_eq!(body, b"aaaaaa");
+
+        // 不限大小时，声明的巨大块也不会导致预先分配或溢出
+        let unlimited = Limits {
+            max_body_size: usize::MAX,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode("ffffffffffffffff\r\nhello", &unlimited),
+            Err(ParseError::IncompleteBody)
+        ));
+        assert!(matches!(
+            decode("ffffffffffffffff\r\n", &Limits::default()),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,483 @@
+//! 按 `Accept-Encoding` 压缩响应体
+//!
+//! 只压缩文本一类的内容，太小的响应体和已经压缩过的内容原样发送。
+//! 长度已知的响应体在内存中压缩后仍带 Content-Length；从 reader 读取的和分块的响应体
+//! 边读边压缩，以分块编码发送。
+
+use crate::chunked::{ChunkedBody, ReaderChunks};
+use crate::http::Request;
+use crate::middleware::{Middleware, Next};
+use crate::response::{Body, Response};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Read, Write};
+use std::mem;
+
+/// 支持的内容编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// HTTP 中的 deflate 是 zlib 格式（RFC 1950），不是裸的 deflate 数据
+    Deflate,
+}
+
+impl Encoding {
+    /// `Content-Encoding` 中的名字
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn matches(self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(self.as_str())
+            || (self == Encoding::Gzip && name.eq_ignore_ascii_case("x-gzip"))
+    }
+
+    fn encoder(self, level: u32) -> Encoder {
+        let level = flate2::Compression::new(level);
+        match self {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), level)),
+        }
+    }
+}
+
+/// 按 `Accept-Encoding` 从 available 中选出客户端最想要的编码，都不接受时返回 None
+///
+/// q 值相同时以 available 中的顺序为准；没有列出的编码取 `*` 的 q 值。
+/// 只有客户端明确给 `identity` 更高的 q 值时才不压缩。
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut listed = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        if name.is_empty() {
+            continue;
+        }
+        listed.push((name, quality(parts)));
+    }
+    let listed_q = |name: &str| {
+        listed
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, q)| q)
+    };
+
+    let wildcard = listed_q("*").unwrap_or(0);
+    let mut best: Option<(Encoding, u16)> = None;
+    for &encoding in available {
+        let q = listed
+            .iter()
+            .find(|(name, _)| encoding.matches(name))
+            .map(|&(_, q)| q)
+            .unwrap_or(wildcard);
+        if q > 0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+
+    match best {
+        Some((_, q)) if q < listed_q("identity").unwrap_or(0) => None,
+        best => best.map(|(encoding, _)| encoding),
+    }
+}
+
+/// 解析 `;q=0.8` 形式的参数，以千分之一为单位；格式不对时当作 0
+fn quality<'a, I: Iterator<Item = &'a str>>(params: I) -> u16 {
+    for param in params {
+        let (name, value) = match param.find('=') {
+            Some(i) => (param[..i].trim(), param[i + 1..].trim()),
+            None => continue,
+        };
+        if !name.eq_ignore_ascii_case("q") {
+            continue;
+        }
+        return match value.parse::<f32>() {
+            Ok(q) if (0.0..=1.0).contains(&q) => (q * 1000.0).round() as u16,
+            _ => 0,
+        };
+    }
+    1000
+}
+
+/// 响应压缩的设置，通过 [`Server::compression`](crate::server::Server::compression) 启用，
+/// 也可以作为中间件只用在部分路由上
+///
+/// 默认小于 1KB 的响应体不压缩，压缩级别为 6。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: 1024,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+
+    /// 小于这个字节数的响应体不压缩，分块的响应体长度未知，总是压缩
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// 压缩级别，0（不压缩）到 9（最小），超出时按 9 处理
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    /// 按请求的 `Accept-Encoding` 压缩响应
+    ///
+    /// 可以压缩的内容类型总是带上 `Vary: Accept-Encoding`，压缩后强 ETag 改为弱 ETag。
+    /// 已有 `Content-Encoding`、带 `Cache-Control: no-transform` 的响应和部分内容（206）不压缩。
+    pub fn apply(&self, request: &Request, mut response: Response) -> Response {
+        let compressible = response.header("content-type").is_some_and(is_compressible)
+            && response.header("content-encoding").is_none()
+            && !response
+                .header("cache-control")
+                .is_some_and(|v| v.to_ascii_lowercase().contains("no-transform"));
+        if !compressible {
+            return response;
+        }
+        // 同一个 URL 的响应随 Accept-Encoding 变化，缓存需要区分
+        add_vary(&mut response, "Accept-Encoding");
+
+        let status = response.status;
+        if !(200..300).contains(&status) || status == 204 || status == 206 {
+            return response;
+        }
+        let large_enough = match &response.body {
+            Body::Empty => false,
+            Body::Chunked(_) => true,
+            body => body.len() >= self.min_size,
+        };
+        let encoding = request
+            .header("accept-encoding")
+            .and_then(|header| negotiate(header, &[Encoding::Gzip, Encoding::Deflate]));
+        let encoding = match encoding {
+            Some(encoding) if large_enough => encoding,
+            _ => return response,
+        };
+
+        response.body = match mem::replace(&mut response.body, Body::Empty) {
+            Body::Bytes(bytes) => {
+                let compressed = encoding.encoder(self.level).compress(&bytes);
+                // 压缩后反而更大时原样发送
+                if compressed.len() >= bytes.len() {
+                    response.body = Body::Bytes(bytes);
+                    return response;
+                }
+                Body::Bytes(compressed)
+            }
+            Body::Reader(reader, len) => Body::Chunked(Box::new(Compressed {
+                body: Box::new(ReaderChunks {
+                    reader: reader.take(len),
+                }),
+                encoder: Some(encoding.encoder(self.level)),
+            })),
+            Body::Chunked(body) => Body::Chunked(Box::new(Compressed {
+                body,
+                encoder: Some(encoding.encoder(self.level)),
+            })),
+            Body::Empty => Body::Empty,
+        };
+        response.set_header("Content-Encoding", encoding.as_str());
+        // Range 按未压缩的内容计算，压缩后的响应不能再声明支持
+        response
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("accept-ranges"));
+
+        // 压缩后的内容与原来的不再逐字节相同
+        if let Some(etag) = response.header("etag") {
+            if !etag.starts_with("W/") {
+                let etag = format!("W/{}", etag);
+                response.set_header("ETag", &etag);
+            }
+        }
+        response
+    }
+}
+
+/// 文本一类、值得压缩的内容类型
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+/// 在 Vary 中加入 name，已经有了就不重复
+pub(crate) fn add_vary(response: &mut Response, name: &str) {
+    let vary = match response.header("vary") {
+        Some(vary) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(name)) => return,
+        Some(vary) if vary.trim() == "*" => return,
+        Some(vary) => format!("{}, {}", vary, name),
+        None => name.to_string(),
+    };
+    response.set_header("Vary", &vary);
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn compress(mut self, data: &[u8]) -> Vec<u8> {
+        // 写进 Vec 不会失败
+        self.write_all(data)
+            .and_then(|()| self.finish())
+            .unwrap_or_default()
+    }
+
+    /// 取出到目前为止压缩好的数据
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(e) => mem::take(e.get_mut()),
+            Encoder::Deflate(e) => mem::take(e.get_mut()),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Deflate(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Deflate(e) => e.flush(),
+        }
+    }
+}
+
+/// 边读边压缩的分块响应体
+///
+/// 每块数据都会立即 flush，保证逐条生成的输出不会被压缩器攒着不发。
+struct Compressed {
+    body: Box<dyn ChunkedBody>,
+    /// 结束后为 None
+    encoder: Option<Encoder>,
+}
+
+impl ChunkedBody for Compressed {
+    fn next_chunk(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut encoder = match self.encoder.take() {
+            Some(encoder) => encoder,
+            None => return Ok(false),
+        };
+
+        let mut data = Vec::new();
+        let more = self.body.next_chunk(&mut data)?;
+        encoder.write_all(&data)?;
+        if !more {
+            buf.extend(encoder.finish()?);
+            return Ok(false);
+        }
+        if !data.is_empty() {
+            encoder.flush()?;
+        }
+        buf.extend(encoder.take());
+        self.encoder = Some(encoder);
+        Ok(true)
+    }
+
+    fn trailers(&mut self) -> Vec<(String, String)> {
+        self.body.trailers()
+    }
+}
+
+/// 作为中间件使用时，压缩后面的中间件和 handler 生成的响应
+impl Middleware for Compression {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let response = next.run(request);
+        self.apply(request, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Cursor;
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        );
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// 把分块的响应体全部取出来
+    fn drain(body: Body) -> Vec<u8> {
+        let mut body = match body {
+            Body::Chunked(body) => body,
+            _ => panic!("expected a chunked body"),
+        };
+        let mut out = Vec::new();
+        while body.next_chunk(&mut out).unwrap() {}
+        out
+    }
+
+    #[test]
+    fn negotiates_with_q_values() {
+        let both = [Encoding::Gzip, Encoding::Deflate];
+        let cases = [
+            ("gzip, deflate, br", Some(Encoding::Gzip)),
+            ("deflate, gzip", Some(Encoding::Gzip)),
+            ("gzip;q=0.5, deflate", Some(Encoding::Deflate)),
+            ("deflate;q=0.2, gzip;q=0", Some(Encoding::Deflate)),
+            ("X-GZIP", Some(Encoding::Gzip)),
+            ("*", Some(Encoding::Gzip)),
+            ("*;q=0.5, gzip;q=0", Some(Encoding::Deflate)),
+            ("br", None),
+            ("", None),
+            ("gzip;q=0, deflate;q=0", None),
+            ("gzip;q=abc", None),
+            ("gzip;q=0.5, identity", None),
+            ("gzip, identity;q=0.5", Some(Encoding::Gzip)),
+        ];
+        for (header, expected) in cases.iter() {
+            assert_eq!(negotiate(header, &both), *expected, "{:?}", header);
+        }
+        assert_eq!(negotiate("deflate", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn compresses_text_bodies() {
+        let text = "hello compression ".repeat(200);
+        let response = Response::text(200, &text).with_header("ETag", "\"abc\"");
+        let response = Compression::new().apply(&request("gzip"), response);
+
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.header("etag"), Some("W/\"abc\""));
+        match response.body {
+            Body::Bytes(bytes) => assert_eq!(gunzip(&bytes), text.as_bytes()),
+            _ => panic!("expected bytes"),
+        }
+
+        let response = Response::text(200, &text).with_header("Vary", "Cookie");
+        let response = Compression::new().apply(&request("deflate"), response);
+        assert_eq!(response.header("content-encoding"), Some("deflate"));
+        assert_eq!(response.header("vary"), Some("Cookie, Accept-Encoding"));
+        let mut out = Vec::new();
+        match response.body {
+            Body::Bytes(bytes) => ZlibDecoder::new(&bytes[..]).read_to_end(&mut out).unwrap(),
+            _ => panic!("expected bytes"),
+        };
+        assert_eq!(out, text.as_bytes());
+    }
+
+    #[test]
+    fn skips_small_binary_and_encoded_bodies() {
+        let compression = Compression::new();
+        let gzip = request("gzip");
+        let text = "x".repeat(2000);
+
+        // 太小：不压缩，但仍然声明 Vary
+        let response = compression.apply(&gzip, Response::text(200, "small"));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+
+        // 客户端不接受
+        let response = compression.apply(&request("br"), Response::text(200, &text));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.body.len(), 2000);
+
+        let skipped = vec![
+            Response::new(200)
+                .with_header("Content-Type", "image/png")
+                .with_body(text.clone().into_bytes()),
+            Response::text(200, &text).with_header("Content-Encoding", "br"),
+            Response::text(200, &text).with_header("Cache-Control", "no-transform"),
+            Response::text(206, &text),
+        ];
+        for response in skipped {
+            let encoding = response.header("content-encoding").map(String::from);
+            let response = compression.apply(&gzip, response);
+            assert_eq!(response.header("content-encoding"), encoding.as_deref());
+            assert_eq!(response.body.len(), 2000);
+        }
+
+        let lower = Compression::new().min_size(1);
+        let response = lower.apply(
+            &gzip,
+            Response::text(200, "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+        );
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn streams_reader_and_chunked_bodies() {
+        let text = "streamed line\n".repeat(500);
+        let response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_header("Accept-Ranges", "bytes")
+            .with_reader(
+                Cursor::new(format!("{}trailing junk", text)),
+                text.len() as u64,
+            );
+        let response = Compression::new().apply(&request("gzip"), response);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("accept-ranges"), None);
+        assert_eq!(gunzip(&drain(response.body)), text.as_bytes());
+
+        let mut lines = vec!["a\n", "b\n"].into_iter();
+        let response = Response::new(200)
+            .with_header("Content-Type", "application/x-ndjson+json")
+            .with_chunked(move |buf: &mut Vec<u8>| match lines.next() {
+                Some(line) => {
+                    buf.extend_from_slice(line.as_bytes());
+                    Ok(true)
+                }
+                None => Ok(false),
+            });
+        let response = Compression::new().apply(&request("gzip"), response);
+        assert_eq!(gunzip(&drain(response.body)), b"a\nb\n");
+    }
+}
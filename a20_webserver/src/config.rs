@@ -0,0 +1,982 @@
+//! 服务器配置
+//!
+//! 配置按 默认值 < 配置文件 < 环境变量 < 命令行参数 的顺序合并，后面的覆盖前面的。
+//! 配置文件是 TOML 格式，路径由 `--config` 或 `A20_CONFIG` 指定：
+//!
+//! ```toml
+//! listen = ["127.0.0.1:7878", "[::1]:7878"]
+//! workers = 8
+//! mode = "threads"           # threads 或 event-loop
+//! root = "public"            # 相对路径相对于配置文件所在的目录
+//! max_request_size = "1MB"   # 也可以直接写字节数
+//! max_headers = 100
+//! max_header_size = "32KB"   # 全部请求头加起来
+//! max_connections_per_ip = 4 # 同一个 IP 的并发连接数，0 表示不限制
+//! log_format = "json"        # text 或 json
+//!
+//! [timeouts]
+//! keep_alive = "5s"          # 也可以直接写秒数，支持 ms、s、m、h
+//! shutdown = "30s"
+//! read = "10s"               # 读取请求时每次等待数据的时间
+//! write = "10s"              # 写出响应时每次等待对方接收的时间
+//! header = "10s"             # 读完请求头的总期限
+//!
+//! [access_log]
+//! target = "logs/access.log" # stdout、off 或文件路径
+//! format = "combined"        # common、combined 或 json
+//! max_size = "10MB"          # 超过后轮转，0 表示不轮转
+//! keep = 5                   # 保留的旧文件数
+//! ```
+
+use crate::access_log::{AccessLogFormat, LogTarget, Rotation};
+use crate::server::Mode;
+use crate::LogFormat;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 命令行的用法说明
+pub const USAGE: &str = "\
+Usage: a20_webserver [OPTIONS] [ROOT]
+
+Options:
+  -c, --config <FILE>             read settings from a TOML file      [A20_CONFIG]
+  -l, --listen <ADDR>             address to listen on, repeatable    [A20_LISTEN]
+  -w, --workers <N>               number of worker threads            [A20_WORKERS]
+  -r, --root <DIR>                document root                       [A20_ROOT]
+      --mode <threads|event-loop> how connections are handled        [A20_MODE]
+      --keep-alive-timeout <DUR>  idle time before closing a connection [A20_KEEP_ALIVE_TIMEOUT]
+      --shutdown-timeout <DUR>    time to drain requests on shutdown  [A20_SHUTDOWN_TIMEOUT]
+      --read-timeout <DUR>        wait for request data at most this long [A20_READ_TIMEOUT]
+      --write-timeout <DUR>       wait for the client to accept data  [A20_WRITE_TIMEOUT]
+      --header-timeout <DUR>      deadline for the whole request head [A20_HEADER_TIMEOUT]
+      --max-request-size <SIZE>   largest accepted request body       [A20_MAX_REQUEST_SIZE]
+      --max-headers <N>           most request headers accepted       [A20_MAX_HEADERS]
+      --max-header-size <SIZE>    largest total size of the headers   [A20_MAX_HEADER_SIZE]
+      --max-connections-per-ip <N>  concurrent connections per client, 0 = unlimited [A20_MAX_CONNECTIONS_PER_IP]
+      --compression <on|off>      gzip/deflate responses on request   [A20_COMPRESSION]
+      --compression-min-size <SIZE>   leave smaller bodies uncompressed [A20_COMPRESSION_MIN_SIZE]
+      --log-format <text|json>    format of the server log            [A20_LOG_FORMAT]
+      --access-log <stdout|off|FILE>  where to write the access log   [A20_ACCESS_LOG]
+      --access-log-format <common|combined|json>                      [A20_ACCESS_LOG_FORMAT]
+      --access-log-max-size <SIZE>    rotate the file past this size  [A20_ACCESS_LOG_MAX_SIZE]
+      --access-log-keep <N>       rotated files to keep               [A20_ACCESS_LOG_KEEP]
+  -h, --help                      print this help
+
+Durations accept ms, s, m and h suffixes (plain numbers are seconds).
+Sizes accept KB, MB and GB suffixes (plain numbers are bytes).
+Environment variables take one comma separated list for A20_LISTEN.";
+
+/// 服务器的全部设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// 监听的地址，至少一个
+    pub listen: Vec<SocketAddr>,
+    /// worker 线程数
+    pub workers: usize,
+    /// 静态文件的根目录
+    pub root: PathBuf,
+    /// 连接的处理方式
+    pub mode: Mode,
+    /// 两个请求之间最多等待多久
+    pub keep_alive_timeout: Duration,
+    /// 关闭时等待正在处理的请求完成的最长时间
+    pub shutdown_timeout: Duration,
+    /// 读取请求时每次等待数据的最长时间
+    pub read_timeout: Duration,
+    /// 写出响应时每次等待对方接收的最长时间
+    pub write_timeout: Duration,
+    /// 读完请求头的总期限
+    pub header_timeout: Duration,
+    /// 请求体的最大字节数
+    pub max_request_size: usize,
+    /// 请求头的最大个数
+    pub max_headers: usize,
+    /// 全部请求头的最大字节数
+    pub max_header_size: usize,
+    /// 同一个 IP 的最大并发连接数，0 表示不限制
+    pub max_connections_per_ip: usize,
+    /// 是否按 Accept-Encoding 压缩响应，并发送预先压缩好的 `.gz` 文件
+    pub compression: bool,
+    /// 小于这个字节数的响应体不压缩
+    pub compression_min_size: u64,
+    pub log_format: LogFormat,
+    /// 访问日志写到哪里，None 表示不记录
+    pub access_log: Option<LogTarget>,
+    pub access_log_format: AccessLogFormat,
+    pub access_log_rotation: Rotation,
+}
+
+impl Default for ServerConfig {
+    /// 127.0.0.1:7878，8 个 worker，本 crate 的 public 目录，请求体最大 1MB，不记录访问日志
+    ///
+    /// 超时和请求头的上限与 `Timeouts`、`Limits` 的默认值相同，不限制每个 IP 的连接数。
+    /// 压缩默认开启，与 `Compression` 的默认值相同。
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            workers: 8,
+            root: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/public")),
+            mode: Mode::Threads,
+            keep_alive_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            header_timeout: Duration::from_secs(10),
+            max_request_size: 1024 * 1024,
+            max_headers: 100,
+            max_header_size: 32 * 1024,
+            max_connections_per_ip: 0,
+            compression: true,
+            compression_min_size: 1024,
+            log_format: LogFormat::Text,
+            access_log: None,
+            access_log_format: AccessLogFormat::Common,
+            access_log_rotation: Rotation::default(),
+        }
+    }
+}
+
+/// 加载配置时的错误
+#[derive(Debug)]
+pub enum ConfigError {
+    /// 读取配置文件失败
+    Io { path: PathBuf, error: io::Error },
+    /// 配置文件不是合法的 TOML
+    Parse { path: PathBuf, message: String },
+    /// 配置文件中有不认识的配置项
+    UnknownKey { path: PathBuf, key: String },
+    /// 配置的值不合法，origin 说明值从哪里来，例如 `--workers`、`A20_WORKERS`
+    InvalidValue {
+        origin: String,
+        value: String,
+        reason: String,
+    },
+    /// 不认识的命令行参数
+    UnknownFlag(String),
+    /// 命令行参数缺少值
+    MissingValue(String),
+    /// 命令行中有 `--help`，调用方应该打印 `USAGE`
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "cannot read config file {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "invalid config file {}: {}", path.display(), message)
+            }
+            ConfigError::UnknownKey { path, key } => {
+                write!(f, "unknown setting `{}` in {}", key, path.display())
+            }
+            ConfigError::InvalidValue {
+                origin,
+                value,
+                reason,
+            } => write!(f, "invalid value {:?} for {}: {}", value, origin, reason),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown option {}", flag),
+            ConfigError::MissingValue(flag) => write!(f, "option {} requires a value", flag),
+            ConfigError::Help => write!(f, "help requested"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// 可以配置的项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Listen,
+    Workers,
+    Root,
+    Mode,
+    KeepAliveTimeout,
+    ShutdownTimeout,
+    ReadTimeout,
+    WriteTimeout,
+    HeaderTimeout,
+    MaxRequestSize,
+    MaxHeaders,
+    MaxHeaderSize,
+    MaxConnectionsPerIp,
+    Compression,
+    CompressionMinSize,
+    LogFormat,
+    AccessLog,
+    AccessLogFormat,
+    AccessLogMaxSize,
+    AccessLogKeep,
+}
+
+/// 每一项在命令行、环境变量和配置文件中的名字
+struct Setting {
+    key: Key,
+    flag: &'static str,
+    short: Option<&'static str>,
+    env: &'static str,
+    file: &'static str,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: Key::Listen,
+        flag: "--listen",
+        short: Some("-l"),
+        env: "A20_LISTEN",
+        file: "listen",
+    },
+    Setting {
+        key: Key::Workers,
+        flag: "--workers",
+        short: Some("-w"),
+        env: "A20_WORKERS",
+        file: "workers",
+    },
+    Setting {
+        key: Key::Root,
+        flag: "--root",
+        short: Some("-r"),
+        env: "A20_ROOT",
+        file: "root",
+    },
+    Setting {
+        key: Key::Mode,
+        flag: "--mode",
+        short: None,
+        env: "A20_MODE",
+        file: "mode",
+    },
+    Setting {
+        key: Key::KeepAliveTimeout,
+        flag: "--keep-alive-timeout",
+        short: None,
+        env: "A20_KEEP_ALIVE_TIMEOUT",
+        file: "timeouts.keep_alive",
+    },
+    Setting {
+        key: Key::ShutdownTimeout,
+        flag: "--shutdown-timeout",
+        short: None,
+        env: "A20_SHUTDOWN_TIMEOUT",
+        file: "timeouts.shutdown",
+    },
+    Setting {
+        key: Key::ReadTimeout,
+        flag: "--read-timeout",
+        short: None,
+        env: "A20_READ_TIMEOUT",
+        file: "timeouts.read",
+    },
+    Setting {
+        key: Key::WriteTimeout,
+        flag: "--write-timeout",
+        short: None,
+        env: "A20_WRITE_TIMEOUT",
+        file: "timeouts.write",
+    },
+    Setting {
+        key: Key::HeaderTimeout,
+        flag: "--header-timeout",
+        short: None,
+        env: "A20_HEADER_TIMEOUT",
+        file: "timeouts.header",
+    },
+    Setting {
+        key: Key::MaxRequestSize,
+        flag: "--max-request-size",
+        short: None,
+        env: "A20_MAX_REQUEST_SIZE",
+        file: "max_request_size",
+    },
+    Setting {
+        key: Key::MaxHeaders,
+        flag: "--max-headers",
+        short: None,
+        env: "A20_MAX_HEADERS",
+        file: "max_headers",
+    },
+    Setting {
+        key: Key::MaxHeaderSize,
+        flag: "--max-header-size",
+        short: None,
+        env: "A20_MAX_HEADER_SIZE",
+        file: "max_header_size",
+    },
+    Setting {
+        key: Key::MaxConnectionsPerIp,
+        flag: "--max-connections-per-ip",
+        short: None,
+        env: "A20_MAX_CONNECTIONS_PER_IP",
+        file: "max_connections_per_ip",
+    },
+    Setting {
+        key: Key::Compression,
+        flag: "--compression",
+        short: None,
+        env: "A20_COMPRESSION",
+        file: "compression.enabled",
+    },
+    Setting {
+        key: Key::CompressionMinSize,
+        flag: "--compression-min-size",
+        short: None,
+        env: "A20_COMPRESSION_MIN_SIZE",
+        file: "compression.min_size",
+    },
+    Setting {
+        key: Key::LogFormat,
+        flag: "--log-format",
+        short: None,
+        env: "A20_LOG_FORMAT",
+        file: "log_format",
+    },
+    Setting {
+        key: Key::AccessLog,
+        flag: "--access-log",
+        short: None,
+        env: "A20_ACCESS_LOG",
+        file: "access_log.target",
+    },
+    Setting {
+        key: Key::AccessLogFormat,
+        flag: "--access-log-format",
+        short: None,
+        env: "A20_ACCESS_LOG_FORMAT",
+        file: "access_log.format",
+    },
+    Setting {
+        key: Key::AccessLogMaxSize,
+        flag: "--access-log-max-size",
+        short: None,
+        env: "A20_ACCESS_LOG_MAX_SIZE",
+        file: "access_log.max_size",
+    },
+    Setting {
+        key: Key::AccessLogKeep,
+        flag: "--access-log-keep",
+        short: None,
+        env: "A20_ACCESS_LOG_KEEP",
+        file: "access_log.keep",
+    },
+];
+
+const CONFIG_FLAG: &str = "--config";
+const CONFIG_ENV: &str = "A20_CONFIG";
+
+impl ServerConfig {
+    /// 从进程的命令行参数和环境变量加载配置
+    pub fn load() -> Result<ServerConfig, ConfigError> {
+        ServerConfig::parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    /// 从给定的命令行参数（不含程序名）和环境变量加载配置
+    ///
+    /// env 按名字返回环境变量的值，方便测试时不碰进程的环境。
+    pub fn parse<I, F>(args: I, env: F) -> Result<ServerConfig, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let args = parse_args(args)?;
+        let mut config = ServerConfig::default();
+
+        // 命令行指定的配置文件优先于环境变量指定的
+        let file = args
+            .iter()
+            .rev()
+            .find(|arg| arg.key.is_none())
+            .map(|arg| PathBuf::from(&arg.value))
+            .or_else(|| env(CONFIG_ENV).map(PathBuf::from));
+        if let Some(path) = file {
+            config.apply_file(&path)?;
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env) {
+                let values = if setting.key == Key::Listen {
+                    value.split(',').map(|s| s.trim().to_string()).collect()
+                } else {
+                    vec![value]
+                };
+                config.set(setting.key, &values, setting.env, None)?;
+            }
+        }
+
+        // 同一个选项出现多次时，--listen 累加，其他的以最后一次为准
+        for setting in SETTINGS {
+            let values: Vec<String> = args
+                .iter()
+                .filter(|arg| arg.key == Some(setting.key))
+                .map(|arg| arg.value.clone())
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            let values = if setting.key == Key::Listen {
+                values
+            } else {
+                values[values.len() - 1..].to_vec()
+            };
+            config.set(setting.key, &values, setting.flag, None)?;
+        }
+
+        Ok(config)
+    }
+
+    /// 读取配置文件，覆盖已有的设置
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigError> {
+        let mut config = ServerConfig::default();
+        config.apply_file(path.as_ref())?;
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| ConfigError::Parse {
+                path: path.to_path_buf(),
+                message: e.message().to_string(),
+            })?;
+
+        let mut entries = Vec::new();
+        flatten("", &table, &mut entries);
+
+        // 相对路径相对于配置文件所在的目录
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for (name, value) in entries {
+            let setting = SETTINGS
+                .iter()
+                .find(|setting| setting.file == name)
+                .ok_or_else(|| ConfigError::UnknownKey {
+                    path: path.to_path_buf(),
+                    key: name.clone(),
+                })?;
+            let origin = format!("`{}` in {}", name, path.display());
+
+            let values = match value {
+                toml::Value::Array(items) if setting.key == Key::Listen => items
+                    .iter()
+                    .map(|item| scalar(item, &origin))
+                    .collect::<Result<Vec<_>, _>>()?,
+                value => vec![scalar(value, &origin)?],
+            };
+            self.set(setting.key, &values, &origin, Some(base))?;
+        }
+        Ok(())
+    }
+
+    /// 设置一项，values 只有 listen 可以有多个
+    fn set(
+        &mut self,
+        key: Key,
+        values: &[String],
+        origin: &str,
+        base: Option<&Path>,
+    ) -> Result<(), ConfigError> {
+        let invalid = |value: &str, reason: &str| ConfigError::InvalidValue {
+            origin: origin.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+        let value = values.first().map(String::as_str).unwrap_or("");
+
+        match key {
+            Key::Listen => {
+                let mut listen = Vec::new();
+                for value in values {
+                    let addrs = value
+                        .to_socket_addrs()
+                        .map_err(|e| invalid(value, &e.to_string()))?;
+                    listen.extend(addrs);
+                }
+                if listen.is_empty() {
+                    return Err(invalid(value, "at least one address is required"));
+                }
+                self.listen = listen;
+            }
+            Key::Workers => {
+                let workers: usize = value
+                    .parse()
+                    .map_err(|_| invalid(value, "expected a positive integer"))?;
+                if workers == 0 {
+                    return Err(invalid(value, "must be at least 1"));
+                }
+                self.workers = workers;
+            }
+            Key::Root => {
+                if value.is_empty() {
+                    return Err(invalid(value, "must not be empty"));
+                }
+                self.root = match base {
+                    Some(base) => base.join(value),
+                    None => PathBuf::from(value),
+                };
+            }
+            Key::Mode => {
+                self.mode = match value.to_ascii_lowercase().as_str() {
+                    "threads" => Mode::Threads,
+                    "event-loop" => Mode::EventLoop,
+                    _ => return Err(invalid(value, "expected `threads` or `event-loop`")),
+                };
+            }
+            Key::KeepAliveTimeout
+            | Key::ShutdownTimeout
+            | Key::ReadTimeout
+            | Key::WriteTimeout
+            | Key::HeaderTimeout => {
+                let timeout = parse_duration(value).map_err(|reason| invalid(value, reason))?;
+                if timeout == Duration::from_secs(0) {
+                    return Err(invalid(value, "must be greater than zero"));
+                }
+                let field = match key {
+                    Key::KeepAliveTimeout => &mut self.keep_alive_timeout,
+                    Key::ShutdownTimeout => &mut self.shutdown_timeout,
+                    Key::ReadTimeout => &mut self.read_timeout,
+                    Key::WriteTimeout => &mut self.write_timeout,
+                    _ => &mut self.header_timeout,
+                };
+                *field = timeout;
+            }
+            Key::MaxRequestSize | Key::MaxHeaderSize => {
+                let size = parse_size(value).map_err(|reason| invalid(value, reason))?;
+                if size == 0 {
+                    return Err(invalid(value, "must be greater than zero"));
+                }
+                if key == Key::MaxRequestSize {
+                    self.max_request_size = size;
+                } else {
+                    self.max_header_size = size;
+                }
+            }
+            Key::MaxHeaders => {
+                let count: usize = value
+                    .parse()
+                    .map_err(|_| invalid(value, "expected a positive integer"))?;
+                if count == 0 {
+                    return Err(invalid(value, "must be at least 1"));
+                }
+                self.max_headers = count;
+            }
+            Key::MaxConnectionsPerIp => {
+                self.max_connections_per_ip = value
+                    .parse()
+                    .map_err(|_| invalid(value, "expected a non-negative integer"))?;
+            }
+            Key::Compression => {
+                self.compression = match value.to_ascii_lowercase().as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid(value, "expected `on` or `off`")),
+                };
+            }
+            Key::CompressionMinSize => {
+                let size = parse_size(value).map_err(|reason| invalid(value, reason))?;
+                self.compression_min_size = size as u64;
+            }
+            Key::LogFormat => {
+                self.log_format = match value.to_ascii_lowercase().as_str() {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    _ => return Err(invalid(value, "expected `text` or `json`")),
+                };
+            }
+            Key::AccessLog => {
+                self.access_log = match value {
+                    "" => return Err(invalid(value, "expected `stdout`, `off` or a file path")),
+                    "off" => None,
+                    "stdout" => Some(LogTarget::Stdout),
+                    path => Some(LogTarget::File(match base {
+                        Some(base) => base.join(path),
+                        None => PathBuf::from(path),
+                    })),
+                };
+            }
+            Key::AccessLogFormat => {
+                self.access_log_format = match value.to_ascii_lowercase().as_str() {
+                    "common" => AccessLogFormat::Common,
+                    "combined" => AccessLogFormat::Combined,
+                    "json" => AccessLogFormat::Json,
+                    _ => return Err(invalid(value, "expected `common`, `combined` or `json`")),
+                };
+            }
+            Key::AccessLogMaxSize => {
+                let size = parse_size(value).map_err(|reason| invalid(value, reason))?;
+                self.access_log_rotation.max_size = size as u64;
+            }
+            Key::AccessLogKeep => {
+                self.access_log_rotation.keep = value
+                    .parse()
+                    .map_err(|_| invalid(value, "expected a non-negative integer"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 命令行中的一个选项，key 为 None 表示 `--config`
+struct Arg {
+    key: Option<Key>,
+    value: String,
+}
+
+/// 把命令行参数拆成选项，支持 `--flag value`、`--flag=value` 和一个位置参数（文档根目录）
+fn parse_args<I>(args: I) -> Result<Vec<Arg>, ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            // 兼容原来的用法：第一个参数是文档根目录
+            parsed.push(Arg {
+                key: Some(Key::Root),
+                value: arg,
+            });
+            continue;
+        }
+
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let key = if flag == CONFIG_FLAG || flag == "-c" {
+            None
+        } else {
+            let setting = SETTINGS
+                .iter()
+                .find(|setting| setting.flag == flag || setting.short == Some(flag))
+                .ok_or_else(|| ConfigError::UnknownFlag(flag.to_string()))?;
+            Some(setting.key)
+        };
+
+        let value = match inline {
+            Some(value) => value,
+            None => args
+                .next()
+                .ok_or_else(|| ConfigError::MissingValue(flag.to_string()))?,
+        };
+        parsed.push(Arg { key, value });
+    }
+    Ok(parsed)
+}
+
+/// 把嵌套的表展开成 `timeouts.keep_alive` 这样的名字
+fn flatten<'a>(prefix: &str, table: &'a toml::Table, out: &mut Vec<(String, &'a toml::Value)>) {
+    for (name, value) in table {
+        let name = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        match value {
+            toml::Value::Table(table) => flatten(&name, table, out),
+            value => out.push((name, value)),
+        }
+    }
+}
+
+/// 配置文件中的值统一转成字符串，和命令行、环境变量走同样的检查
+fn scalar(value: &toml::Value, origin: &str) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        other => Err(ConfigError::InvalidValue {
+            origin: origin.to_string(),
+            value: other.to_string(),
+            reason: "expected a string, an integer or a boolean".to_string(),
+        }),
+    }
+}
+
+/// 解析 `500ms`、`5s`、`2m`、`1h`，没有单位时按秒计
+fn parse_duration(s: &str) -> Result<Duration, &'static str> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "expected a duration like 500ms, 5s, 2m or 1h")?;
+
+    let millis = match unit.trim() {
+        "ms" => Some(number),
+        "" | "s" => number.checked_mul(1000),
+        "m" => number.checked_mul(60 * 1000),
+        "h" => number.checked_mul(60 * 60 * 1000),
+        _ => return Err("unknown unit, expected ms, s, m or h"),
+    };
+    millis
+        .map(Duration::from_millis)
+        .ok_or("duration is too large")
+}
+
+/// 解析 `512`、`64KB`、`1MB`、`1GB`，单位按 1024 进位，不区分大小写
+fn parse_size(s: &str) -> Result<usize, &'static str> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: usize = number
+        .parse()
+        .map_err(|_| "expected a size like 512, 64KB or 1MB")?;
+
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err("unknown unit, expected B, KB, MB or GB"),
+    };
+    number.checked_mul(multiplier).ok_or("size is too large")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn parse(list: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ServerConfig::parse(args(list), |name| env.get(name).cloned())
+    }
+
+    /// 在测试自己的临时目录 dir 中写一个配置文件，返回它的路径
+    fn write_config(dir: &TempDir, name: &str, text: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_without_any_source() {
+        assert_eq!(parse(&[], &[]).unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn file_then_env_then_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            &dir,
+            "layered.toml",
+            r#"
+                listen = ["127.0.0.1:8000", "127.0.0.1:8001"]
+                workers = 2
+                root = "site"
+                max_request_size = "64KB"
+                log_format = "json"
+
+                [timeouts]
+                keep_alive = "1500ms"
+                shutdown = 10
+            "#,
+        );
+        let path = path.to_str().unwrap();
+
+        let config = parse(&["--config", path], &[]).unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.root, Path::new(path).parent().unwrap().join("site"));
+        assert_eq!(config.max_request_size, 64 * 1024);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(1500));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+
+        let config = parse(
+            &["--workers=6", "-l", "127.0.0.1:9000", "/srv/www"],
+            &[
+                ("A20_CONFIG", path),
+                ("A20_WORKERS", "4"),
+                ("A20_LOG_FORMAT", "text"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.workers, 6);
+        assert_eq!(config.listen, vec!["127.0.0.1:9000".parse().unwrap()]);
+        assert_eq!(config.root, PathBuf::from("/srv/www"));
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn invalid_values_name_their_origin() {
+        let err = parse(&["--workers", "0"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid value "0" for --workers: must be at least 1"#
+        );
+
+        let err = parse(&[], &[("A20_KEEP_ALIVE_TIMEOUT", "5 days")]).unwrap_err();
+        assert!(
+            err.to_string().contains("A20_KEEP_ALIVE_TIMEOUT"),
+            "{}",
+            err
+        );
+
+        let err = parse(&["--access-log-format", "apache"], &[]).unwrap_err();
+        assert!(err.to_string().contains("--access-log-format"), "{}", err);
+
+        let err = parse(&["--log-format", "xml"], &[]).unwrap_err();
+        assert!(err.to_string().contains("`text` or `json`"), "{}", err);
+
+        assert!(matches!(
+            parse(&["--listen", "not an address"], &[]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--max-request-size", "1TB"], &[]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            parse(&["--port", "80"], &[]),
+            Err(ConfigError::UnknownFlag(_))
+        ));
+        assert!(matches!(
+            parse(&["--workers"], &[]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(parse(&["-h"], &[]), Err(ConfigError::Help)));
+    }
+
+    #[test]
+    fn bad_config_files_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(&dir, "unknown.toml", "workers = 4\nthreads = 8\n");
+        let err = ServerConfig::from_file(&path).unwrap_err();
+        assert!(matches!(&err, ConfigError::UnknownKey { key, .. } if key == "threads"));
+
+        let path = write_config(&dir, "wrong_type.toml", "[timeouts]\nshutdown = true\n");
+        let err = ServerConfig::from_file(&path).unwrap_err();
+        assert!(err.to_string().contains("`timeouts.shutdown`"), "{}", err);
+
+        let path = write_config(&dir, "broken.toml", "workers = \n");
+        assert!(matches!(
+            ServerConfig::from_file(&path),
+            Err(ConfigError::Parse { .. })
+        ));
+
+        assert!(matches!(
+            ServerConfig::from_file("/nonexistent/a20.toml"),
+            Err(ConfigError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn access_log_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            &dir,
+            "access.toml",
+            "[access_log]\ntarget = \"access.log\"\nformat = \"json\"\nmax_size = \"10MB\"\n",
+        );
+        let config = ServerConfig::from_file(&path).unwrap();
+        assert_eq!(
+            config.access_log,
+            Some(LogTarget::File(path.parent().unwrap().join("access.log")))
+        );
+        assert_eq!(config.access_log_format, AccessLogFormat::Json);
+        assert_eq!(config.access_log_rotation.max_size, 10 * 1024 * 1024);
+        assert_eq!(config.access_log_rotation.keep, 5);
+
+        let config = parse(
+            &["--access-log", "off"],
+            &[("A20_ACCESS_LOG", "stdout"), ("A20_ACCESS_LOG_KEEP", "2")],
+        )
+        .unwrap();
+        assert_eq!(config.access_log, None);
+        assert_eq!(config.access_log_rotation.keep, 2);
+    }
+
+    #[test]
+    fn timeout_and_limit_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            &dir,
+            "limits.toml",
+            "max_headers = 20\nmax_connections_per_ip = 4\n[timeouts]\nread = \"2s\"\nheader = \"500ms\"\n",
+        );
+        let config = parse(
+            &[
+                "--config",
+                path.to_str().unwrap(),
+                "--max-header-size",
+                "8KB",
+            ],
+            &[("A20_WRITE_TIMEOUT", "3")],
+        )
+        .unwrap();
+        assert_eq!(config.read_timeout, Duration::from_secs(2));
+        assert_eq!(config.write_timeout, Duration::from_secs(3));
+        assert_eq!(config.header_timeout, Duration::from_millis(500));
+        assert_eq!(config.max_headers, 20);
+        assert_eq!(config.max_header_size, 8 * 1024);
+        assert_eq!(config.max_connections_per_ip, 4);
+        assert_eq!(config.mode, Mode::Threads);
+
+        let config = parse(&["--mode", "event-loop"], &[]).unwrap();
+        assert_eq!(config.mode, Mode::EventLoop);
+        assert!(parse(&[], &[("A20_MODE", "epoll")]).is_err());
+
+        let path = write_config(
+            &dir,
+            "compression.toml",
+            "[compression]\nenabled = false\nmin_size = \"2KB\"\n",
+        );
+        let config = parse(&["--config", path.to_str().unwrap()], &[]).unwrap();
+        assert!(!config.compression);
+        assert_eq!(config.compression_min_size, 2048);
+        let config = parse(&["--compression=on"], &[("A20_COMPRESSION", "off")]).unwrap();
+        assert!(config.compression);
+        assert!(parse(&["--compression", "maybe"], &[]).is_err());
+
+        let err = parse(&["--header-timeout", "0"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value \"0\" for --header-timeout: must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn durations_and_sizes() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("").is_err());
+
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("8kb"), Ok(8 * 1024));
+        assert_eq!(parse_size("2 MB"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("MB").is_err());
+    }
+}
@@ -0,0 +1,1226 @@
+//! 基于 epoll（通过 mio）的事件循环模式
+//!
+//! 一个 I/O 线程用非阻塞 socket 管理所有连接，读到完整的请求后才交给线程池中的
+//! worker 调用 handler，handler 返回的响应再交回 I/O 线程写出。空闲的持久连接
+//! 不占用 worker，所以几个线程就能同时保持成千上万个 keep-alive 连接。
+//! 流式的响应体（reader 和分块响应体）也在 worker 上一段一段地取出，
+//! 取数据时阻塞或 panic 都不会影响 I/O 线程和其他连接。
+
+use crate::chunked::{self, ChunkedBody, ChunkedDecoder};
+use crate::extract::{self, BodyLimits, MultipartParser, StreamedMultipart};
+use crate::http::{Limits, Method, ParseError, Request, Version, MAX_LINE_LEN};
+use crate::response::{Body, Response};
+use crate::router::Handler;
+use crate::server::{error_response, IpSlot, Options, PerIp, Tracker};
+use crate::{ExecuteError, LogLevel, ThreadPool};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+use std::net::{self, IpAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// worker 处理完请求后唤醒 I/O 线程用的 token，监听地址从 0 开始编号
+const WAKER: Token = Token(usize::MAX);
+
+/// 没有事件时多久检查一次超时
+const TICK: Duration = Duration::from_millis(100);
+
+/// 每次从 socket 读取的字节数
+const READ_CHUNK: usize = 16 * 1024;
+
+/// 写出响应体时每次从 reader 读取的字节数
+const WRITE_CHUNK: usize = 64 * 1024;
+
+/// 在 listeners 上接受并处理连接，直到 tracker 开始关闭且所有连接都处理完，
+/// 或者超过关闭期限
+pub(crate) fn serve(
+    listeners: Vec<net::TcpListener>,
+    handler: Arc<dyn Handler>,
+    pool: &ThreadPool,
+    options: &Options,
+    per_ip: &Arc<PerIp>,
+    tracker: &Tracker,
+    shutdown_timeout: Duration,
+) -> io::Result<()> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    let mut registered = Vec::with_capacity(listeners.len());
+    for (i, listener) in listeners.into_iter().enumerate() {
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry()
+            .register(&mut listener, Token(i), Interest::READABLE)?;
+        registered.push(listener);
+    }
+
+    let (done, finished) = mpsc::channel();
+    let (refilled, refills) = mpsc::channel();
+    let mut event_loop = EventLoop {
+        poll,
+        next_token: registered.len(),
+        listeners: registered,
+        connections: HashMap::new(),
+        handler,
+        pool,
+        options,
+        per_ip,
+        tracker,
+        waker,
+        done,
+        finished,
+        refilled,
+        refills,
+    };
+    event_loop.run(shutdown_timeout)
+}
+
+/// worker 处理完请求后交回 I/O 线程的结果
+struct Done {
+    token: Token,
+    request: Request,
+    response: Response,
+    received: (SystemTime, Instant),
+}
+
+/// worker 取出下一段响应体后交回 I/O 线程的结果
+struct Refilled {
+    token: Token,
+    out: Box<Outgoing>,
+    result: io::Result<()>,
+}
+
+/// 响应对应的请求，写完后计入 tracker 和访问日志
+type Served = (Request, (SystemTime, Instant), u16);
+
+struct EventLoop<'a> {
+    poll: Poll,
+    listeners: Vec<TcpListener>,
+    /// token 只增不减，不会复用，迟到的 Done 不会交给别的连接
+    next_token: usize,
+    connections: HashMap<Token, Connection>,
+    handler: Arc<dyn Handler>,
+    pool: &'a ThreadPool,
+    options: &'a Options,
+    per_ip: &'a Arc<PerIp>,
+    tracker: &'a Tracker,
+    waker: Arc<Waker>,
+    done: Sender<Done>,
+    finished: Receiver<Done>,
+    refilled: Sender<Refilled>,
+    refills: Receiver<Refilled>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    client: Option<IpAddr>,
+    /// 占用的每 IP 名额，连接关闭时归还
+    _slot: IpSlot,
+    /// 已经读到但还没有解析的数据，分块请求体解码过的部分随即从中移除
+    buf: Vec<u8>,
+    /// 分块请求体解码到一半时的进度
+    partial: Option<Partial>,
+    state: State,
+    /// 这个连接上已经开始处理的请求数
+    served: usize,
+    /// 当前请求的请求头期限从这时开始算
+    started: Instant,
+    /// 最近一次读到或写出数据的时间
+    active: Instant,
+}
+
+impl Connection {
+    /// 持久连接上两个请求之间，还没有收到下一个请求的任何数据
+    fn is_idle(&self) -> bool {
+        self.served > 0 && self.buf.is_empty() && self.partial.is_none()
+    }
+}
+
+enum State {
+    /// 等待或正在读取请求
+    Reading,
+    /// 请求已经交给线程池
+    Handling,
+    /// 正在写出响应
+    Writing(Box<Outgoing>),
+    /// 已经写出的部分发完了，worker 正在取下一段响应体
+    Refilling(Option<Box<Served>>),
+}
+
+/// 正在写出的响应
+struct Outgoing {
+    buf: Vec<u8>,
+    pos: usize,
+    /// buf 写完之后从哪里取后面的数据
+    source: Source,
+    /// 已经放进 buf 的响应体字节数，不含分块的格式
+    body_bytes: u64,
+    /// 写完之后是否保持连接
+    keep: bool,
+    /// 由 handler 生成的响应带着对应的请求，解析失败时的错误响应没有
+    request: Option<Served>,
+}
+
+/// Outgoing::write 的结果
+enum Progress {
+    /// 全部写完
+    Done,
+    /// socket 写满了
+    Blocked,
+    /// 已经取出的数据写完了，需要从 source 取下一段
+    Empty,
+}
+
+enum Source {
+    Done,
+    /// reader 和其中还没有读出的字节数
+    Reader(Box<dyn Read + Send>, u64),
+    /// 分块的响应体，第二项为 false 时（HTTP/1.0 的客户端）不加分块的格式
+    Chunked(Box<dyn ChunkedBody>, bool),
+}
+
+impl Outgoing {
+    fn new(response: Response, head_only: bool, keep: bool, chunked: bool) -> Outgoing {
+        let mut buf = response.head(chunked).into_bytes();
+        let mut body_bytes = 0;
+        let source = match response.body {
+            _ if head_only => Source::Done,
+            Body::Empty => Source::Done,
+            Body::Bytes(bytes) => {
+                buf.extend_from_slice(&bytes);
+                body_bytes = bytes.len() as u64;
+                Source::Done
+            }
+            Body::Reader(reader, len) => Source::Reader(reader, len),
+            Body::Chunked(body) => Source::Chunked(body, chunked),
+        };
+
+        Outgoing {
+            buf,
+            pos: 0,
+            source,
+            body_bytes,
+            keep,
+            request: None,
+        }
+    }
+
+    /// 尽量多写 buf 中的数据
+    ///
+    /// 取下一段数据可能阻塞，由调用方交给 worker 调用 `refill`，不在这里做。
+    fn write(&mut self, stream: &mut TcpStream) -> io::Result<Progress> {
+        loop {
+            if self.pos == self.buf.len() {
+                return Ok(match self.source {
+                    Source::Done | Source::Reader(_, 0) => Progress::Done,
+                    _ => Progress::Empty,
+                });
+            }
+
+            match stream.write(&self.buf[self.pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.pos += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Progress::Blocked),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// buf 写完之后取下一段数据，在 worker 上调用
+    fn refill(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+
+        match &mut self.source {
+            Source::Done => {}
+            Source::Reader(_, 0) => self.source = Source::Done,
+            Source::Reader(reader, remaining) => {
+                let want = (*remaining).min(WRITE_CHUNK as u64) as usize;
+                self.buf.resize(want, 0);
+                let n = reader.read(&mut self.buf)?;
+                if n == 0 {
+                    // 文件在读取过程中变短了，已经发出的 Content-Length 对不上
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body shorter than content-length",
+                    ));
+                }
+                self.buf.truncate(n);
+                *remaining -= n as u64;
+                self.body_bytes += n as u64;
+            }
+            Source::Chunked(body, framed) => {
+                let mut data = Vec::new();
+                let more = body.next_chunk(&mut data)?;
+                self.body_bytes += data.len() as u64;
+                if !*framed {
+                    self.buf = data;
+                } else {
+                    if !data.is_empty() {
+                        chunked::encode_chunk(&data, &mut self.buf);
+                    }
+                    if !more {
+                        chunked::encode_last(&body.trailers(), &mut self.buf);
+                    }
+                }
+                if !more {
+                    self.source = Source::Done;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventLoop<'_> {
+    fn run(&mut self, shutdown_timeout: Duration) -> io::Result<()> {
+        let options = self.options;
+        let log = &*options.log;
+        let mut events = Events::with_capacity(1024);
+        let mut stopping: Option<Instant> = None;
+
+        loop {
+            match self.poll.poll(&mut events, Some(TICK)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {}
+                    Token(i) if i < self.listeners.len() => self.accept(i),
+                    token => self.ready(token),
+                }
+            }
+            // 唤醒可能被合并，每一轮都把已经处理完的请求取出来
+            self.finish_handled();
+
+            if stopping.is_none() && self.tracker.is_shutting_down() {
+                log(
+                    LogLevel::Info,
+                    format_args!("Shutting down, waiting for in-flight requests."),
+                );
+                for mut listener in self.listeners.drain(..) {
+                    let _ = self.poll.registry().deregister(&mut listener);
+                }
+                // 还没有请求在处理的连接直接关闭
+                let idle: Vec<Token> = self
+                    .connections
+                    .iter()
+                    .filter(|(_, conn)| matches!(conn.state, State::Reading))
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in idle {
+                    if let Some(conn) = self.connections.remove(&token) {
+                        self.close(conn);
+                    }
+                }
+                stopping = Some(Instant::now());
+            }
+
+            self.expire(Instant::now());
+
+            if let Some(since) = stopping {
+                if self.connections.is_empty() {
+                    return Ok(());
+                }
+                if since.elapsed() >= shutdown_timeout {
+                    log(
+                        LogLevel::Warn,
+                        format_args!("Shutdown timeout reached, aborting remaining connections."),
+                    );
+                    // 通知还在执行的 handler 放弃；排队中的请求会在下面随连接一起计为放弃
+                    self.pool.shutdown_now();
+                    let tokens: Vec<Token> = self.connections.keys().copied().collect();
+                    for token in tokens {
+                        if let Some(conn) = self.connections.remove(&token) {
+                            self.close(conn);
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// 接受 listener 上所有等待中的连接
+    fn accept(&mut self, index: usize) {
+        loop {
+            let (mut stream, addr) = match self.listeners[index].accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    (self.options.log)(
+                        LogLevel::Warn,
+                        format_args!("Failed to accept connection: {}", e),
+                    );
+                    return;
+                }
+            };
+            // 唤醒 accept 的那个连接也在这里被丢弃
+            if self.tracker.is_shutting_down() {
+                continue;
+            }
+
+            let slot = match self.per_ip.acquire(addr.ip()) {
+                Some(slot) => slot,
+                None => {
+                    // 新连接的发送缓冲区是空的，一次就能写完
+                    let _ = Response::text(429, "429 Too Many Requests")
+                        .with_header("Retry-After", "1")
+                        .with_header("Connection", "close")
+                        .write_to(&mut stream, false);
+                    continue;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, interest) {
+                (self.options.log)(
+                    LogLevel::Warn,
+                    format_args!("Failed to register connection: {}", e),
+                );
+                continue;
+            }
+
+            let now = Instant::now();
+            let conn = Connection {
+                stream,
+                client: Some(addr.ip()),
+                _slot: slot,
+                buf: Vec::new(),
+                partial: None,
+                state: State::Reading,
+                served: 0,
+                started: now,
+                active: now,
+            };
+            // 数据可能已经随连接一起到达，先试着读一次
+            self.advance(token, conn);
+        }
+    }
+
+    fn ready(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            self.advance(token, conn);
+        }
+    }
+
+    /// 推进连接的状态，仍然打开的连接放回表中
+    fn advance(&mut self, token: Token, mut conn: Connection) {
+        if self.drive(token, &mut conn) {
+            self.connections.insert(token, conn);
+        } else {
+            self.close(conn);
+        }
+    }
+
+    /// 尽可能推进连接的状态，返回 false 表示连接应该关闭
+    fn drive(&mut self, token: Token, conn: &mut Connection) -> bool {
+        loop {
+            match &mut conn.state {
+                State::Reading => {
+                    // 先看缓冲区里是否已经有完整的请求（pipelining），没有再从 socket 读
+                    match parse_buffered(
+                        &mut conn.buf,
+                        &mut conn.partial,
+                        &self.options.limits,
+                        &self.options.body_limits,
+                    ) {
+                        Ok(Some(request)) => {
+                            self.dispatch(token, conn, request);
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            conn.state = State::Writing(Box::new(Outgoing::new(
+                                error_response(&e),
+                                false,
+                                false,
+                                true,
+                            )));
+                            continue;
+                        }
+                    }
+
+                    let len = conn.buf.len();
+                    conn.buf.resize(len + READ_CHUNK, 0);
+                    let result = conn.stream.read(&mut conn.buf[len..]);
+                    conn.buf.truncate(len + *result.as_ref().unwrap_or(&0));
+                    match result {
+                        // 客户端关闭了连接，读到一半的请求也一起放弃
+                        Ok(0) => return false,
+                        Ok(_) => {
+                            // 后续请求的请求头期限从第一个字节到达时开始算
+                            if len == 0 && conn.partial.is_none() && conn.served > 0 {
+                                conn.started = Instant::now();
+                            }
+                            conn.active = Instant::now();
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => return false,
+                    }
+                }
+                State::Handling | State::Refilling(_) => return true,
+                State::Writing(out) => {
+                    let before = (out.pos, out.body_bytes);
+                    let result = out.write(&mut conn.stream);
+                    if (out.pos, out.body_bytes) != before {
+                        conn.active = Instant::now();
+                    }
+                    match result {
+                        Ok(Progress::Done) => {}
+                        Ok(Progress::Blocked) => return true,
+                        Ok(Progress::Empty) => return self.refill(token, conn),
+                        Err(e) => {
+                            (self.options.log)(
+                                LogLevel::Warn,
+                                format_args!("Failed to write response: {}", e),
+                            );
+                            return false;
+                        }
+                    }
+
+                    self.complete(conn.client, out.request.take(), out.body_bytes, true);
+                    if !out.keep || self.tracker.is_shutting_down() {
+                        return false;
+                    }
+                    conn.state = State::Reading;
+                    conn.started = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// 把请求交给线程池，队列满时回应 503
+    fn dispatch(&mut self, token: Token, conn: &mut Connection, request: Request) {
+        conn.served += 1;
+        self.tracker.begin_request();
+        let received = (SystemTime::now(), Instant::now());
+
+        let handler = Arc::clone(&self.handler);
+        let done = self.done.clone();
+        let waker = Arc::clone(&self.waker);
+        let result = self.pool.execute(move || {
+            let mut request = request;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| handler.handle(&mut request)));
+            // handler panic 时先回应 500，再把 panic 交给线程池记录
+            let (response, panicked) = match result {
+                Ok(response) => (response, None),
+                Err(payload) => (
+                    Response::text(500, "500 Internal Server Error")
+                        .with_header("Connection", "close"),
+                    Some(payload),
+                ),
+            };
+            // I/O 线程已经退出时结果直接丢弃
+            let finished = Done {
+                token,
+                request,
+                response,
+                received,
+            };
+            if done.send(finished).is_ok() {
+                let _ = waker.wake();
+            }
+            if let Some(payload) = panicked {
+                panic::resume_unwind(payload);
+            }
+        });
+
+        match result {
+            Ok(()) => conn.state = State::Handling,
+            Err(e) => {
+                // 请求随任务一起被丢弃了
+                self.tracker.end_request(false);
+                if !matches!(e, ExecuteError::QueueFull) {
+                    (self.options.log)(
+                        LogLevel::Warn,
+                        format_args!("Failed to dispatch request: {}", e),
+                    );
+                }
+                let response = Response::text(503, "503 Service Unavailable")
+                    .with_header("Retry-After", "1")
+                    .with_header("Connection", "close");
+                conn.state = State::Writing(Box::new(Outgoing::new(response, false, false, true)));
+            }
+        }
+    }
+
+    /// 把取下一段响应体的工作交给线程池，返回 false 表示连接应该关闭
+    ///
+    /// 响应体由 handler 提供，可能阻塞在数据库、channel 或很慢的文件上，也可能 panic，
+    /// 都只影响这一个连接。
+    fn refill(&mut self, token: Token, conn: &mut Connection) -> bool {
+        let mut out = match mem::replace(&mut conn.state, State::Handling) {
+            State::Writing(out) => out,
+            _ => unreachable!("refill is only called while writing"),
+        };
+        let request = out.request.take();
+
+        let refilled = self.refilled.clone();
+        let waker = Arc::clone(&self.waker);
+        let result = self.pool.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| out.refill()));
+            let (result, panicked) = match result {
+                Ok(result) => (result, None),
+                Err(payload) => {
+                    let e = io::Error::other("response body panicked");
+                    (Err(e), Some(payload))
+                }
+            };
+            // panic 之后的 source 不再使用，在这里而不是 I/O 线程上释放
+            let source = match panicked {
+                Some(_) => mem::replace(&mut out.source, Source::Done),
+                None => Source::Done,
+            };
+            if refilled.send(Refilled { token, out, result }).is_ok() {
+                let _ = waker.wake();
+            }
+            drop(source);
+            // 和 handler 一样交给线程池记录
+            if let Some(payload) = panicked {
+                panic::resume_unwind(payload);
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                conn.state = State::Refilling(request.map(Box::new));
+                true
+            }
+            Err(e) => {
+                (self.options.log)(
+                    LogLevel::Warn,
+                    format_args!("Failed to dispatch response body: {}", e),
+                );
+                self.complete(conn.client, request, 0, false);
+                conn.state = State::Reading;
+                false
+            }
+        }
+    }
+
+    /// 取出 worker 处理完的请求和取出的响应体，开始或继续写出响应
+    fn finish_handled(&mut self) {
+        while let Ok(refilled) = self.refills.try_recv() {
+            let mut conn = match self.connections.remove(&refilled.token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let Refilled {
+                token,
+                mut out,
+                result,
+            } = refilled;
+            if let State::Refilling(request) = &mut conn.state {
+                out.request = request.take().map(|request| *request);
+            }
+            conn.state = State::Writing(out);
+            conn.active = Instant::now();
+
+            match result {
+                Ok(()) => self.advance(token, conn),
+                Err(e) => {
+                    (self.options.log)(
+                        LogLevel::Warn,
+                        format_args!("Failed to read response body: {}", e),
+                    );
+                    self.close(conn);
+                }
+            }
+        }
+
+        while let Ok(done) = self.finished.try_recv() {
+            // 连接已经在关闭超时时被放弃了
+            let mut conn = match self.connections.remove(&done.token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            let Done {
+                token,
+                request,
+                mut response,
+                received,
+            } = done;
+            self.options.log_error(&request, &response);
+            let head_only = request.method == Method::Head;
+            let keep =
+                self.options
+                    .keep_connection(&request, &mut response, conn.served, self.tracker);
+            let status = response.status;
+            let chunked = request.version == Version::Http11;
+            let mut out = Outgoing::new(response, head_only, keep, chunked);
+            out.request = Some((request, received, status));
+            conn.state = State::Writing(Box::new(out));
+
+            self.advance(token, conn);
+        }
+    }
+
+    /// 一个响应写完或放弃时，计入 tracker 并记录访问日志，bytes 是写出的响应体字节数
+    fn complete(
+        &self,
+        client: Option<IpAddr>,
+        request: Option<Served>,
+        bytes: u64,
+        completed: bool,
+    ) {
+        if let Some((request, received, status)) = request {
+            self.tracker.end_request(completed);
+            let bytes = if completed { bytes } else { 0 };
+            self.options
+                .log_access(client, received, &request, status, bytes);
+        }
+    }
+
+    fn close(&mut self, mut conn: Connection) {
+        match &mut conn.state {
+            State::Writing(out) => self.complete(conn.client, out.request.take(), 0, false),
+            State::Refilling(request) => {
+                self.complete(conn.client, request.take().map(|r| *r), 0, false)
+            }
+            State::Handling => self.tracker.end_request(false),
+            State::Reading => {}
+        }
+        let _ = self.poll.registry().deregister(&mut conn.stream);
+    }
+
+    /// 关闭空闲太久的连接，请求读得太慢时回应 408，写不出去的连接直接关闭
+    fn expire(&mut self, now: Instant) {
+        let timeouts = &self.options.timeouts;
+        let keep_alive = self.options.keep_alive.timeout;
+        let mut expired = Vec::new();
+
+        for (token, conn) in &self.connections {
+            let deadline = match &conn.state {
+                State::Reading if conn.is_idle() => conn.active + keep_alive,
+                State::Reading => {
+                    let read = conn.active + timeouts.read;
+                    if conn.partial.is_some() || head_end(&conn.buf).is_some() {
+                        read
+                    } else {
+                        read.min(conn.started + timeouts.header)
+                    }
+                }
+                State::Handling | State::Refilling(_) => continue,
+                State::Writing(_) => conn.active + timeouts.write,
+            };
+            if now >= deadline {
+                expired.push(*token);
+            }
+        }
+
+        for token in expired {
+            let mut conn = match self.connections.remove(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            match conn.state {
+                // 请求读到一半（或者第一个请求一直没来）时回应 408，空闲的持久连接直接关闭
+                State::Reading if !conn.is_idle() => {
+                    let response = error_response(&ParseError::Timeout);
+                    conn.state =
+                        State::Writing(Box::new(Outgoing::new(response, false, false, true)));
+                    conn.active = now;
+                    self.advance(token, conn);
+                }
+                _ => self.close(conn),
+            }
+        }
+    }
+}
+
+/// 请求头结束的位置（空行之后），还没有读到空行时返回 None
+fn head_end(buf: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(i) = buf[start..].iter().position(|&b| b == b'\n') {
+        let line = &buf[start..start + i];
+        if line.is_empty() || line == b"\r" {
+            return Some(start + i + 1);
+        }
+        start += i + 1;
+    }
+    None
+}
+
+/// 请求头已经解析、请求体还没收完的请求
+///
+/// multipart 请求体边收边交给 upload 解析，文件内容在 I/O 线程上直接写进临时文件，
+/// 和 nginx 把大请求体写到磁盘一样，不会整个放在内存中。
+struct Partial {
+    request: Request,
+    body: PartialBody,
+    upload: Option<MultipartParser>,
+}
+
+enum PartialBody {
+    Chunked(ChunkedDecoder),
+    /// 按 Content-Length 还要收多少字节，只用于 multipart
+    Sized(usize),
+}
+
+impl Partial {
+    /// 处理缓冲区中的数据并移除用掉的部分，返回请求体是否已经收完
+    fn advance(&mut self, buf: &mut Vec<u8>, limits: &Limits) -> Result<bool, ParseError> {
+        let limits = match self.upload {
+            Some(_) => self.request.upload_limits(limits),
+            None => *limits,
+        };
+        match &mut self.body {
+            PartialBody::Chunked(decoder) => {
+                let used = decoder.decode(buf, &limits)?;
+                buf.drain(..used);
+                if let Some(upload) = &mut self.upload {
+                    upload
+                        .feed(&decoder.take_body())
+                        .map_err(ParseError::Upload)?;
+                }
+                Ok(decoder.is_done())
+            }
+            PartialBody::Sized(remaining) => {
+                let n = buf.len().min(*remaining);
+                let upload = self.upload.as_mut().expect("only uploads are sized");
+                upload.feed(&buf[..n]).map_err(ParseError::Upload)?;
+                buf.drain(..n);
+                *remaining -= n;
+                Ok(*remaining == 0)
+            }
+        }
+    }
+}
+
+/// 从缓冲区解析一个完整的请求并移除用掉的数据，数据还不完整时返回 None
+///
+/// 分块和 multipart 的请求体没收完时把进度留在 partial 中，处理过的数据随即移除，
+/// 缓冲区中只剩还不完整的一块，下次从那里接着处理。
+fn parse_buffered(
+    buf: &mut Vec<u8>,
+    partial: &mut Option<Partial>,
+    limits: &Limits,
+    body_limits: &BodyLimits,
+) -> Result<Option<Request>, ParseError> {
+    if partial.is_none() {
+        let head_len = match head_end(buf) {
+            Some(len) => len,
+            None if buf.len() > MAX_LINE_LEN + limits.max_header_size => {
+                return Err(ParseError::HeadersTooLarge)
+            }
+            None => return Ok(None),
+        };
+
+        let mut request = Request::read_head(&mut Cursor::new(&buf[..head_len]), limits)?;
+        request.body_limits = *body_limits;
+        let upload = extract::upload_parser(&request);
+        let body = if request.is_chunked()? {
+            PartialBody::Chunked(ChunkedDecoder::default())
+        } else if upload.is_some() {
+            let len = request.content_length()?;
+            if len > request.upload_limits(limits).max_body_size {
+                return Err(ParseError::BodyTooLarge);
+            }
+            PartialBody::Sized(len)
+        } else {
+            return parse_sized(request, buf, head_len, limits);
+        };
+        buf.drain(..head_len);
+        *partial = Some(Partial {
+            request,
+            body,
+            upload,
+        });
+    }
+
+    let progress = partial.as_mut().expect("set above");
+    match progress.advance(buf, limits) {
+        Ok(true) => {}
+        Ok(false) => return Ok(None),
+        Err(e) => {
+            *partial = None;
+            return Err(e);
+        }
+    }
+
+    let Partial {
+        mut request,
+        body,
+        upload,
+    } = partial.take().expect("checked above");
+    if let PartialBody::Chunked(decoder) = body {
+        let (body, trailers) = decoder.finish();
+        request.body = body;
+        request.trailers = trailers;
+    }
+    if let Some(upload) = upload {
+        let multipart = upload.finish().map_err(ParseError::Upload)?;
+        request.multipart = Some(StreamedMultipart::new(multipart));
+    }
+    Ok(Some(request))
+}
+
+/// 按 Content-Length 读取请求体，读完后从缓冲区移除请求，数据还不完整时返回 None
+fn parse_sized(
+    mut request: Request,
+    buf: &mut Vec<u8>,
+    head_len: usize,
+    limits: &Limits,
+) -> Result<Option<Request>, ParseError> {
+    let len = request.content_length()?;
+    if len > limits.max_body_size {
+        return Err(ParseError::BodyTooLarge);
+    }
+    if buf.len() - head_len < len {
+        return Ok(None);
+    }
+    request.read_body(&mut Cursor::new(&buf[head_len..head_len + len]), limits)?;
+    buf.drain(..head_len + len);
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock;
+    use crate::server::{Mode, Server, ShutdownReport, Timeouts};
+    use std::io::BufRead;
+    use std::sync::Mutex;
+    use std::thread;
+
+    fn start(
+        server: Server,
+    ) -> (
+        net::SocketAddr,
+        crate::server::ShutdownHandle,
+        thread::JoinHandle<ShutdownReport>,
+    ) {
+        let server = server.mode(Mode::EventLoop);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run().unwrap());
+        (addr, handle, running)
+    }
+
+    /// 每次都从头解析，不保留分块请求体的进度，返回请求和用掉的字节数
+    fn parse(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ParseError> {
+        let mut rest = buf.to_vec();
+        let request = parse_buffered(&mut rest, &mut None, limits, &BodyLimits::default())?;
+        Ok(request.map(|request| (request, buf.len() - rest.len())))
+    }
+
+    #[test]
+    fn parses_requests_as_they_arrive() {
+        let limits = Limits::default();
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n";
+
+        // 请求头或请求体不完整时等待更多数据
+        for len in [0, 10, 38, 40].iter() {
+            assert!(parse(&raw[..*len], &limits).unwrap().is_none());
+        }
+
+        let (request, used) = parse(raw, &limits).unwrap().unwrap();
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.body, b"abc");
+        let (request, rest) = parse(&raw[used..], &limits).unwrap().unwrap();
+        assert_eq!(request.path, "/b");
+        assert_eq!(used + rest, raw.len());
+
+        let small = Limits {
+            max_body_size: 2,
+            ..Limits::default()
+        };
+        assert!(matches!(parse(&raw[..20], &small), Ok(None)));
+        assert!(matches!(parse(raw, &small), Err(ParseError::BodyTooLarge)));
+        let endless = vec![b'a'; MAX_LINE_LEN + limits.max_header_size + 1];
+        assert!(matches!(
+            parse(&endless, &limits),
+            Err(ParseError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn parses_chunked_requests() {
+        let limits = Limits::default();
+        let raw = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nX-Sum: 3\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+
+        for len in [50, 60, 70].iter() {
+            assert!(parse(&raw[..*len], &limits).unwrap().is_none());
+        }
+        let (request, used) = parse(raw, &limits).unwrap().unwrap();
+        assert_eq!(request.body, b"abc");
+        assert_eq!(request.trailers.get("x-sum").map(String::as_str), Some("3"));
+        let (request, rest) = parse(&raw[used..], &limits).unwrap().unwrap();
+        assert_eq!(request.path, "/b");
+        assert_eq!(used + rest, raw.len());
+
+        // 数据分很多次到达时接着上次的进度解码，每块只解码一次，解码过的数据随即移除
+        let end = raw.len() - "GET /b HTTP/1.1\r\n\r\n".len();
+        let mut buf = Vec::new();
+        let mut partial = None;
+        for &byte in &raw[..end - 1] {
+            buf.push(byte);
+            assert!(
+                parse_buffered(&mut buf, &mut partial, &limits, &BodyLimits::default())
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        // 只差最后的空行
+        assert_eq!(buf, b"\r");
+        buf.extend_from_slice(&raw[end - 1..]);
+        let request = parse_buffered(&mut buf, &mut partial, &limits, &BodyLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body, b"abc");
+        assert_eq!(buf, b"GET /b HTTP/1.1\r\n\r\n");
+        assert!(partial.is_none());
+
+        // 每块一个字节、带着很长扩展的请求体不会一直堆在缓冲区里，原始数据太多时回应 413
+        let small = Limits {
+            max_body_size: 1024,
+            ..Limits::default()
+        };
+        let mut buf = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let mut partial = None;
+        let chunk = format!("1;{}\r\na\r\n", "x".repeat(MAX_LINE_LEN - 10));
+        let result = loop {
+            buf.extend_from_slice(chunk.as_bytes());
+            match parse_buffered(&mut buf, &mut partial, &small, &BodyLimits::default()) {
+                Ok(None) => assert!(buf.is_empty()),
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(ParseError::BodyTooLarge)));
+        assert_eq!(ParseError::BodyTooLarge.status(), 413);
+
+        let gzip = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert!(matches!(
+            parse(gzip, &limits),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+    }
+
+    #[test]
+    fn streams_chunked_responses_in_both_modes() {
+        for mode in [Mode::Threads, Mode::EventLoop].iter() {
+            let handler = |req: &mut Request| {
+                let mut parts = vec![req.body.clone(), b"-".to_vec(), b"done".to_vec()].into_iter();
+                Response::new(200).with_chunked(move |buf: &mut Vec<u8>| {
+                    buf.extend(parts.next().unwrap_or_default());
+                    Ok(parts.len() > 0)
+                })
+            };
+            let server = Server::bind("127.0.0.1:0", handler).unwrap().mode(*mode);
+            let addr = server.local_addr().unwrap();
+            let handle = server.shutdown_handle().unwrap();
+            let running = thread::spawn(move || server.run().unwrap());
+
+            let mut client = net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\nPOST / HTTP/1.0\r\nContent-Length: 3\r\n\r\nold")
+                .unwrap();
+            let mut out = String::new();
+            client.read_to_string(&mut out).unwrap();
+
+            // HTTP/1.1 分块发送并保持连接，HTTP/1.0 不加分块格式，以关闭连接结束响应体
+            let first = "Transfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n1\r\n-\r\n4\r\ndone\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n";
+            assert!(out.contains(first), "{}: {}", mode, out);
+            assert!(
+                out.ends_with("Connection: close\r\n\r\nold-done"),
+                "{}: {}",
+                mode,
+                out
+            );
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn multiplexes_idle_connections_on_one_worker() {
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::bind("127.0.0.1:0", handler).unwrap().workers(1);
+        let (addr, handle, running) = start(server);
+
+        // 空闲的持久连接不占 worker，唯一的 worker 仍然可以处理新的请求
+        let mut idle = Vec::new();
+        for _ in 0..50 {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+            idle.push(stream);
+        }
+        for stream in &mut idle {
+            let mut reader = io::BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+        }
+
+        let mut client = net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        let a = out.find("\r\n\r\n/a").unwrap();
+        let b = out.find("\r\n\r\n/b").unwrap();
+        assert!(a < b);
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 2\r\n\r\n/b"));
+
+        handle.shutdown();
+        let report = running.join().unwrap();
+        assert_eq!(report.aborted, 0);
+    }
+
+    #[test]
+    fn streams_large_bodies_and_answers_errors() {
+        let body: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let handler = move |req: &mut Request| match req.path.as_str() {
+            "/big" => Response::new(200).with_reader(Cursor::new(body.clone()), body.len() as u64),
+            _ => Response::text(200, "ok"),
+        };
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .max_request_size(4)
+            .timeouts(Timeouts {
+                header: Duration::from_millis(200),
+                ..Timeouts::default()
+            });
+        let (addr, handle, running) = start(server);
+
+        let mut client = net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /big HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = Vec::new();
+        client.read_to_end(&mut out).unwrap();
+        let head_len = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(out.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(out[head_len..] == expected[..]);
+
+        let mut client = net::TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        // 请求头一直没有发完
+        let mut client = net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(
+            out.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            out
+        );
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn slow_or_panicking_bodies_do_not_stall_other_connections() {
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        let handler = move |req: &mut Request| match req.path.as_str() {
+            "/slow" => {
+                let blocked = Arc::clone(&blocked);
+                Response::new(200).with_chunked(move |buf: &mut Vec<u8>| {
+                    let _ = lock(&blocked).recv();
+                    buf.extend_from_slice(b"slow");
+                    Ok(false)
+                })
+            }
+            "/panic" => Response::new(200).with_chunked(|_: &mut Vec<u8>| -> io::Result<bool> {
+                panic!("body generator failed")
+            }),
+            path => Response::text(200, path),
+        };
+        let server = Server::bind("127.0.0.1:0", handler).unwrap().workers(2);
+        let (addr, handle, running) = start(server);
+        let get = |path: &str| {
+            let mut client = net::TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            client.write_all(request.as_bytes()).unwrap();
+            client
+        };
+
+        // 响应体一直等不到数据时，其他连接照常处理
+        let mut slow = get("/slow");
+        thread::sleep(Duration::from_millis(100));
+        let mut out = String::new();
+        get("/fast").read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\n/fast"), "{}", out);
+
+        // 响应体 panic 时只关闭这个连接，响应没有结束块
+        let mut out = String::new();
+        get("/panic").read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{}", out);
+        assert!(
+            out.ends_with("Transfer-Encoding: chunked\r\n\r\n"),
+            "{}",
+            out
+        );
+        let mut out = String::new();
+        get("/after").read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\n/after"), "{}", out);
+
+        release.send(()).unwrap();
+        let mut out = String::new();
+        slow.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\n4\r\nslow\r\n0\r\n\r\n"), "{}", out);
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_drains_in_flight_requests() {
+        let handler = |req: &mut Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(200, &req.path)
+        };
+        let server = Server::bind("127.0.0.1:0", handler).unwrap().workers(2);
+        let (addr, handle, running) = start(server);
+
+        let _idle = net::TcpStream::connect(addr).unwrap();
+        let mut client = net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.contains("Connection: close"));
+        assert!(out.ends_with("/slow"));
+
+        assert_eq!(
+            running.join().unwrap(),
+            ShutdownReport {
+                drained: 1,
+                aborted: 0
+            }
+        );
+    }
+}
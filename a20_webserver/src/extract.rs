@@ -0,0 +1,942 @@
+//! 把请求体解析成表单、multipart 或 JSON
+//!
+//! 通过 [`Request::extract`](crate::http::Request::extract) 使用：
+//! Content-Type 不符时返回 415，超过 [`BodyLimits`] 时返回 413，格式错误时返回 400，
+//! 出错时可以直接把 [`ExtractError`] 转成响应。
+//!
+//! ```
+//! use a20_webserver::extract::{Form, Json};
+//! use a20_webserver::http::Request;
+//! use a20_webserver::response::Response;
+//!
+//! fn search(req: &mut Request) -> Response {
+//!     match req.extract::<Form>() {
+//!         Ok(form) => Response::text(200, form.get("q").unwrap_or("")),
+//!         Err(e) => e.into(),
+//!     }
+//! }
+//!
+//! fn tags(req: &mut Request) -> Response {
+//!     match req.extract::<Json<Vec<String>>>() {
+//!         Ok(Json(tags)) => Response::text(200, &tags.join(",")),
+//!         Err(e) => e.into(),
+//!     }
+//! }
+//! ```
+
+use crate::http::{percent_decode, Request};
+use crate::lock;
+use crate::response::{self, Response};
+
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+/// multipart 中每个部分的部分头最多的字节数
+const MAX_PART_HEADER_SIZE: usize = 16 * 1024;
+
+/// 各种请求体的大小上限
+///
+/// 服务器的上限由 [`Server::body_limits`](crate::server::Server::body_limits) 设置，
+/// 也是 [`Request::extract`] 默认使用的上限。表单和 JSON 请求体要先整个读进内存，
+/// 还受 `max_request_size` 的限制；multipart 请求体由服务器边读边解析，
+/// 文件直接写进临时文件，整个请求体只受 `max_multipart_size` 的限制。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyLimits {
+    /// urlencoded 表单的字节数
+    pub max_form_size: usize,
+    /// JSON 请求体的字节数
+    pub max_json_size: usize,
+    /// multipart 中最多有几个部分
+    pub max_parts: usize,
+    /// multipart 中每个普通字段的字节数
+    pub max_field_size: usize,
+    /// multipart 中每个文件的字节数
+    pub max_file_size: u64,
+    /// 整个 multipart 请求体的字节数
+    pub max_multipart_size: usize,
+}
+
+impl Default for BodyLimits {
+    /// 表单 64KB，JSON 1MB，multipart 最多 100 个部分，每个字段 64KB，每个文件 10MB，
+    /// 整个 multipart 请求体 32MB
+    fn default() -> BodyLimits {
+        BodyLimits {
+            max_form_size: 64 * 1024,
+            max_json_size: 1024 * 1024,
+            max_parts: 100,
+            max_field_size: 64 * 1024,
+            max_file_size: 10 * 1024 * 1024,
+            max_multipart_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+/// 解析请求体时的错误
+#[derive(Debug)]
+pub enum ExtractError {
+    /// Content-Type 不是期望的类型，对应 415
+    UnsupportedMediaType { expected: &'static str },
+    /// 请求体或其中的一部分超过上限，对应 413
+    TooLarge { limit: u64 },
+    /// 请求体格式不对，对应 400
+    Invalid(String),
+    /// 写临时文件失败，对应 500
+    Io(io::Error),
+}
+
+impl ExtractError {
+    /// 回应客户端时使用的状态码
+    pub fn status(&self) -> u16 {
+        match self {
+            ExtractError::UnsupportedMediaType { .. } => 415,
+            ExtractError::TooLarge { .. } => 413,
+            ExtractError::Invalid(_) => 400,
+            ExtractError::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractError::UnsupportedMediaType { expected } => {
+                write!(f, "expected content-type {}", expected)
+            }
+            ExtractError::TooLarge { limit } => write!(f, "body exceeds {} bytes", limit),
+            ExtractError::Invalid(reason) => write!(f, "invalid body: {}", reason),
+            ExtractError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl Error for ExtractError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExtractError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> ExtractError {
+        ExtractError::Io(e)
+    }
+}
+
+impl From<ExtractError> for Response {
+    /// 4xx 带上原因，方便调用方排查；500 不暴露服务器内部的细节
+    fn from(e: ExtractError) -> Response {
+        let status = e.status();
+        let text = match e {
+            ExtractError::Io(_) => format!("{} {}", status, response::reason_phrase(status)),
+            e => format!("{} {}: {}", status, response::reason_phrase(status), e),
+        };
+        Response::text(status, &text)
+    }
+}
+
+/// 可以从请求中解析出来的类型
+pub trait FromRequest: Sized {
+    fn from_request(request: &Request, limits: &BodyLimits) -> Result<Self, ExtractError>;
+}
+
+/// `application/x-www-form-urlencoded` 表单，保持字段的顺序，同名字段可以出现多次
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    /// 解析 `a=1&b=2` 形式的字符串，`+` 表示空格
+    pub fn parse(s: &str) -> Result<Form, ExtractError> {
+        let mut fields = Vec::new();
+        for pair in s.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            fields.push((decode_component(name)?, decode_component(value)?));
+        }
+        Ok(Form { fields })
+    }
+
+    /// 第一个名为 name 的字段
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 所有名为 name 的字段，例如多选框
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+}
+
+impl FromRequest for Form {
+    fn from_request(request: &Request, limits: &BodyLimits) -> Result<Form, ExtractError> {
+        expect_type(request, "application/x-www-form-urlencoded", |t| {
+            t == "application/x-www-form-urlencoded"
+        })?;
+        check_size(request.body.len(), limits.max_form_size)?;
+        let body = std::str::from_utf8(&request.body)
+            .map_err(|_| ExtractError::Invalid("form is not valid UTF-8".to_string()))?;
+        Form::parse(body)
+    }
+}
+
+fn decode_component(s: &str) -> Result<String, ExtractError> {
+    percent_decode(&s.replace('+', " "))
+        .ok_or_else(|| ExtractError::Invalid(format!("bad escape in {:?}", s)))
+}
+
+/// 用 serde 反序列化的 JSON 请求体，Content-Type 须为 `application/json` 或 `*+json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request, limits: &BodyLimits) -> Result<Json<T>, ExtractError> {
+        expect_type(request, "application/json", |t| {
+            t == "application/json" || t.ends_with("+json")
+        })?;
+        check_size(request.body.len(), limits.max_json_size)?;
+        serde_json::from_slice(&request.body)
+            .map(Json)
+            .map_err(|e| ExtractError::Invalid(e.to_string()))
+    }
+}
+
+/// `multipart/form-data` 请求体
+///
+/// 带 filename 的部分写进临时文件，其余的作为普通字段。
+/// `Server` 在读取请求体时就边读边解析，文件内容收到多少写多少，不会整个放在内存中，
+/// extract 只是取走解析好的结果，所以同一个请求只能 extract 一次。
+/// 通过 `Request::from_reader` 自己读取的请求则从 `body` 中解析。
+#[derive(Debug, Default)]
+pub struct Multipart {
+    fields: Vec<(String, String)>,
+    files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    /// 第一个名为 name 的普通字段
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    /// 第一个字段名为 name 的文件
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    pub fn files(&self) -> &[UploadedFile] {
+        &self.files
+    }
+
+    /// 取出所有文件，例如要逐个 `persist`
+    pub fn into_files(self) -> Vec<UploadedFile> {
+        self.files
+    }
+
+    /// 服务器按它的上限解析好之后，再按 extract_with 传入的上限检查一遍
+    fn check(&self, limits: &BodyLimits) -> Result<(), ExtractError> {
+        if self.fields.len() + self.files.len() > limits.max_parts {
+            return Err(ExtractError::TooLarge {
+                limit: limits.max_parts as u64,
+            });
+        }
+        for (_, value) in &self.fields {
+            check_size(value.len(), limits.max_field_size)?;
+        }
+        if self
+            .files
+            .iter()
+            .any(|file| file.size > limits.max_file_size)
+        {
+            return Err(ExtractError::TooLarge {
+                limit: limits.max_file_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl FromRequest for Multipart {
+    fn from_request(request: &Request, limits: &BodyLimits) -> Result<Multipart, ExtractError> {
+        if let Some(streamed) = &request.multipart {
+            let multipart = lock(&streamed.0)
+                .take()
+                .ok_or_else(|| invalid("multipart body was already extracted"))?;
+            multipart.check(limits)?;
+            return Ok(multipart);
+        }
+        parse_multipart(&request.body, &boundary(request)?, limits)
+    }
+}
+
+/// 服务器读取请求时已经解析好的 multipart 请求体，第一次 extract 时取走
+///
+/// 请求被 clone 时和原来的请求共用一份。
+#[derive(Debug, Clone)]
+pub(crate) struct StreamedMultipart(Arc<Mutex<Option<Multipart>>>);
+
+impl StreamedMultipart {
+    pub(crate) fn new(multipart: Multipart) -> StreamedMultipart {
+        StreamedMultipart(Arc::new(Mutex::new(Some(multipart))))
+    }
+}
+
+/// multipart/form-data 的 boundary，Content-Type 不符时返回 415
+fn boundary(request: &Request) -> Result<String, ExtractError> {
+    let content_type = expect_type(request, "multipart/form-data", |t| {
+        t == "multipart/form-data"
+    })?;
+    params(content_type)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+        .ok_or_else(|| invalid("missing boundary"))
+}
+
+/// 请求是带有合法 boundary 的 multipart/form-data 时，返回按 `request.body_limits`
+/// 边读边解析请求体的解析器
+pub(crate) fn upload_parser(request: &Request) -> Option<MultipartParser> {
+    let boundary = boundary(request).ok()?;
+    Some(MultipartParser::new(&boundary, request.body_limits))
+}
+
+/// multipart 中上传的文件，保存在临时文件中，drop 时删除
+#[derive(Debug)]
+pub struct UploadedFile {
+    name: String,
+    filename: String,
+    content_type: Option<String>,
+    size: u64,
+    file: NamedTempFile,
+}
+
+impl UploadedFile {
+    /// 表单中的字段名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 客户端给出的文件名，已去掉目录部分，但仍然不可信，不要直接用作路径
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 临时文件的路径
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// 重新打开临时文件读取内容
+    pub fn open(&self) -> io::Result<File> {
+        self.file.reopen()
+    }
+
+    /// 把临时文件移动到 path 保留下来，不再自动删除
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> io::Result<File> {
+        self.file.persist(path).map_err(|e| e.error)
+    }
+}
+
+fn parse_multipart(
+    body: &[u8],
+    boundary: &str,
+    limits: &BodyLimits,
+) -> Result<Multipart, ExtractError> {
+    let mut parser = MultipartParser::new(boundary, *limits);
+    parser.feed(body)?;
+    parser.finish()
+}
+
+/// 分几次传入数据、边收边解析的 multipart 请求体
+///
+/// 文件内容收到多少就写多少到临时文件，内存中只留还不能确定归属的少量数据：
+/// 可能是分隔符开头的末尾几个字节，以及还没收完的部分头。
+pub(crate) struct MultipartParser {
+    /// `\r\n--boundary`；请求体开头的分隔符前面没有 CRLF，开始时先在 buf 中补上
+    delimiter: Vec<u8>,
+    limits: BodyLimits,
+    buf: Vec<u8>,
+    state: PartState,
+    multipart: Multipart,
+    parts: usize,
+    /// 已经收到的请求体字节数
+    size: usize,
+}
+
+enum PartState {
+    /// 第一个分隔符之前的内容（preamble），忽略
+    Preamble,
+    /// 分隔符之后，等待 CRLF 或表示结束的 `--`
+    Boundary,
+    /// 部分头，到空行为止
+    Head,
+    /// 部分的内容，到下一个分隔符为止
+    Content(Part),
+    /// 结束分隔符之后的内容（epilogue），忽略
+    Epilogue,
+}
+
+enum Part {
+    Field { name: String, value: Vec<u8> },
+    File(UploadedFile),
+}
+
+impl MultipartParser {
+    pub(crate) fn new(boundary: &str, limits: BodyLimits) -> MultipartParser {
+        MultipartParser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            buf: b"\r\n".to_vec(),
+            state: PartState::Preamble,
+            multipart: Multipart::default(),
+            parts: 0,
+            size: 0,
+        }
+    }
+
+    /// 解析接着收到的一段请求体
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Result<(), ExtractError> {
+        self.size = self.size.saturating_add(data.len());
+        if self.size > self.limits.max_multipart_size {
+            return Err(ExtractError::TooLarge {
+                limit: self.limits.max_multipart_size as u64,
+            });
+        }
+        self.buf.extend_from_slice(data);
+
+        loop {
+            match &mut self.state {
+                PartState::Preamble | PartState::Content(_) => {
+                    let found = find(&self.buf, &self.delimiter);
+                    // 没找到分隔符时，末尾可能是下一次才收完的分隔符，先留着
+                    let end = found
+                        .unwrap_or_else(|| self.buf.len().saturating_sub(self.delimiter.len() - 1));
+                    if let PartState::Content(part) = &mut self.state {
+                        part.write(&self.buf[..end], &self.limits)?;
+                    }
+                    match found {
+                        Some(i) => {
+                            self.buf.drain(..i + self.delimiter.len());
+                            let state = mem::replace(&mut self.state, PartState::Boundary);
+                            if let PartState::Content(part) = state {
+                                part.finish(&mut self.multipart)?;
+                            }
+                        }
+                        None => {
+                            self.buf.drain(..end);
+                            return Ok(());
+                        }
+                    }
+                }
+                PartState::Boundary => {
+                    if self.buf.len() < 2 {
+                        return Ok(());
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.state = PartState::Epilogue;
+                        continue;
+                    }
+                    if !self.buf.starts_with(b"\r\n") {
+                        return Err(invalid("malformed boundary line"));
+                    }
+                    self.buf.drain(..2);
+                    self.parts += 1;
+                    if self.parts > self.limits.max_parts {
+                        return Err(ExtractError::TooLarge {
+                            limit: self.limits.max_parts as u64,
+                        });
+                    }
+                    self.state = PartState::Head;
+                }
+                PartState::Head => {
+                    let head_len = if self.buf.starts_with(b"\r\n") {
+                        0
+                    } else {
+                        match find(&self.buf, b"\r\n\r\n") {
+                            Some(i) => i + 2,
+                            None if self.buf.len() > MAX_PART_HEADER_SIZE => {
+                                return Err(ExtractError::TooLarge {
+                                    limit: MAX_PART_HEADER_SIZE as u64,
+                                })
+                            }
+                            None => return Ok(()),
+                        }
+                    };
+                    let head = std::str::from_utf8(&self.buf[..head_len])
+                        .map_err(|_| invalid("part headers are not valid UTF-8"))?;
+                    let part = start_part(head)?;
+                    self.buf.drain(..head_len + 2);
+                    self.state = PartState::Content(part);
+                }
+                PartState::Epilogue => {
+                    self.buf.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// 请求体已经收完，返回解析结果
+    pub(crate) fn finish(self) -> Result<Multipart, ExtractError> {
+        match self.state {
+            PartState::Epilogue => Ok(self.multipart),
+            PartState::Preamble => Err(invalid("missing boundary")),
+            PartState::Head => Err(invalid("unterminated part headers")),
+            PartState::Boundary | PartState::Content(_) => Err(invalid("missing closing boundary")),
+        }
+    }
+}
+
+impl Part {
+    fn write(&mut self, data: &[u8], limits: &BodyLimits) -> Result<(), ExtractError> {
+        match self {
+            Part::Field { value, .. } => {
+                check_size(value.len() + data.len(), limits.max_field_size)?;
+                value.extend_from_slice(data);
+            }
+            Part::File(file) => {
+                file.size += data.len() as u64;
+                if file.size > limits.max_file_size {
+                    return Err(ExtractError::TooLarge {
+                        limit: limits.max_file_size,
+                    });
+                }
+                file.file.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, multipart: &mut Multipart) -> Result<(), ExtractError> {
+        match self {
+            Part::Field { name, value } => {
+                let value = String::from_utf8(value)
+                    .map_err(|_| invalid(&format!("field `{}` is not valid UTF-8", name)))?;
+                multipart.fields.push((name, value));
+            }
+            Part::File(mut file) => {
+                file.file.flush()?;
+                multipart.files.push(file);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 按部分头中的 Content-Disposition 开始一个字段或文件
+fn start_part(head: &str) -> Result<Part, ExtractError> {
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed part header"))?;
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(value.trim());
+        } else if name.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+
+    let disposition = disposition.ok_or_else(|| invalid("part without content-disposition"))?;
+    if !media_type(disposition).eq_ignore_ascii_case("form-data") {
+        return Err(invalid("part is not form-data"));
+    }
+    let params = params(disposition);
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone())
+    };
+    let name = param("name").ok_or_else(|| invalid("part without a name"))?;
+
+    Ok(match param("filename") {
+        Some(filename) => {
+            // 旧浏览器会带上客户端的完整路径
+            let filename = filename
+                .rsplit(['/', '\\'])
+                .next()
+                .unwrap_or("")
+                .to_string();
+            Part::File(UploadedFile {
+                name,
+                filename,
+                content_type,
+                size: 0,
+                file: NamedTempFile::new()?,
+            })
+        }
+        None => Part::Field {
+            name,
+            value: Vec::new(),
+        },
+    })
+}
+
+fn invalid(reason: &str) -> ExtractError {
+    ExtractError::Invalid(reason.to_string())
+}
+
+fn check_size(len: usize, limit: usize) -> Result<(), ExtractError> {
+    if len > limit {
+        return Err(ExtractError::TooLarge {
+            limit: limit as u64,
+        });
+    }
+    Ok(())
+}
+
+/// 检查 Content-Type 的媒体类型（小写，不含参数），返回完整的 Content-Type
+fn expect_type<'a, F>(
+    request: &'a Request,
+    expected: &'static str,
+    matches: F,
+) -> Result<&'a str, ExtractError>
+where
+    F: Fn(&str) -> bool,
+{
+    match request.header("content-type") {
+        Some(value) if matches(&media_type(value).to_ascii_lowercase()) => Ok(value),
+        _ => Err(ExtractError::UnsupportedMediaType { expected }),
+    }
+}
+
+/// `type/subtype; a=b` 中分号之前的部分
+fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or("").trim()
+}
+
+/// `type; a=b; c="d;e"` 中的参数，值可以带引号，引号中的 `\` 转义下一个字符
+fn params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().skip_while(|&c| c != ';').peekable();
+
+    while chars.next().is_some() {
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ';' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+        }
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            // 跳过引号之后到下一个分号之前的内容
+            while chars.peek().is_some_and(|&c| c != ';') {
+                chars.next();
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ';' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::fs;
+    use std::io::Read;
+
+    fn post(content_type: &str, body: &[u8]) -> Request {
+        let mut raw = format!(
+            "POST / HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            content_type,
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(body);
+        Request::from_reader(&mut &raw[..]).unwrap()
+    }
+
+    #[test]
+    fn decodes_urlencoded_forms() {
+        let request = post(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            b"q=rust+web%20server&tag=a&tag=b&empty=&flag",
+        );
+        let form: Form = request.extract().unwrap();
+        assert_eq!(form.get("q"), Some("rust web server"));
+        assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.get("missing"), None);
+
+        let err = post("application/x-www-form-urlencoded", b"a=%zz")
+            .extract::<Form>()
+            .unwrap_err();
+        assert_eq!(err.status(), 400);
+        let err = post("text/plain", b"a=1").extract::<Form>().unwrap_err();
+        assert_eq!(err.status(), 415);
+
+        let small = BodyLimits {
+            max_form_size: 4,
+            ..BodyLimits::default()
+        };
+        let err = post("application/x-www-form-urlencoded", b"a=12345")
+            .extract_with::<Form>(&small)
+            .unwrap_err();
+        assert_eq!(err.status(), 413);
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn deserializes_json() {
+        let request = post(
+            "application/json; charset=utf-8",
+            br#"{"name":"apple","count":3}"#,
+        );
+        let Json(item) = request.extract::<Json<Item>>().unwrap();
+        assert_eq!(
+            item,
+            Item {
+                name: "apple".to_string(),
+                count: 3
+            }
+        );
+        let request = post("application/merge-patch+json", br#"[1, 2]"#);
+        assert_eq!(request.extract::<Json<Vec<u8>>>().unwrap().0, [1, 2]);
+
+        let err = post("application/json", br#"{"name":"apple"}"#)
+            .extract::<Json<Item>>()
+            .unwrap_err();
+        assert_eq!(err.status(), 400);
+        let response = Response::from(err);
+        assert_eq!(response.status, 400);
+        assert_eq!(
+            post("text/json", b"{}")
+                .extract::<Json<Item>>()
+                .unwrap_err()
+                .status(),
+            415
+        );
+        let small = BodyLimits {
+            max_json_size: 8,
+            ..BodyLimits::default()
+        };
+        let err = post("application/json", br#"{"name":"apple","count":3}"#)
+            .extract_with::<Json<Item>>(&small)
+            .unwrap_err();
+        assert_eq!(err.status(), 413);
+    }
+
+    const BOUNDARY: &str = "----a20boundary";
+
+    fn multipart_body() -> Vec<u8> {
+        format!(
+            "preamble\r\n--{b}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             holiday; \"2024\"\r\n--{b}\r\n\
+             Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\\\pics\\\\a;b.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             line one\r\nline two\r\n--{b}--\r\nepilogue",
+            b = BOUNDARY
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn parses_multipart_into_fields_and_temp_files() {
+        let content_type = format!("multipart/form-data; boundary=\"{}\"", BOUNDARY);
+        let request = post(&content_type, &multipart_body());
+        let multipart: Multipart = request.extract().unwrap();
+
+        assert_eq!(multipart.get("title"), Some("holiday; \"2024\""));
+        let photo = multipart.file("photo").unwrap();
+        assert_eq!(photo.filename(), "a;b.txt");
+        assert_eq!(photo.content_type(), Some("text/plain"));
+        assert_eq!(photo.size(), 18);
+        let mut content = String::new();
+        photo.open().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "line one\r\nline two");
+
+        // 临时文件在 drop 时删除，persist 之后保留
+        let path = photo.path().to_path_buf();
+        assert!(path.exists());
+        drop(multipart);
+        assert!(!path.exists());
+
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("upload.txt");
+        let multipart: Multipart = request.extract().unwrap();
+        let file = multipart.into_files().pop().unwrap();
+        file.persist(&kept).unwrap();
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "line one\r\nline two");
+    }
+
+    #[test]
+    fn rejects_bad_or_oversized_multipart() {
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        let body = multipart_body();
+
+        let truncated = post(&content_type, &body[..body.len() - 30]);
+        assert_eq!(truncated.extract::<Multipart>().unwrap_err().status(), 400);
+        let no_boundary = post("multipart/form-data", &body);
+        assert_eq!(
+            no_boundary.extract::<Multipart>().unwrap_err().status(),
+            400
+        );
+        let wrong_type = post("multipart/mixed; boundary=x", &body);
+        assert_eq!(wrong_type.extract::<Multipart>().unwrap_err().status(), 415);
+        let nameless = format!(
+            "--{b}\r\nContent-Disposition: form-data\r\n\r\nx\r\n--{b}--",
+            b = BOUNDARY
+        );
+        let err = post(&content_type, nameless.as_bytes())
+            .extract::<Multipart>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid body: part without a name");
+
+        let request = post(&content_type, &body);
+        let limits = [
+            BodyLimits {
+                max_file_size: 10,
+                ..BodyLimits::default()
+            },
+            BodyLimits {
+                max_field_size: 10,
+                ..BodyLimits::default()
+            },
+            BodyLimits {
+                max_parts: 1,
+                ..BodyLimits::default()
+            },
+        ];
+        for limits in limits.iter() {
+            let err = request.extract_with::<Multipart>(limits).unwrap_err();
+            assert_eq!(err.status(), 413, "{:?}", limits);
+        }
+    }
+
+    #[test]
+    fn parses_multipart_fed_in_pieces() {
+        let body = multipart_body();
+        let file = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f\"\r\n\r\n{x}\r\n--{b}--",
+            b = BOUNDARY,
+            x = "x".repeat(100)
+        );
+
+        // 一次一个字节，分隔符和部分头被拆开也能解析，内容中只留可能是分隔符的几个字节
+        let mut parser = MultipartParser::new(BOUNDARY, BodyLimits::default());
+        for byte in body.iter() {
+            parser.feed(&[*byte]).unwrap();
+            if let PartState::Content(_) = parser.state {
+                assert!(parser.buf.len() < parser.delimiter.len());
+            }
+        }
+        let multipart = parser.finish().unwrap();
+        assert_eq!(multipart.get("title"), Some("holiday; \"2024\""));
+        let mut content = String::new();
+        let photo = multipart.file("photo").unwrap();
+        photo.open().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "line one\r\nline two");
+
+        // 文件超过上限时立刻报错，不等请求体收完
+        let limits = BodyLimits {
+            max_file_size: 10,
+            ..BodyLimits::default()
+        };
+        let mut parser = MultipartParser::new(BOUNDARY, limits);
+        let head_len = file.find("xxx").unwrap();
+        parser.feed(&file.as_bytes()[..head_len + 10]).unwrap();
+        let err = parser.feed(&file.as_bytes()[head_len + 10..]).unwrap_err();
+        assert_eq!(err.status(), 413);
+
+        let limits = BodyLimits {
+            max_multipart_size: file.len() - 1,
+            ..BodyLimits::default()
+        };
+        let mut parser = MultipartParser::new(BOUNDARY, limits);
+        assert_eq!(parser.feed(file.as_bytes()).unwrap_err().status(), 413);
+
+        // 没有结束的部分头也有上限
+        let mut parser = MultipartParser::new(BOUNDARY, BodyLimits::default());
+        parser
+            .feed(format!("--{}\r\n", BOUNDARY).as_bytes())
+            .unwrap();
+        let head = "X-Padding: a\r\n".repeat(MAX_PART_HEADER_SIZE / 10);
+        assert_eq!(parser.feed(head.as_bytes()).unwrap_err().status(), 413);
+    }
+
+    #[test]
+    fn parses_header_params() {
+        assert_eq!(
+            params(r#"form-data; name="a\"b"; filename = x.txt ;flag; x=1"#),
+            [
+                ("name".to_string(), "a\"b".to_string()),
+                ("filename".to_string(), "x.txt".to_string()),
+                ("flag".to_string(), String::new()),
+                ("x".to_string(), "1".to_string()),
+            ]
+        );
+        assert!(params("text/plain").is_empty());
+    }
+}
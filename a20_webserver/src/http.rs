@@ -0,0 +1,741 @@
+use crate::chunked;
+use crate::extract::{self, BodyLimits, ExtractError, FromRequest, StreamedMultipart};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// 请求行、单个请求头允许的最大字节数
+pub(crate) const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// 解析请求时的上限
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// 请求头的最大个数，超过时返回 `ParseError::TooManyHeaders`
+    pub max_headers: usize,
+    /// 全部请求头（不含请求行）的最大字节数，超过时返回 `ParseError::HeadersTooLarge`
+    pub max_header_size: usize,
+    /// 请求体的最大字节数，超过时返回 `ParseError::BodyTooLarge`
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    /// 最多 100 个请求头、共 32KB，请求体最大 1MB
+    fn default() -> Limits {
+        Limits {
+            max_headers: 100,
+            max_header_size: 32 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// HTTP 请求方法
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    /// 其他合法但不常用的方法，例如 TRACE
+    Other(String),
+}
+
+impl Method {
+    /// 解析方法名，方法名必须是非空的 token
+    fn parse(s: &str) -> Option<Method> {
+        if s.is_empty() || !s.bytes().all(is_token_byte) {
+            return None;
+        }
+
+        let method = match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            other => Method::Other(other.to_string()),
+        };
+
+        Some(method)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Other(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// HTTP 协议版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Version> {
+        match s {
+            "HTTP/1.0" => Some(Version::Http10),
+            "HTTP/1.1" => Some(Version::Http11),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// 从连接中解析出的 HTTP 请求
+///
+/// 请求头的名称统一转成小写保存，同名的请求头按出现顺序用 ", " 拼接。
+/// `params` 由路由在匹配成功后填入路径参数。
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub params: HashMap<String, String>,
+    /// 分块请求体末尾的 trailer，名字为小写
+    pub trailers: HashMap<String, String>,
+    /// 通过认证的用户名，由 `BasicAuth` 或会话中间件设置，会记入访问日志
+    pub user: Option<String>,
+    /// `extract` 使用的上限，由服务器按 `Server::body_limits` 设置
+    pub(crate) body_limits: BodyLimits,
+    /// 服务器读取请求体时边读边解析好的 multipart 请求体，此时 `body` 为空
+    pub(crate) multipart: Option<StreamedMultipart>,
+}
+
+impl Request {
+    /// 从 reader 中读取并解析一个完整的请求
+    ///
+    /// 先读请求行，再逐行读请求头直到空行，最后按 Content-Length 读取请求体。
+    /// 使用 `Limits` 的默认值，请求体超过 1MB 时返回 `ParseError::BodyTooLarge`。
+    pub fn from_reader<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::from_reader_with_limits(reader, &Limits::default())
+    }
+
+    /// 和 `from_reader` 一样，但请求体超过 max_body 字节时返回 `ParseError::BodyTooLarge`
+    ///
+    /// 只检查 Content-Length，超长的请求体不会被读取。
+    pub fn from_reader_with_limit<R: BufRead>(
+        reader: &mut R,
+        max_body: usize,
+    ) -> Result<Request, ParseError> {
+        let limits = Limits {
+            max_body_size: max_body,
+            ..Limits::default()
+        };
+        Request::from_reader_with_limits(reader, &limits)
+    }
+
+    /// 和 `from_reader` 一样，但请求头和请求体不能超过 limits
+    pub fn from_reader_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Request, ParseError> {
+        let mut request = Request::read_head(reader, limits)?;
+        request.read_body(reader, limits)?;
+        Ok(request)
+    }
+
+    /// 读取请求行和请求头，请求体留在 reader 中
+    pub(crate) fn read_head<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Request, ParseError> {
+        let line = match read_line(reader, MAX_LINE_LEN)? {
+            Some(line) => line,
+            None => return Err(ParseError::Closed),
+        };
+
+        let mut parts = line.split(' ');
+        let (method, target, version) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(m), Some(t), Some(v), None) => (m, t, v),
+                _ => return Err(ParseError::InvalidRequestLine),
+            };
+
+        let method = Method::parse(method).ok_or(ParseError::InvalidRequestLine)?;
+        let version = Version::parse(version).ok_or(ParseError::InvalidVersion)?;
+
+        // 只接受 origin-form 和 OPTIONS 的 "*"
+        if !target.starts_with('/') && target != "*" {
+            return Err(ParseError::InvalidTarget);
+        }
+        let (path, query) = match target.find('?') {
+            Some(i) => (target[..i].to_string(), Some(target[i + 1..].to_string())),
+            None => (target.to_string(), None),
+        };
+
+        let headers = read_headers(reader, limits)?;
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            params: HashMap::new(),
+            trailers: HashMap::new(),
+            user: None,
+            body_limits: BodyLimits::default(),
+            multipart: None,
+        })
+    }
+
+    /// 请求体是否以 `Transfer-Encoding: chunked` 发送，不支持的编码返回错误
+    pub(crate) fn is_chunked(&self) -> Result<bool, ParseError> {
+        let encoding = match self.header("transfer-encoding") {
+            Some(encoding) => encoding,
+            None => return Ok(false),
+        };
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        // 两者同时出现时无法确定请求体的边界，可能被用来走私请求
+        if self.header("content-length").is_some() {
+            return Err(ParseError::InvalidContentLength);
+        }
+        Ok(true)
+    }
+
+    /// 按 Content-Length 或 `Transfer-Encoding: chunked` 读取请求体
+    pub(crate) fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), ParseError> {
+        if self.is_chunked()? {
+            let (body, trailers) = chunked::read_chunked(reader, limits)?;
+            self.body = body;
+            self.trailers = trailers;
+            return Ok(());
+        }
+
+        let len = self.content_length()?;
+        if len > limits.max_body_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+        // 不按 Content-Length 预先分配，缓冲区只随实际收到的数据增长
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body)?;
+        if body.len() < len {
+            // 请求体还没读完连接就断了
+            return Err(ParseError::IncompleteBody);
+        }
+        self.body = body;
+        Ok(())
+    }
+
+    /// 与 read_body 相同，但 multipart/form-data 的请求体按 `body_limits` 边读边解析，
+    /// 文件直接写进临时文件，整个请求体受 `max_multipart_size` 而不是 `max_body_size` 的限制
+    pub(crate) fn read_body_streaming<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), ParseError> {
+        let mut parser = match extract::upload_parser(self) {
+            Some(parser) => parser,
+            None => return self.read_body(reader, limits),
+        };
+        let limits = self.upload_limits(limits);
+        let mut feed = |data: &[u8]| parser.feed(data).map_err(ParseError::Upload);
+
+        if self.is_chunked()? {
+            self.trailers = chunked::read_chunked_into(reader, &limits, &mut feed)?;
+        } else {
+            let mut remaining = self.content_length()?;
+            if remaining > limits.max_body_size {
+                return Err(ParseError::BodyTooLarge);
+            }
+            while remaining > 0 {
+                let data = reader.fill_buf()?;
+                if data.is_empty() {
+                    return Err(ParseError::IncompleteBody);
+                }
+                let n = data.len().min(remaining);
+                feed(&data[..n])?;
+                reader.consume(n);
+                remaining -= n;
+            }
+        }
+
+        let multipart = parser.finish().map_err(ParseError::Upload)?;
+        self.multipart = Some(StreamedMultipart::new(multipart));
+        Ok(())
+    }
+
+    /// 边读边解析 multipart 请求体时使用的上限
+    pub(crate) fn upload_limits(&self, limits: &Limits) -> Limits {
+        Limits {
+            max_body_size: self.body_limits.max_multipart_size,
+            ..*limits
+        }
+    }
+
+    /// 按名称（不区分大小写）取请求头
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_str())
+    }
+
+    /// 按 Content-Type 把请求体解析成 `Form`、`Multipart` 或 `Json<T>`
+    ///
+    /// 使用服务器设置的上限，自己读取的请求使用默认的上限。
+    pub fn extract<T: FromRequest>(&self) -> Result<T, ExtractError> {
+        T::from_request(self, &self.body_limits)
+    }
+
+    /// 与 extract 相同，但指定请求体各部分的上限
+    pub fn extract_with<T: FromRequest>(&self, limits: &BodyLimits) -> Result<T, ExtractError> {
+        T::from_request(self, limits)
+    }
+
+    /// 客户端是否希望在这个请求之后保持连接
+    ///
+    /// HTTP/1.1 默认保持连接，除非带有 `Connection: close`；
+    /// HTTP/1.0 默认关闭，除非带有 `Connection: keep-alive`。
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.has_connection_token("close"),
+            Version::Http10 => self.has_connection_token("keep-alive"),
+        }
+    }
+
+    /// Connection 请求头中是否含有某个选项（不区分大小写）
+    fn has_connection_token(&self, token: &str) -> bool {
+        self.header("connection")
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    }
+
+    /// 取路由匹配到的路径参数
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|v| v.as_str())
+    }
+
+    /// 请求体长度，没有 Content-Length 时为 0
+    pub(crate) fn content_length(&self) -> Result<usize, ParseError> {
+        match self.header("content-length") {
+            // 重复的 Content-Length 会被拼接成 "a, b"，这里直接当作非法值
+            Some(v) => v.parse().map_err(|_| ParseError::InvalidContentLength),
+            None => Ok(0),
+        }
+    }
+}
+
+/// 解析请求时可能出现的错误
+#[derive(Debug)]
+pub enum ParseError {
+    /// 读取连接时出错
+    Io(io::Error),
+    /// 还没读到任何数据连接就关闭了
+    Closed,
+    /// 请求行格式不正确
+    InvalidRequestLine,
+    /// 不支持的协议版本
+    InvalidVersion,
+    /// 请求目标不是以 '/' 开头的路径
+    InvalidTarget,
+    /// 请求头格式不正确
+    InvalidHeader,
+    /// Content-Length 不是合法的数字
+    InvalidContentLength,
+    /// 请求体比 Content-Length 声明的短，或者分块的请求体没有结束
+    IncompleteBody,
+    /// 分块请求体的格式不正确
+    InvalidChunk,
+    /// Transfer-Encoding 不是 chunked
+    UnsupportedTransferEncoding,
+    /// 请求行超过长度上限
+    LineTooLong,
+    /// Content-Length 超过允许的最大请求体
+    BodyTooLarge,
+    /// 请求头的个数超过上限
+    TooManyHeaders,
+    /// 请求头的总字节数超过上限
+    HeadersTooLarge,
+    /// 请求还没读完就超时了
+    Timeout,
+    /// 边读边解析 multipart 请求体时出错，状态码由其中的错误决定
+    Upload(ExtractError),
+}
+
+impl ParseError {
+    /// 是否应该以 4xx 状态回应客户端，状态码由 `status` 给出
+    ///
+    /// 连接已经断开或读写出错的情况下没有必要再回应。
+    pub fn is_bad_request(&self) -> bool {
+        !matches!(self, ParseError::Io(_) | ParseError::Closed)
+    }
+
+    /// 回应客户端时使用的状态码
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::Timeout => 408,
+            ParseError::BodyTooLarge => 413,
+            ParseError::TooManyHeaders | ParseError::HeadersTooLarge => 431,
+            ParseError::UnsupportedTransferEncoding => 501,
+            ParseError::Upload(e) => e.status(),
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "io error: {}", e),
+            ParseError::Closed => write!(f, "connection closed"),
+            ParseError::InvalidRequestLine => write!(f, "invalid request line"),
+            ParseError::InvalidVersion => write!(f, "unsupported http version"),
+            ParseError::InvalidTarget => write!(f, "invalid request target"),
+            ParseError::InvalidHeader => write!(f, "invalid header"),
+            ParseError::InvalidContentLength => write!(f, "invalid content-length"),
+            ParseError::IncompleteBody => write!(f, "incomplete body"),
+            ParseError::InvalidChunk => write!(f, "invalid chunk"),
+            ParseError::UnsupportedTransferEncoding => {
+                write!(f, "unsupported transfer-encoding")
+            }
+            ParseError::LineTooLong => write!(f, "line too long"),
+            ParseError::BodyTooLarge => write!(f, "body too large"),
+            ParseError::TooManyHeaders => write!(f, "too many headers"),
+            ParseError::HeadersTooLarge => write!(f, "headers too large"),
+            ParseError::Timeout => write!(f, "timed out reading request"),
+            ParseError::Upload(e) => write!(f, "upload failed: {}", e),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            ParseError::Upload(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        match e.kind() {
+            // 设置了读超时的 socket 超时时返回 WouldBlock（Unix）或 TimedOut（Windows）
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::Timeout,
+            _ => ParseError::Io(e),
+        }
+    }
+}
+
+/// 读取请求头，直到遇到空行
+fn read_headers<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<HashMap<String, String>, ParseError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut count = 0;
+    let mut remaining = limits.max_header_size;
+
+    loop {
+        // 单个请求头太长和请求头总量超限一样处理
+        let line = match read_line(reader, remaining.min(MAX_LINE_LEN)) {
+            Ok(line) => line.ok_or(ParseError::InvalidHeader)?,
+            Err(ParseError::LineTooLong) => return Err(ParseError::HeadersTooLarge),
+            Err(e) => return Err(e),
+        };
+        if line.is_empty() {
+            return Ok(headers);
+        }
+
+        count += 1;
+        if count > limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        remaining = remaining.saturating_sub(line.len() + 2);
+
+        let (name, value) = parse_header_line(&line)?;
+        insert_header(&mut headers, name, value);
+    }
+}
+
+/// 把 `Name: value` 拆成名字和去掉首尾空白的值
+pub(crate) fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    let colon = line.find(':').ok_or(ParseError::InvalidHeader)?;
+    let name = &line[..colon];
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(ParseError::InvalidHeader);
+    }
+    Ok((name, line[colon + 1..].trim()))
+}
+
+/// 按小写的名字保存，重复出现的值用 ", " 拼接
+pub(crate) fn insert_header(headers: &mut HashMap<String, String>, name: &str, value: &str) {
+    headers
+        .entry(name.to_ascii_lowercase())
+        .and_modify(|v| {
+            v.push_str(", ");
+            v.push_str(value);
+        })
+        .or_insert_with(|| value.to_string());
+}
+
+/// 读取一行（不含行尾的 CRLF），连接已关闭且没有数据时返回 None
+///
+/// 连同 CRLF 超过 max 字节时返回 `ParseError::LineTooLong`。
+fn read_line<R: BufRead>(reader: &mut R, max: usize) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    // 多读一个字节，用来判断是否超长
+    let n = reader
+        .by_ref()
+        .take(max as u64 + 1)
+        .read_until(b'\n', &mut buf)?;
+
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.last() != Some(&b'\n') || n > max {
+        if n > max {
+            return Err(ParseError::LineTooLong);
+        }
+        // 行还没结束连接就断了
+        return Err(ParseError::InvalidRequestLine);
+    }
+
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| ParseError::InvalidHeader)
+}
+
+/// 解码 URL 中的 %XX 转义，转义不完整或结果不是 UTF-8 时返回 None
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            // from_str_radix 会接受 "+1" 这样的写法，这里先检查
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+/// RFC 7230 中 token 允许的字符
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::from_reader(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_query_and_headers() {
+        let req = parse(
+            "GET /search?q=rust&page=2 HTTP/1.1\r\nHost: localhost\r\nX-Tag: a\r\nx-tag: b\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path, "/search");
+        assert_eq!(req.query.as_deref(), Some("q=rust&page=2"));
+        assert_eq!(req.version, Version::Http11);
+        assert_eq!(req.header("HOST"), Some("localhost"));
+        assert_eq!(req.header("x-tag"), Some("a, b"));
+        assert!(req.body.is_empty());
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").unwrap().keep_alive());
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n")
+            .unwrap()
+            .keep_alive());
+        assert!(!parse("GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive());
+        assert!(parse("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap()
+            .keep_alive());
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let mut raw = "POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET".as_bytes();
+        let req = Request::from_reader(&mut raw).unwrap();
+
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.body, b"hello");
+        // 多余的数据留在 reader 里
+        assert_eq!(raw, b"GET");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::InvalidVersion)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nno colon\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::IncompleteBody)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(parse(""), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_limit_without_reading_them() {
+        let mut raw = "POST / HTTP/1.1\r\nContent-Length: 6\r\n\r\nhello!".as_bytes();
+        let err = Request::from_reader_with_limit(&mut raw, 5).unwrap_err();
+        assert!(matches!(err, ParseError::BodyTooLarge));
+        assert_eq!(err.status(), 413);
+        assert_eq!(raw, b"hello!");
+
+        let mut raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".as_bytes();
+        assert!(Request::from_reader_with_limit(&mut raw, 5).is_ok());
+
+        // 默认上限是 1MB，巨大的 Content-Length 不会导致按它分配内存
+        let err = parse("POST / HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParseError::BodyTooLarge));
+        let mut raw = "POST / HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\nhello".as_bytes();
+        let err = Request::from_reader_with_limit(&mut raw, usize::MAX).unwrap_err();
+        assert!(matches!(err, ParseError::IncompleteBody));
+    }
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let request = parse(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3\r\nabc\r\n2\r\nde\r\n0\r\nX-Done: yes\r\n\r\nGET /next HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.body, b"abcde");
+        assert_eq!(
+            request.trailers.get("x-done").map(String::as_str),
+            Some("yes")
+        );
+
+        let err = parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err();
+        assert_eq!(err.status(), 501);
+        let err = parse(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::InvalidContentLength));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(
+            percent_decode("/a%20b/%E4%BD%A0").as_deref(),
+            Some("/a b/你")
+        );
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let raw = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert!(matches!(parse(&raw), Err(ParseError::LineTooLong)));
+    }
+
+    #[test]
+    fn enforces_header_limits() {
+        let limits = Limits {
+            max_headers: 2,
+            max_header_size: 64,
+            max_body_size: 4,
+        };
+        let parse = |raw: String| Request::from_reader_with_limits(&mut raw.as_bytes(), &limits);
+
+        let ok = parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n".to_string()).unwrap();
+        assert_eq!(ok.header("b"), Some("2"));
+
+        let err = parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n".to_string()).unwrap_err();
+        assert!(matches!(err, ParseError::TooManyHeaders));
+        assert_eq!(err.status(), 431);
+
+        let long = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(60));
+        assert!(matches!(parse(long), Err(ParseError::HeadersTooLarge)));
+        // 一个请求头不超限，加起来超限
+        let total = format!(
+            "GET / HTTP/1.1\r\nA: {}\r\nB: {}\r\n\r\n",
+            "x".repeat(30),
+            "y".repeat(30)
+        );
+        assert!(matches!(parse(total), Err(ParseError::HeadersTooLarge)));
+
+        let body = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".to_string();
+        assert!(matches!(parse(body), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn timeouts_become_408() {
+        let err = ParseError::from(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+        assert!(matches!(err, ParseError::Timeout));
+        assert!(err.is_bad_request());
+        assert_eq!(err.status(), 408);
+    }
+}
@@ -0,0 +1,199 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+
+/// 等待任务结果失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// 任务 panic 了，附带 panic 信息
+    Panicked(String),
+    /// 任务还没执行就被丢弃了，例如线程池关闭或被 `OverflowPolicy::DropOldest` 挤掉
+    Cancelled,
+    /// 任务还没有完成（`try_join`）
+    NotFinished,
+    /// 等待超时，任务还没有完成（`join_timeout`）
+    Timeout,
+    /// 结果已经被取走了
+    AlreadyJoined,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(msg) => write!(f, "job panicked: {}", msg),
+            JoinError::Cancelled => write!(f, "job was cancelled before it ran"),
+            JoinError::NotFinished => write!(f, "job has not finished yet"),
+            JoinError::Timeout => write!(f, "timed out waiting for job"),
+            JoinError::AlreadyJoined => write!(f, "job result was already taken"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// `ThreadPool::spawn` 返回的句柄，用来取得任务的返回值
+///
+/// 结果只能取走一次。`try_join` 和 `join_timeout` 在任务没完成时不会取走结果，可以再次调用。
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JoinError>>,
+    joined: Cell<bool>,
+}
+
+impl<T> JobHandle<T> {
+    /// 阻塞直到任务完成，返回任务的返回值
+    pub fn join(self) -> Result<T, JoinError> {
+        if self.joined.get() {
+            return Err(JoinError::AlreadyJoined);
+        }
+        // 任务被丢弃时 sender 随之释放，recv 返回错误
+        self.receiver.recv().unwrap_or(Err(JoinError::Cancelled))
+    }
+
+    /// 不阻塞，任务还没完成时返回 `JoinError::NotFinished`
+    pub fn try_join(&self) -> Result<T, JoinError> {
+        if self.joined.get() {
+            return Err(JoinError::AlreadyJoined);
+        }
+        match self.receiver.try_recv() {
+            Ok(result) => self.take(result),
+            Err(TryRecvError::Empty) => Err(JoinError::NotFinished),
+            Err(TryRecvError::Disconnected) => self.take(Err(JoinError::Cancelled)),
+        }
+    }
+
+    /// 最多等待 timeout，超时返回 `JoinError::Timeout`
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JoinError> {
+        if self.joined.get() {
+            return Err(JoinError::AlreadyJoined);
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => self.take(result),
+            Err(RecvTimeoutError::Timeout) => Err(JoinError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => self.take(Err(JoinError::Cancelled)),
+        }
+    }
+
+    fn take(&self, result: Result<T, JoinError>) -> Result<T, JoinError> {
+        self.joined.set(true);
+        result
+    }
+}
+
+/// 把有返回值的闭包包装成线程池的任务，并返回对应的句柄
+///
+/// 闭包 panic 时先把 panic 信息交给句柄，再继续 unwind，让线程池照常统计 panic 次数。
+pub(crate) fn with_handle<F, T>(f: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+
+    let job = move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => {
+            // 句柄已经被丢弃时没有人关心结果
+            let _ = sender.send(Ok(value));
+        }
+        Err(payload) => {
+            let _ = sender.send(Err(JoinError::Panicked(panic_message(&*payload))));
+            panic::resume_unwind(payload);
+        }
+    };
+
+    let handle = JobHandle {
+        receiver,
+        joined: Cell::new(false),
+    };
+    (job, handle)
+}
+
+/// 取出 panic 信息，panic!() 的参数通常是 &str 或 String
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OverflowPolicy, ThreadPool};
+    use std::sync::mpsc;
+
+    #[test]
+    fn join_returns_the_value() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|i| pool.spawn(move || i * 10).unwrap())
+            .collect();
+
+        let results: Vec<i32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn panics_are_returned_as_errors() {
+        let pool = ThreadPool::build(1).unwrap();
+        let handle = pool.spawn(|| -> i32 { panic!("boom {}", 42) }).unwrap();
+
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Panicked("boom 42".to_string()))
+        );
+        // worker 还活着；等它执行完下一个任务，panic 计数一定已经更新
+        assert_eq!(pool.spawn(|| 1).unwrap().join(), Ok(1));
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn try_join_and_join_timeout_do_not_consume_unfinished_results() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+        let handle = pool
+            .spawn(move || {
+                wait.recv().unwrap();
+                "done"
+            })
+            .unwrap();
+
+        assert_eq!(handle.try_join(), Err(JoinError::NotFinished));
+        assert_eq!(
+            handle.join_timeout(Duration::from_millis(20)),
+            Err(JoinError::Timeout)
+        );
+
+        release.send(()).unwrap();
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)), Ok("done"));
+        assert_eq!(handle.try_join(), Err(JoinError::AlreadyJoined));
+    }
+
+    #[test]
+    fn dropped_jobs_are_cancelled() {
+        let pool = ThreadPool::bounded(1, 1, OverflowPolicy::DropOldest).unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, ready) = mpsc::channel();
+        let busy = pool
+            .spawn(move || {
+                started.send(()).unwrap();
+                wait.recv().unwrap();
+            })
+            .unwrap();
+        ready.recv().unwrap();
+
+        let evicted = pool.spawn(|| 1).unwrap();
+        let kept = pool.spawn(|| 2).unwrap();
+        release.send(()).unwrap();
+
+        assert_eq!(evicted.join(), Err(JoinError::Cancelled));
+        assert_eq!(kept.join(), Ok(2));
+        assert_eq!(busy.join(), Ok(()));
+    }
+}
@@ -1,26 +1,91 @@
-use std::sync::mpsc;
+pub mod access_log;
+pub mod auth;
+mod builder;
+mod cancel;
+pub mod chunked;
+pub mod compress;
+pub mod config;
+mod date;
+mod event_loop;
+pub mod extract;
+pub mod http;
+mod job;
+mod logger;
+pub mod middleware;
+mod queue;
+mod range;
+pub mod response;
+pub mod router;
+mod scope;
+pub mod server;
+pub mod session;
+pub mod static_files;
+mod stats;
+mod timer;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::Instant;
 
-/// sender发送的信息的类型的枚举
-///
-/// NewJob - 表示新的任务
-///
-/// Terminate - 表示结束
-enum Message {
-    NewJob(Job),
-    Terminate,
-}
+use queue::{JobQueue, Popped, Pushed};
+use stats::{Histogram, WorkerMetrics};
+use timer::Timer;
+
+pub use builder::ThreadPoolBuilder;
+pub use cancel::{CancellationToken, DropGuard};
+pub use job::{JobHandle, JoinError};
+pub use logger::{LogFormat, LogLevel};
+pub use queue::{OverflowPolicy, Priority};
+pub use scope::Scope;
+pub use stats::{Percentiles, PoolStats, WorkerStats};
+pub use timer::ScheduledHandle;
 
 /// 线程池的结构体
 /// # Arguments
 ///
-/// * workers - 是实际的任务运行者
-/// * sender - 用于发送任务
+/// * shared - 所有 worker 共享的状态，包括任务队列和 worker 本身
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
+    /// 执行延迟任务和周期任务的定时器
+    timer: Timer,
+}
+
+/// worker 之间共享的状态
+struct Shared {
+    queue: JobQueue,
+    config: ThreadPoolBuilder,
+    /// 当前存活的 worker，空闲退出的 worker 会把自己移除
+    workers: Mutex<HashMap<usize, Worker>>,
+    next_id: AtomicUsize,
+    /// 正在等待任务的 worker 数，刚创建的 worker 也算作空闲
+    idle: AtomicUsize,
+    /// 正在执行任务的 worker 数
+    active: AtomicUsize,
+    /// 正常执行完的任务数
+    completed: AtomicU64,
+    /// 任务从提交到执行完的延迟
+    latency: Histogram,
+    /// panic 的任务数
+    panicked: AtomicUsize,
+    /// 因线程意外退出而重新创建的 worker 数
+    respawned: AtomicUsize,
+    /// 因队列已满被丢弃的任务数
+    dropped: AtomicUsize,
+    /// 线程池关闭时取消，通过 `execute_with_token` 交给任务
+    token: CancellationToken,
+}
+
+impl Shared {
+    fn log(&self, level: LogLevel, args: fmt::Arguments) {
+        (self.config.logger)(level, args);
+    }
 }
 
 trait FnBox {
@@ -33,7 +98,86 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<dyn FnBox + Send + 'static>;
+/// 队列中的任务，记下提交的时间用来统计延迟
+pub(crate) struct Job {
+    task: Box<dyn FnBox + Send + 'static>,
+    submitted: Instant,
+    priority: Priority,
+}
+
+impl Job {
+    pub(crate) fn new<F>(f: F) -> Job
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Job {
+            task: Box::new(f),
+            submitted: Instant::now(),
+            priority: Priority::Normal,
+        }
+    }
+
+    pub(crate) fn with_priority(mut self, priority: Priority) -> Job {
+        self.priority = priority;
+        self
+    }
+
+    pub(crate) fn call(self) {
+        self.task.call_box()
+    }
+}
+
+/// 创建线程池失败的原因
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// 线程数量为 0
+    ZeroSize,
+    /// 最少线程数大于最多线程数
+    MinExceedsMax { min: usize, max: usize },
+    /// 操作系统无法创建线程
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::MinExceedsMax { min, max } => {
+                write!(f, "min threads ({}) exceeds max threads ({})", min, max)
+            }
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn worker thread: {}", e),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::Spawn(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// 提交任务失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// 线程池正在关闭，任务不会再被执行
+    ShuttingDown,
+    /// 任务队列已满，并且溢出策略为 `OverflowPolicy::Reject`
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown => write!(f, "thread pool is shutting down"),
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
 
 impl ThreadPool {
     /// 创建线程池。
@@ -42,91 +186,703 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// `new` 函数在 size 为 0 时会 panic。
+    /// `new` 函数在 size 为 0 或无法创建线程时会 panic，需要处理这些情况时使用 `build`。
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// 创建线程池，size 为 0 或无法创建线程时返回错误
+    ///
+    /// 线程数固定为 size，任务队列不限长度。
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().threads(size).build()
+    }
 
-        // 一个sender 对应 一个 receiver
-        let (sender, receiver) = mpsc::channel();
+    /// 创建任务队列最多容纳 capacity 个任务的线程池，队列满时按 policy 处理
+    pub fn bounded(
+        size: usize,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder()
+            .threads(size)
+            .queue_capacity(capacity)
+            .overflow_policy(policy)
+            .build()
+    }
+
+    /// 返回线程池的构建器，用于设置线程数范围、线程名等
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
 
-        // Mutex为receiver加锁
-        // Arc使receiver能在多个线程中调用
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// 由构建器调用，参数已经检查过
+    fn from_builder(config: ThreadPoolBuilder) -> Result<ThreadPool, PoolCreationError> {
+        // Arc使队列能在多个线程中使用
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(config.queue_capacity, config.overflow_policy),
+            workers: Mutex::new(HashMap::with_capacity(config.max_threads)),
+            next_id: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            latency: Histogram::new(),
+            panicked: AtomicUsize::new(0),
+            respawned: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            token: config.token.clone().unwrap_or_default(),
+            config,
+        });
 
-        // 构建Worker的容器Vector，初始化容量为size
-        let mut workers = Vec::with_capacity(size);
+        let timer = Timer::start(Arc::clone(&shared)).map_err(PoolCreationError::Spawn)?;
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        // 创建失败时 pool 被 drop，已经创建的 worker 会被正常停掉
+        let pool = ThreadPool { shared, timer };
+        {
+            let mut workers = lock(&pool.shared.workers);
+            for _ in 0..pool.shared.config.min_threads {
+                spawn_worker(&pool.shared, &mut workers).map_err(PoolCreationError::Spawn)?;
+            }
         }
 
-        ThreadPool { workers, sender }
+        Ok(pool)
+    }
+
+    /// 当前存活的 worker 数
+    pub fn threads(&self) -> usize {
+        lock(&self.shared.workers).len()
+    }
+
+    /// 当前空闲的 worker 数
+    pub fn idle_threads(&self) -> usize {
+        self.shared.idle.load(Ordering::SeqCst)
+    }
+
+    /// 到目前为止 panic 的任务数
+    ///
+    /// 任务 panic 不会影响执行它的 worker，可以用这个数字做告警。
+    pub fn panic_count(&self) -> usize {
+        self.shared.panicked.load(Ordering::SeqCst)
+    }
+
+    /// 到目前为止因线程意外退出而被替换的 worker 数
+    pub fn respawn_count(&self) -> usize {
+        self.shared.respawned.load(Ordering::SeqCst)
+    }
+
+    /// 到目前为止因 `OverflowPolicy::DropOldest` 被丢弃的任务数
+    pub fn dropped_count(&self) -> usize {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+
+    /// 队列中等待执行的任务数
+    pub fn queued(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// 线程池当前状态的快照
+    ///
+    /// 各项数字分别读取，线程池繁忙时彼此之间可能有细微的出入。
+    pub fn stats(&self) -> PoolStats {
+        let shared = &self.shared;
+        let mut workers: Vec<WorkerStats> = lock(&shared.workers)
+            .values()
+            .map(|worker| worker.metrics.snapshot(worker.id))
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+
+        PoolStats {
+            threads: workers.len(),
+            queued: shared.queue.len(),
+            active: shared.active.load(Ordering::SeqCst),
+            completed: shared.completed.load(Ordering::SeqCst),
+            panicked: shared.panicked.load(Ordering::SeqCst),
+            dropped: shared.dropped.load(Ordering::SeqCst),
+            respawned: shared.respawned.load(Ordering::SeqCst),
+            workers,
+            latency: shared.latency.percentiles(),
+        }
     }
 
     /// 执行任务
     ///
-    /// 由sender发送任务信息
-    pub fn execute<F>(&self, f: F)
+    /// 把任务放进队列，队列满时的行为由溢出策略决定：
+    /// `Block` 会阻塞到有空位，`Reject` 返回 `ExecuteError::QueueFull`，
+    /// `DropOldest` 丢弃最早的任务，`CallerRuns` 在当前线程上直接执行任务。
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        submit(&self.shared, Job::new(f))
+    }
+
+    /// 按优先级执行任务
+    ///
+    /// `Priority::High` 的任务排在所有普通任务前面，适合健康检查、管理请求这类不能被大量普通任务拖慢的工作；
+    /// `Priority::Low` 的任务只在没有其他任务时执行，队列满时也最先被 `DropOldest` 丢弃。
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        submit(&self.shared, Job::new(f).with_priority(priority))
+    }
+
+    /// 提交一个有返回值的任务，通过返回的 `JobHandle` 取得结果
+    ///
+    /// 任务 panic 时 `join` 返回 `JoinError::Panicked`，
+    /// 任务没执行就被丢弃时返回 `JoinError::Cancelled`。
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = job::with_handle(f);
+        self.execute(job)?;
+        Ok(handle)
+    }
+}
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+/// 把任务放进队列，队列满时按溢出策略处理
+fn submit(shared: &Arc<Shared>, job: Job) -> Result<(), ExecuteError> {
+    match shared.queue.push(job)? {
+        // 先入队再检查空闲的 worker，和 retire 的顺序相反，见 retire
+        Pushed::Queued => grow_if_needed(shared),
+        Pushed::Evicted(old) => {
+            shared.dropped.fetch_add(1, Ordering::SeqCst);
+            // 被丢弃的任务在锁外释放，它捕获的资源（例如连接）随之关闭
+            drop(old);
+        }
+        Pushed::RunHere(job) => run_job(shared, job),
+    }
+    Ok(())
+}
+
+/// 排队的任务（包括刚提交的这个）多于空闲的 worker 时，增加一个 worker
+fn grow_if_needed(shared: &Arc<Shared>) {
+    // 先不加锁检查，固定大小或者不缺 worker 时不碰 workers 的锁
+    let config = &shared.config;
+    if config.min_threads == config.max_threads
+        || shared.queue.len() <= shared.idle.load(Ordering::SeqCst)
+    {
+        return;
+    }
+
+    let mut workers = lock(&shared.workers);
+    if workers.len() >= config.max_threads {
+        return;
+    }
+
+    if let Err(e) = spawn_worker(shared, &mut workers) {
+        shared.log(
+            LogLevel::Warn,
+            format_args!("Failed to spawn extra worker: {}", e),
+        );
     }
 }
 
 impl Drop for ThreadPool {
-    /// 当sender发送终止的消息时，停掉所有的worker
+    /// 取消所有定时任务并关闭任务队列，worker 执行完队列中剩余的任务后退出
+    ///
+    /// 线程池的取消信号也在这里发出，正在执行的长任务可以据此提前结束。
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        self.shared.token.cancel();
 
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
-        }
+        let shared = &self.shared;
+        shared.log(
+            LogLevel::Info,
+            format_args!("Sending terminate message to all workers."),
+        );
 
-        println!("Shutting down all workers.");
+        // 定时器线程可能正阻塞在满了的队列上，关闭队列唤醒它之后才能等它退出
+        shared.queue.close();
+        self.timer.stop();
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        shared.log(LogLevel::Info, format_args!("Shutting down all workers."));
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        let mut workers: Vec<Worker> = lock(&self.shared.workers)
+            .drain()
+            .map(|(_, worker)| worker)
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+
+        for worker in &workers {
+            shared.log(
+                LogLevel::Info,
+                format_args!("Shutting down worker {}", worker.id),
+            );
+
+            // 等待期间线程可能意外退出并被替换，所以要一直等到槽位为空
+            while let Some(thread) = lock(&worker.thread).take() {
+                if thread.join().is_err() {
+                    shared.log(
+                        LogLevel::Error,
+                        format_args!("Worker {} panicked.", worker.id),
+                    );
+                }
             }
         }
     }
 }
 
+/// 加锁并忽略中毒
+///
+/// 持锁的线程 panic 会使 Mutex 中毒，但这里保护的数据不会因此处于不一致的状态，
+/// 所以直接取出数据继续使用，避免一个 panic 让其他所有 worker 跟着 panic。
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    /// 当前的线程，线程意外退出后会被替换成新的线程
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    metrics: Arc<WorkerMetrics>,
 }
 
-impl Worker {
-    /// 构建Worker
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            // 接受到消息时，此处会被回调
-            let message = receiver.lock().unwrap().recv().unwrap();
+/// 创建一个新的 worker 并登记，调用方需要持有 workers 的锁
+fn spawn_worker(shared: &Arc<Shared>, workers: &mut HashMap<usize, Worker>) -> io::Result<()> {
+    let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+    let thread = Arc::new(Mutex::new(None));
+    let metrics = Arc::new(WorkerMetrics::default());
+    spawn_thread(
+        id,
+        Arc::clone(shared),
+        Arc::clone(&thread),
+        Arc::clone(&metrics),
+    )?;
 
-            // 对消息类型进行匹配，决定如何执行
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
+    workers.insert(
+        id,
+        Worker {
+            id,
+            thread,
+            metrics,
+        },
+    );
+    Ok(())
+}
 
-                    job.call_box();
-                }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
+/// 为 worker 创建线程，并把 JoinHandle 放进槽位
+fn spawn_thread(
+    id: usize,
+    shared: Arc<Shared>,
+    slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    metrics: Arc<WorkerMetrics>,
+) -> io::Result<()> {
+    let mut builder = thread::Builder::new().name(format!("{}-{}", shared.config.name, id));
+    if let Some(size) = shared.config.stack_size {
+        builder = builder.stack_size(size);
+    }
+
+    // 先锁住槽位，防止新线程在 JoinHandle 放进去之前就退出并替换自己
+    let mut guard = lock(&slot);
+    shared.idle.fetch_add(1, Ordering::SeqCst);
+    let sentinel = Sentinel {
+        id,
+        shared: Arc::clone(&shared),
+        slot: Arc::clone(&slot),
+        metrics,
+    };
 
+    match builder.spawn(move || run(id, &sentinel.shared, &sentinel.metrics)) {
+        Ok(handle) => {
+            *guard = Some(handle);
+            Ok(())
+        }
+        Err(e) => {
+            shared.idle.fetch_sub(1, Ordering::SeqCst);
+            Err(e)
+        }
+    }
+}
+
+/// worker 线程的主循环
+fn run(id: usize, shared: &Shared, metrics: &WorkerMetrics) {
+    // 线程数可以伸缩时才需要在空闲超时后检查是否退出
+    let config = &shared.config;
+    let timeout = if config.min_threads < config.max_threads {
+        Some(config.keep_alive)
+    } else {
+        None
+    };
+
+    let local = shared.queue.register(id);
+
+    loop {
+        match shared.queue.pop(&local, timeout) {
+            Popped::Job(job) => {
+                shared.log(
+                    LogLevel::Debug,
+                    format_args!("Worker {} got a job; executing.", id),
+                );
+
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+                shared.active.fetch_add(1, Ordering::SeqCst);
+                let start = Instant::now();
+                run_job(shared, job);
+                metrics.record(start.elapsed());
+                shared.active.fetch_sub(1, Ordering::SeqCst);
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+            }
+            Popped::Timeout => {
+                if retire(id, shared) {
+                    shared.log(
+                        LogLevel::Info,
+                        format_args!("Worker {} retired after being idle.", id),
+                    );
                     break;
                 }
             }
-        });
+            // 队列关闭并且取空
+            Popped::Closed => {
+                shared.log(
+                    LogLevel::Info,
+                    format_args!("Worker {} was told to terminate.", id),
+                );
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+        }
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    shared.queue.unregister(id);
+}
+
+/// 空闲超时的 worker 在线程数多于 min_threads、队列中也没有任务时退出
+///
+/// 退出时 worker 已经不再计入 idle。提交任务时先入队再读 idle，这里先减 idle 再读队列，
+/// 所以要么这里看到新任务而留下，要么 `grow_if_needed` 看到少了一个空闲的 worker，
+/// 等这里放开锁之后补上一个，不会有任务留在没有 worker 的队列里。
+fn retire(id: usize, shared: &Shared) -> bool {
+    let mut workers = lock(&shared.workers);
+    // 线程池正在 drop 时 worker 已经被取走，交给 drop 等待它退出
+    if workers.len() <= shared.config.min_threads || !workers.contains_key(&id) {
+        return false;
+    }
+
+    shared.idle.fetch_sub(1, Ordering::SeqCst);
+    if shared.queue.len() > 0 {
+        shared.idle.fetch_add(1, Ordering::SeqCst);
+        return false;
+    }
+
+    // 移除后 JoinHandle 被丢弃，线程随即自行结束
+    workers.remove(&id);
+    true
+}
+
+/// 执行一个任务，任务 panic 时只记录下来，不影响当前线程
+fn run_job(shared: &Shared, job: Job) {
+    let submitted = job.submitted;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| job.call()));
+    shared.latency.record(submitted.elapsed());
+    if result.is_ok() {
+        shared.completed.fetch_add(1, Ordering::SeqCst);
+    } else {
+        shared.panicked.fetch_add(1, Ordering::SeqCst);
+        shared.log(LogLevel::Warn, format_args!("Job panicked."));
+    }
+    // panic 的 payload 在 drop 时也可能 panic，这种情况交给 Sentinel 处理
+    drop(result);
+}
+
+/// 随 worker 线程一起存在，线程因 panic 退出时创建一个新线程顶替它
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+    slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    metrics: Arc<WorkerMetrics>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+
+        self.shared.log(
+            LogLevel::Error,
+            format_args!("Worker {} died unexpectedly; respawning.", self.id),
+        );
+        self.shared.respawned.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = spawn_thread(
+            self.id,
+            Arc::clone(&self.shared),
+            Arc::clone(&self.slot),
+            Arc::clone(&self.metrics),
+        ) {
+            self.shared.log(
+                LogLevel::Error,
+                format_args!("Failed to respawn worker {}: {}", self.id, e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn build_rejects_zero_size() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+        assert!(matches!(
+            ThreadPool::builder().min_threads(4).max_threads(2).build(),
+            Err(PoolCreationError::MinExceedsMax { min: 4, max: 2 })
+        ));
+    }
+
+    #[test]
+    fn executes_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+
+        let mut results: Vec<i32> = rx.iter().take(4).collect();
+        results.sort();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_kill_workers() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic!("boom")).unwrap();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+
+        rx.recv().unwrap();
+        assert_eq!(pool.panic_count(), 1);
+        assert_eq!(pool.respawn_count(), 0);
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        /// drop 时会 panic 的 payload，让 panic 逃出 catch_unwind
+        struct Bomb;
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("payload dropped");
+            }
+        }
+
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic::panic_any(Bomb)).unwrap();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+
+        // 唯一的 worker 线程退出后，新线程接着处理后面的任务
+        rx.recv().unwrap();
+        assert_eq!(pool.panic_count(), 1);
+        assert_eq!(pool.respawn_count(), 1);
+    }
+
+    /// 占住唯一的 worker，直到 release 收到消息
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, ready) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait.recv();
+        })
+        .unwrap();
+        ready.recv().unwrap();
+        release
+    }
+
+    #[test]
+    fn reject_policy_returns_queue_full() {
+        let pool = ThreadPool::bounded(1, 1, OverflowPolicy::Reject).unwrap();
+        let release = block_worker(&pool);
+
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+        assert_eq!(pool.queued(), 1);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn drop_oldest_policy_evicts_first_job() {
+        let pool = ThreadPool::bounded(1, 1, OverflowPolicy::DropOldest).unwrap();
+        let release = block_worker(&pool);
+        let (tx, rx) = mpsc::channel();
+
+        for i in 0..3 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+        release.send(()).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(pool.dropped_count(), 2);
+    }
+
+    #[test]
+    fn caller_runs_policy_runs_on_calling_thread() {
+        // 容量为 0，任何任务都放不进队列
+        let pool = ThreadPool::bounded(1, 0, OverflowPolicy::CallerRuns).unwrap();
+        let caller = thread::current().id();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(move || tx.send(thread::current().id()).unwrap())
+            .unwrap();
+        assert_eq!(rx.try_recv().unwrap(), caller);
+    }
+
+    #[test]
+    fn block_policy_waits_for_space() {
+        let pool = ThreadPool::bounded(1, 1, OverflowPolicy::Block).unwrap();
+        let release = block_worker(&pool);
+        pool.execute(|| {}).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let pool = Arc::new(pool);
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.execute(move || tx.send(()).unwrap()))
+        };
+
+        // 队列满着，提交方被阻塞
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        release.send(()).unwrap();
+        submitter.join().unwrap().unwrap();
+        rx.recv().unwrap();
+    }
+
+    #[test]
+    fn elastic_pool_grows_and_shrinks() {
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(3)
+            .keep_alive(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert_eq!(pool.threads(), 1);
+
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        for _ in 0..3 {
+            let wait = Arc::clone(&wait);
+            pool.execute(move || {
+                let _ = lock(&wait).recv();
+            })
+            .unwrap();
+        }
+        assert!(eventually(|| pool.threads() == 3));
+
+        // 第四个任务到来时已经达到上限，只能排队
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.threads(), 3);
+
+        drop(release);
+        assert!(eventually(|| pool.threads() == 1));
+    }
+
+    /// 在几秒之内反复检查，条件成立时返回 true
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        condition()
+    }
+
+    #[test]
+    fn retiring_workers_never_strand_jobs() {
+        // 最后一个 worker 空闲超时退出的同时提交任务，任务也一定会被执行
+        let pool = ThreadPool::builder()
+            .min_threads(0)
+            .max_threads(1)
+            .keep_alive(Duration::from_millis(1))
+            .build()
+            .unwrap();
+        for i in 0..200 {
+            thread::sleep(Duration::from_micros(i % 20 * 100));
+            let handle = pool.spawn(move || i).unwrap();
+            assert_eq!(handle.join_timeout(Duration::from_secs(5)), Ok(i));
         }
     }
+
+    #[test]
+    fn threads_are_named_after_the_pool() {
+        let pool = ThreadPool::builder()
+            .threads(1)
+            .name("a20-test")
+            .stack_size(256 * 1024)
+            .build()
+            .unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(move || tx.send(thread::current().name().map(String::from)).unwrap())
+            .unwrap();
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("a20-test-0"));
+    }
+
+    #[test]
+    fn stats_and_logs_follow_the_jobs() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&logs);
+        let pool = ThreadPool::builder()
+            .threads(2)
+            .logger(move |level, message| lock(&captured).push((level, message.to_string())))
+            .build()
+            .unwrap();
+
+        for i in 0..10 {
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(2));
+                if i == 9 {
+                    panic!("last one");
+                }
+            })
+            .unwrap();
+        }
+
+        // 计数在任务返回之后才更新，等它们追上
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut stats = pool.stats();
+        while stats.completed + (stats.panicked as u64) < 10 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+            stats = pool.stats();
+        }
+
+        assert_eq!(stats.threads, 2);
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.completed, 9);
+        assert_eq!(stats.panicked, 1);
+        assert_eq!(stats.latency.count, 10);
+        assert!(stats.latency.p50 >= Duration::from_millis(2));
+        assert!(stats.latency.p50 <= stats.latency.max);
+        assert_eq!(stats.workers.iter().map(|w| w.jobs).sum::<u64>(), 10);
+        assert!(stats
+            .workers
+            .iter()
+            .all(|w| w.busy >= w.jobs as u32 * Duration::from_millis(2)));
+
+        let logs = lock(&logs);
+        let debug = logs
+            .iter()
+            .filter(|(level, _)| *level == LogLevel::Debug)
+            .count();
+        assert_eq!(debug, 10);
+        assert!(logs.contains(&(LogLevel::Warn, "Job panicked.".to_string())));
+    }
 }
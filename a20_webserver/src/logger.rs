@@ -0,0 +1,111 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// 线程池日志的级别
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// 每个任务都会产生的日志，例如 worker 取到任务
+    Debug,
+    /// worker 的创建、退出等生命周期事件
+    Info,
+    /// 任务 panic、无法增加 worker 等需要留意的情况
+    Warn,
+    /// worker 线程意外退出等错误
+    Error,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 内置日志的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// 每条日志一行纯文本
+    #[default]
+    Text,
+    /// 每条日志一行 JSON，例如 `{"level":"INFO","message":"..."}`
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 接收线程池日志的回调，参数在需要时才格式化
+pub(crate) type Logger = Arc<dyn Fn(LogLevel, fmt::Arguments) + Send + Sync>;
+
+/// 默认的日志：Info 及以上的级别打印到标准输出，Debug 直接丢弃
+pub(crate) fn stdout_logger(format: LogFormat) -> Logger {
+    match format {
+        LogFormat::Text => Arc::new(|level, args| {
+            if level >= LogLevel::Info {
+                println!("{}", args);
+            }
+        }),
+        LogFormat::Json => Arc::new(|level, args| {
+            if level >= LogLevel::Info {
+                println!(
+                    r#"{{"level":"{}","message":"{}"}}"#,
+                    level,
+                    json_escape(&args.to_string())
+                );
+            }
+        }),
+    }
+}
+
+/// 转义成 JSON 字符串的内容，不含两边的引号
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escape_handles_quotes_and_control_characters() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(
+            json_escape("say \"hi\"\\\n\u{1}"),
+            "say \\\"hi\\\"\\\\\\n\\u0001"
+        );
+    }
+}
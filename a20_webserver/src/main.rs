@@ -1,64 +1,66 @@
-use a20_webserver;
+use a20_webserver::config::{self, ConfigError, ServerConfig};
+use a20_webserver::http::Request;
+use a20_webserver::response::Response;
+use a20_webserver::router::{Handler, Router};
+use a20_webserver::server::{self, Server};
+use a20_webserver::static_files::StaticFiles;
+use a20_webserver::CancellationToken;
 
-use a20_webserver::ThreadPool;
-
-use std::fs::File;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::thread;
+use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
 fn main() {
-    // 绑定本地IP:Port监听
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-
-    // 创建线程池
-    let pool = ThreadPool::new(8);
+    // 配置文件 < 环境变量 < 命令行参数，运行 --help 查看所有选项
+    let config = ServerConfig::load().unwrap_or_else(|e| {
+        if let ConfigError::Help = e {
+            println!("{}", config::USAGE);
+            process::exit(0);
+        }
+        eprintln!("Invalid configuration: {}", e);
+        eprintln!("Run with --help to see the available options.");
+        process::exit(2);
+    });
 
-    // 遍历监听的listener
-    // take(10) 表示只接受10个请求
-    for stream in listener.incoming().take(10) {
-        let stream = stream.unwrap();
+    let files = StaticFiles::new(&config.root).unwrap_or_else(|e| {
+        eprintln!("Invalid document root {}: {}", config.root.display(), e);
+        process::exit(1);
+    });
+    let files = Arc::new(
+        files
+            .with_index("hello.html")
+            .with_not_found_page("404.html")
+            .with_precompressed(config.compression),
+    );
 
-        // 使用线程池处理请求过来的stream
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
-
-    println!("Shutting down.");
-}
+    // 注册路由
+    let sleep_files = Arc::clone(&files);
+    let token = CancellationToken::new();
+    let sleep_token = token.clone();
+    let router = Router::new()
+        .get("/sleep", move |_: &mut Request| {
+            // 等5秒，服务器强制关闭时提前结束
+            if sleep_token.wait_timeout(Duration::from_secs(5)) {
+                return Response::text(503, "503 Service Unavailable");
+            }
+            sleep_files.serve("hello.html")
+        })
+        .get("/*path", move |req: &mut Request| files.handle(req));
 
-/// 处理tcp连接请求，进行不同的响应
-fn handle_connection(mut stream: TcpStream) {
-    // 将stream存入buffer
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+    // 按配置监听地址，由线程池处理连接
+    let server = Server::from_config(&config, router)
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to bind: {}", e);
+            process::exit(1);
+        })
+        .cancellation_token(token);
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    // Ctrl-C 或 kill 时优雅关闭
+    #[cfg(unix)]
+    server::shutdown_on_signals(server.shutdown_handle().unwrap());
 
-    // 判断请求以什么开头，决定响应不同的内容
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        // 线程睡5秒
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
-    };
-
-    // 读取本地文件，并转换为String
-    let mut contents = String::new();
-    let mut file = File::open(filename).unwrap();
-    file.read_to_string(&mut contents).unwrap();
-
-    // 封装响应体
-    let response = format!("{}\r\nContent-Length: {}\r\n\r\n{}", status_line, contents.len(), contents);
-
-    // 返回数据
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    if let Err(e) = server.run() {
+        eprintln!("Failed to start server: {}", e);
+        process::exit(1);
+    }
 }
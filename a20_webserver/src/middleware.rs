@@ -0,0 +1,189 @@
+//! 包在 handler 外面、对每个请求都生效的中间件
+//!
+//! 日志、认证、CORS、计时这类与具体路由无关的逻辑写成 [`Middleware`]，
+//! 用 [`Stack`] 按顺序套在 handler 外面，或者通过
+//! [`Server::middleware`](crate::server::Server::middleware) 作用于整个服务器。
+
+use crate::http::Request;
+use crate::response::Response;
+use crate::router::Handler;
+
+use std::sync::Arc;
+
+/// 中间件
+///
+/// 调用 `next.run(request)` 把请求交给后面的中间件和 handler：
+/// 在这之前可以修改请求，之后可以修改响应，不调用则直接以自己的响应结束（短路）。
+///
+/// 任何 `Fn(&mut Request, Next) -> Response` 的闭包都自动实现了 Middleware。
+///
+/// ```
+/// use a20_webserver::http::Request;
+/// use a20_webserver::middleware::{Next, Stack};
+/// use a20_webserver::response::Response;
+/// use std::time::Instant;
+///
+/// let handler = Stack::new(|_: &mut Request| Response::text(200, "hello"))
+///     .with(|req: &mut Request, next: Next| {
+///         let start = Instant::now();
+///         let mut response = next.run(req);
+///         let elapsed = format!("{}us", start.elapsed().as_micros());
+///         response.set_header("X-Response-Time", &elapsed);
+///         response
+///     })
+///     .with(|req: &mut Request, next: Next| match req.header("x-api-key") {
+///         Some(_) => next.run(req),
+///         None => Response::text(401, "401 Unauthorized"),
+///     });
+/// ```
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: &mut Request, next: Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// 中间件之后剩下的部分：其余的中间件和最终的 handler
+///
+/// `run` 会消耗 Next，每个中间件最多把请求往下传一次。
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    /// 把请求交给下一个中间件，没有了就交给 handler
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middlewares: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+/// 套上了一组中间件的 handler
+///
+/// 先加入的中间件在最外层：最先看到请求，最后看到响应。
+/// Stack 本身也是 Handler，可以只用在某个路由上。
+pub struct Stack {
+    pub(crate) middlewares: Vec<Box<dyn Middleware>>,
+    pub(crate) handler: Arc<dyn Handler>,
+}
+
+impl Stack {
+    pub fn new<H: Handler>(handler: H) -> Stack {
+        Stack {
+            middlewares: Vec::new(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// 在已有的中间件里面再套一层
+    pub fn with<M: Middleware>(mut self, middleware: M) -> Stack {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Handler for Stack {
+    fn handle(&self, request: &mut Request) -> Response {
+        Next {
+            middlewares: &self.middlewares,
+            handler: &*self.handler,
+        }
+        .run(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    /// 记录经过顺序的中间件
+    struct Trace {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Trace {
+        fn handle(&self, request: &mut Request, next: Next) -> Response {
+            self.log.lock().unwrap().push(format!("{} in", self.name));
+            let response = next.run(request);
+            self.log.lock().unwrap().push(format!("{} out", self.name));
+            response
+        }
+    }
+
+    #[test]
+    fn runs_in_order_around_the_handler() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler_log = Arc::clone(&log);
+        let stack = Stack::new(move |_: &mut Request| {
+            handler_log.lock().unwrap().push("handler".to_string());
+            Response::new(204)
+        })
+        .with(Trace {
+            name: "a",
+            log: Arc::clone(&log),
+        })
+        .with(Trace {
+            name: "b",
+            log: Arc::clone(&log),
+        });
+
+        assert_eq!(stack.handle(&mut get("/")).status, 204);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["a in", "b in", "handler", "b out", "a out"]
+        );
+
+        // 没有中间件时直接调用 handler
+        let plain = Stack::new(|_: &mut Request| Response::new(204));
+        assert_eq!(plain.handle(&mut get("/")).status, 204);
+    }
+
+    #[test]
+    fn modifies_requests_and_responses_or_short_circuits() {
+        let stack = Stack::new(|req: &mut Request| Response::text(200, &req.path))
+            .with(|req: &mut Request, next: Next| {
+                if req.path == "/blocked" {
+                    return Response::text(403, "403 Forbidden");
+                }
+                next.run(req)
+            })
+            .with(|req: &mut Request, next: Next| {
+                req.path = format!("/api{}", req.path);
+                let mut response = next.run(req);
+                response.set_header("X-Wrapped", "yes");
+                response
+            });
+
+        let response = stack.handle(&mut get("/users"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-wrapped"), Some("yes"));
+        assert_eq!(response.body.len(), "/api/users".len() as u64);
+
+        // 外层短路时里面的中间件和 handler 都不会运行
+        let response = stack.handle(&mut get("/blocked"));
+        assert_eq!(response.status, 403);
+        assert_eq!(response.header("x-wrapped"), None);
+    }
+}
@@ -0,0 +1,649 @@
+use crate::{lock, ExecuteError, Job};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 一次从全局队列或其他 worker 那里最多拿走的任务数
+const MAX_BATCH: usize = 32;
+
+/// 找不到任务时先让出几次 CPU 再睡眠，短任务密集提交时不必每次都唤醒线程
+const SPIN_ROUNDS: usize = 8;
+
+/// 任务队列满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// 阻塞调用方，直到队列有空位
+    Block,
+    /// 立即返回 `ExecuteError::QueueFull`
+    Reject,
+    /// 丢弃队列中最早的任务，为新任务腾出位置
+    DropOldest,
+    /// 在调用方的线程上直接执行新任务
+    CallerRuns,
+}
+
+/// 任务的优先级
+///
+/// 高优先级的任务排在所有普通任务前面，低优先级的任务只在没有其他任务时执行。
+/// 同一优先级内按提交顺序执行。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// push 成功时的几种结果
+pub(crate) enum Pushed {
+    /// 任务进入了队列
+    Queued,
+    /// 任务进入了队列，为此挤掉了最早的任务
+    Evicted(Job),
+    /// 队列已满，任务需要由调用方执行
+    RunHere(Job),
+}
+
+/// pop 的几种结果
+pub(crate) enum Popped {
+    Job(Job),
+    /// 等待超时，队列仍然为空
+    Timeout,
+    /// 队列已关闭并且已经取空
+    Closed,
+}
+
+/// 每个 worker 自己的任务队列
+pub(crate) type Local = Mutex<VecDeque<Job>>;
+
+/// 高、低优先级任务各自的全局队列
+///
+/// len 用来在不加锁的情况下判断是否为空，大部分时候这两个队列都是空的。
+struct Lane {
+    jobs: Mutex<VecDeque<Job>>,
+    len: AtomicUsize,
+}
+
+impl Lane {
+    fn new() -> Lane {
+        Lane {
+            jobs: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, job: Job) {
+        let mut jobs = lock(&self.jobs);
+        jobs.push_back(job);
+        self.len.store(jobs.len(), Ordering::SeqCst);
+    }
+
+    fn pop_front(&self) -> Option<Job> {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let mut jobs = lock(&self.jobs);
+        let job = jobs.pop_front();
+        self.len.store(jobs.len(), Ordering::SeqCst);
+        job
+    }
+}
+
+thread_local! {
+    /// 当前线程是 worker 时，记录它所属的 JobQueue 的地址和它的本地队列
+    static CURRENT: RefCell<Option<(usize, Arc<Local>)>> = const { RefCell::new(None) };
+}
+
+/// worker 共享的任务调度器
+///
+/// 从外部提交的普通任务进入全局队列，worker 在执行任务时提交的普通任务进入它自己的本地队列。
+/// worker 先从本地队列的尾部取任务；本地队列空了就从全局队列成批取一部分；
+/// 全局队列也空了就从其他 worker 本地队列的头部偷一半。
+/// 这样大部分时候各个 worker 只碰自己的锁，不会都挤在同一把锁上。
+///
+/// 高优先级和低优先级的任务各有一个全局队列，不进本地队列。
+/// worker 每次取任务都先看高优先级队列，所有普通任务都没有了才看低优先级队列。
+///
+/// 所有队列里的任务总数记在 len 中，容量和溢出策略都按总数计算。
+/// capacity 为 None 时不限制长度。关闭之后不再接受新任务，
+/// 但已经在队列里的任务仍然会被取出执行。
+pub(crate) struct JobQueue {
+    injector: Mutex<VecDeque<Job>>,
+    high: Lane,
+    low: Lane,
+    locals: RwLock<Vec<(usize, Arc<Local>)>>,
+    /// 所有队列中的任务数，包括已经占了名额但还没放进队列的任务
+    len: AtomicUsize,
+    closed: AtomicBool,
+    /// 正在等待任务的 worker 数
+    sleepers: AtomicUsize,
+    /// 刚被唤醒、正在找任务的 worker 数
+    searching: AtomicUsize,
+    /// 因为队列已满而等待的调用方数
+    blocked: AtomicUsize,
+    /// 下一次偷任务时从哪个 worker 开始找
+    next_victim: AtomicUsize,
+    /// 只用来配合两个 Condvar 睡眠和唤醒
+    sleep: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: Option<usize>, policy: OverflowPolicy) -> JobQueue {
+        JobQueue {
+            injector: Mutex::new(VecDeque::new()),
+            high: Lane::new(),
+            low: Lane::new(),
+            locals: RwLock::new(Vec::new()),
+            len: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            next_victim: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// 放入任务，队列满时按 policy 处理
+    pub(crate) fn push(&self, job: Job) -> Result<Pushed, ExecuteError> {
+        let mut evicted = None;
+        while !self.try_reserve()? {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    self.wait_for_room()?;
+                    break;
+                }
+                OverflowPolicy::Reject => return Err(ExecuteError::QueueFull),
+                OverflowPolicy::DropOldest => {
+                    // 容量为 0 时队列里没有可以丢弃的任务
+                    if self.capacity == Some(0) {
+                        return Err(ExecuteError::QueueFull);
+                    }
+                    // 新任务直接占用被挤掉的任务的名额。没找到任务说明刚被 worker 取走，重试即可
+                    if let Some(old) = self.take_oldest() {
+                        evicted = Some(old);
+                        break;
+                    }
+                }
+                OverflowPolicy::CallerRuns => return Ok(Pushed::RunHere(job)),
+            }
+        }
+
+        self.insert(job);
+
+        Ok(match evicted {
+            Some(old) => Pushed::Evicted(old),
+            None => Pushed::Queued,
+        })
+    }
+
+    /// worker 取任务，所有队列都为空时最多等待 timeout，timeout 为 None 时一直等待
+    pub(crate) fn pop(&self, local: &Local, timeout: Option<Duration>) -> Popped {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut searching = false;
+        let mut spins = 0;
+
+        loop {
+            if let Some(job) = self.find(local) {
+                self.release();
+                if searching {
+                    self.searching.fetch_sub(1, Ordering::SeqCst);
+                }
+                // 还有剩下的任务时叫醒一个睡着的 worker 来偷
+                if self.len.load(Ordering::SeqCst) > 0 {
+                    self.wake_worker();
+                }
+                return Popped::Job(job);
+            }
+
+            if spins < SPIN_ROUNDS {
+                if !searching {
+                    searching = true;
+                    self.searching.fetch_add(1, Ordering::SeqCst);
+                }
+                spins += 1;
+                thread::yield_now();
+                continue;
+            }
+
+            spins = 0;
+            if let Some(popped) = self.sleep_until_work(deadline, searching) {
+                return popped;
+            }
+            searching = true;
+        }
+    }
+
+    /// 登记 worker 并返回它的本地队列，同一个 id 重复登记时返回原来的队列
+    ///
+    /// 需要在 worker 线程上调用，之后这个线程提交的任务会进入它的本地队列。
+    pub(crate) fn register(&self, id: usize) -> Arc<Local> {
+        let local = {
+            let mut locals = self.locals.write().unwrap_or_else(PoisonError::into_inner);
+            match locals.iter().find(|(other, _)| *other == id) {
+                Some((_, local)) => Arc::clone(local),
+                None => {
+                    let local = Arc::new(Mutex::new(VecDeque::new()));
+                    locals.push((id, Arc::clone(&local)));
+                    local
+                }
+            }
+        };
+
+        let key = self.key();
+        CURRENT.with(|current| *current.borrow_mut() = Some((key, Arc::clone(&local))));
+        local
+    }
+
+    /// worker 退出时注销，本地队列中剩下的任务转移到全局队列
+    pub(crate) fn unregister(&self, id: usize) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+
+        let removed = {
+            let mut locals = self.locals.write().unwrap_or_else(PoisonError::into_inner);
+            match locals.iter().position(|(other, _)| *other == id) {
+                Some(index) => locals.swap_remove(index).1,
+                None => return,
+            }
+        };
+
+        let leftover: Vec<Job> = lock(&removed).drain(..).collect();
+        if !leftover.is_empty() {
+            lock(&self.injector).extend(leftover);
+            self.wake_worker();
+        }
+    }
+
+    /// 关闭队列，唤醒所有等待中的 worker 和调用方
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = lock(&self.sleep);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// 取出所有还没开始执行的任务，一般在 close 之后调用
+    pub(crate) fn drain(&self) -> Vec<Job> {
+        let mut jobs = Vec::new();
+        while let Some(job) = self.take_oldest() {
+            self.release();
+            jobs.push(job);
+        }
+        jobs
+    }
+
+    /// 所有队列中等待执行的任务数
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// 用地址区分不同的 JobQueue，JobQueue 放在 Arc 中，地址不会变
+    fn key(&self) -> usize {
+        self as *const JobQueue as usize
+    }
+
+    /// 为新任务占一个名额，队列已满时返回 false
+    fn try_reserve(&self) -> Result<bool, ExecuteError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ExecuteError::ShuttingDown);
+        }
+
+        let reserved = match self.capacity {
+            None => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(capacity) => self
+                .len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                    if len < capacity {
+                        Some(len + 1)
+                    } else {
+                        None
+                    }
+                })
+                .is_ok(),
+        };
+
+        // 先占名额再检查 closed：worker 先看到 closed 再看 len，
+        // 这样要么这里看到 closed，要么 worker 看到这个名额，不会有任务被落下
+        if reserved && self.closed.load(Ordering::SeqCst) {
+            self.release();
+            return Err(ExecuteError::ShuttingDown);
+        }
+        Ok(reserved)
+    }
+
+    /// 阻塞到占到名额或者队列关闭
+    fn wait_for_room(&self) -> Result<(), ExecuteError> {
+        let mut guard = lock(&self.sleep);
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+
+        let result = loop {
+            match self.try_reserve() {
+                Ok(true) => break Ok(()),
+                Ok(false) => {}
+                Err(e) => break Err(e),
+            }
+            guard = self
+                .not_full
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        };
+
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// 释放一个名额，有调用方在等待空位时唤醒一个
+    fn release(&self) {
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            // 只需要等对方进入 wait，在锁外通知，被唤醒的线程不用再等这把锁
+            drop(lock(&self.sleep));
+            self.not_full.notify_one();
+        }
+    }
+
+    /// 把已经占了名额的任务放进队列
+    fn insert(&self, job: Job) {
+        match job.priority {
+            Priority::High => self.high.push(job),
+            Priority::Low => self.low.push(job),
+            Priority::Normal => self.insert_normal(job),
+        }
+        self.wake_worker();
+    }
+
+    fn insert_normal(&self, job: Job) {
+        let key = self.key();
+        let job = CURRENT.with(|current| match &*current.borrow() {
+            Some((owner, local)) if *owner == key => {
+                lock(local).push_back(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            lock(&self.injector).push_back(job);
+        }
+    }
+
+    /// 有 worker 在睡眠时唤醒一个
+    ///
+    /// 已经有 worker 醒着在找任务时不再叫醒别人：它在睡回去之前会再看一次 len，
+    /// 不会漏掉任务。这样大量提交短任务时不会每次都唤醒一个线程。
+    fn wake_worker(&self) {
+        if self.searching.load(Ordering::SeqCst) == 0 && self.sleepers.load(Ordering::SeqCst) > 0 {
+            drop(lock(&self.sleep));
+            self.not_empty.notify_one();
+        }
+    }
+
+    /// 依次从高优先级队列、本地队列、全局队列、其他 worker 和低优先级队列找任务
+    fn find(&self, local: &Local) -> Option<Job> {
+        if let Some(job) = self.high.pop_front() {
+            return Some(job);
+        }
+        if let Some(job) = lock(local).pop_back() {
+            return Some(job);
+        }
+        if let Some(job) = self.take_from_injector(local) {
+            return Some(job);
+        }
+        if let Some(job) = self.steal(local) {
+            return Some(job);
+        }
+        self.low.pop_front()
+    }
+
+    /// 从全局队列取一个任务，并按 worker 数分一批到本地队列
+    fn take_from_injector(&self, local: &Local) -> Option<Job> {
+        let workers = self
+            .locals
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+            .max(1);
+
+        let (job, batch) = {
+            let mut injector = lock(&self.injector);
+            let job = injector.pop_front()?;
+            let count = (injector.len() / workers).min(MAX_BATCH);
+            let batch: Vec<Job> = injector.drain(..count).collect();
+            (job, batch)
+        };
+
+        // 不同时持有两把锁，避免和偷任务的 worker 互相等待
+        if !batch.is_empty() {
+            lock(local).extend(batch);
+        }
+        Some(job)
+    }
+
+    /// 从其他 worker 本地队列的头部偷走一半任务
+    fn steal(&self, local: &Local) -> Option<Job> {
+        let locals = self.locals.read().unwrap_or_else(PoisonError::into_inner);
+        let count = locals.len();
+        let start = self.next_victim.fetch_add(1, Ordering::Relaxed);
+
+        for i in 0..count {
+            let victim = &locals[(start + i) % count].1;
+            if std::ptr::eq(&**victim, local) {
+                continue;
+            }
+
+            let mut stolen = {
+                let mut jobs = lock(victim);
+                let half = jobs.len().div_ceil(2).min(MAX_BATCH);
+                jobs.drain(..half).collect::<VecDeque<Job>>()
+            };
+
+            if let Some(job) = stolen.pop_front() {
+                if !stolen.is_empty() {
+                    lock(local).extend(stolen);
+                }
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    /// 取出最早的任务，优先丢弃低优先级的任务，高优先级的任务最后才丢弃
+    fn take_oldest(&self) -> Option<Job> {
+        if let Some(job) = self.low.pop_front() {
+            return Some(job);
+        }
+        if let Some(job) = lock(&self.injector).pop_front() {
+            return Some(job);
+        }
+        let oldest = {
+            let locals = self.locals.read().unwrap_or_else(PoisonError::into_inner);
+            locals.iter().find_map(|(_, local)| lock(local).pop_front())
+        };
+        oldest.or_else(|| self.high.pop_front())
+    }
+
+    /// 没有任务时睡眠，有任务可取时返回 None，之后调用方算作在找任务
+    fn sleep_until_work(&self, deadline: Option<Instant>, searching: bool) -> Option<Popped> {
+        let mut guard = lock(&self.sleep);
+        // 要在检查 len 之前退出 searching，和 wake_worker 的检查顺序相对应
+        if searching {
+            self.searching.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+
+        let popped = loop {
+            // 先看 closed 再看 len，和 try_reserve 的顺序相对应
+            let closed = self.closed.load(Ordering::SeqCst);
+            if self.len.load(Ordering::SeqCst) > 0 {
+                break None;
+            }
+            if closed {
+                break Some(Popped::Closed);
+            }
+
+            guard = match deadline {
+                None => self
+                    .not_empty
+                    .wait(guard)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Some(Popped::Timeout);
+                    }
+                    self.not_empty
+                        .wait_timeout(guard, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        };
+
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        if popped.is_none() {
+            self.searching.fetch_add(1, Ordering::SeqCst);
+        }
+        popped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn job(tx: &mpsc::Sender<usize>, value: usize) -> Job {
+        let tx = tx.clone();
+        Job::new(move || tx.send(value).unwrap())
+    }
+
+    fn run(popped: Popped) {
+        match popped {
+            Popped::Job(job) => job.call(),
+            _ => panic!("expected a job"),
+        }
+    }
+
+    #[test]
+    fn jobs_pushed_by_a_worker_go_to_its_local_queue() {
+        let queue = Arc::new(JobQueue::new(None, OverflowPolicy::Block));
+        let (tx, _rx) = mpsc::channel();
+
+        let worker = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || {
+                let local = queue.register(0);
+                for i in 0..4 {
+                    queue.push(job(&tx, i)).unwrap();
+                }
+                let queued = lock(&local).len();
+                queue.unregister(0);
+                queued
+            })
+        };
+
+        assert_eq!(worker.join().unwrap(), 4);
+        // 注销之后剩下的任务转移到全局队列
+        assert_eq!(lock(&queue.injector).len(), 4);
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn idle_workers_steal_from_busy_ones() {
+        let queue = Arc::new(JobQueue::new(None, OverflowPolicy::Block));
+        let (tx, rx) = mpsc::channel();
+
+        let busy = Arc::new(Mutex::new(VecDeque::new()));
+        for i in 0..4 {
+            lock(&busy).push_back(job(&tx, i));
+        }
+        queue.locals.write().unwrap().push((0, Arc::clone(&busy)));
+        queue.len.store(4, Ordering::SeqCst);
+
+        let idle = queue.register(1);
+        // 偷走头部的一半，一个直接返回，另一个放进自己的本地队列
+        run(queue.pop(&idle, None));
+        assert_eq!(rx.recv().unwrap(), 0);
+        assert_eq!(lock(&idle).len(), 1);
+        assert_eq!(lock(&busy).len(), 2);
+        assert_eq!(queue.len(), 3);
+        queue.unregister(1);
+    }
+
+    #[test]
+    fn high_priority_jobs_jump_ahead_and_low_ones_wait() {
+        let queue = JobQueue::new(None, OverflowPolicy::Block);
+        let (tx, rx) = mpsc::channel();
+
+        queue
+            .push(job(&tx, 1).with_priority(Priority::Low))
+            .unwrap();
+        queue.push(job(&tx, 2)).unwrap();
+        queue.push(job(&tx, 3)).unwrap();
+        queue
+            .push(job(&tx, 4).with_priority(Priority::High))
+            .unwrap();
+
+        let local = queue.register(0);
+        for _ in 0..4 {
+            run(queue.pop(&local, None));
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![4, 2, 3, 1]);
+        queue.unregister(0);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_low_priority_jobs_first() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::DropOldest);
+        let (tx, _rx) = mpsc::channel();
+
+        queue.push(job(&tx, 1)).unwrap();
+        queue
+            .push(job(&tx, 2).with_priority(Priority::Low))
+            .unwrap();
+        match queue.push(job(&tx, 3)).unwrap() {
+            Pushed::Evicted(old) => assert_eq!(old.priority, Priority::Low),
+            _ => panic!("expected an eviction"),
+        }
+    }
+
+    #[test]
+    fn closed_queue_is_drained_before_reporting_closed() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::Reject);
+        let (tx, rx) = mpsc::channel();
+
+        queue.push(job(&tx, 1)).unwrap();
+        queue.push(job(&tx, 2)).unwrap();
+        assert!(matches!(
+            queue.push(job(&tx, 3)),
+            Err(ExecuteError::QueueFull)
+        ));
+
+        queue.close();
+        assert!(matches!(
+            queue.push(job(&tx, 4)),
+            Err(ExecuteError::ShuttingDown)
+        ));
+
+        let local = queue.register(0);
+        run(queue.pop(&local, None));
+        run(queue.pop(&local, None));
+        assert!(matches!(queue.pop(&local, None), Popped::Closed));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+        queue.unregister(0);
+    }
+}
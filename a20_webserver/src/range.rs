@@ -0,0 +1,335 @@
+//! `Range: bytes=...` 的解析，以及多个范围的 `multipart/byteranges` 响应体
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 一个请求最多接受多少个范围，再多就当作没有 Range，直接返回整个文件
+const MAX_RANGES: usize = 16;
+
+/// 文件中的一段，两端都包含在内
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Content-Range 的值，例如 `bytes 0-499/1234`
+    pub(crate) fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// 解析 Range 的结果
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// 单位不是 bytes、格式有误或者范围太多，按规范忽略 Range 返回整个文件
+    Ignore,
+    /// 没有一个范围落在文件内，应该返回 416
+    Unsatisfiable,
+    /// 至少一个范围可以满足，不能满足的已经去掉
+    Satisfiable(Vec<ByteRange>),
+}
+
+/// 按文件长度 len 解析 Range 请求头
+///
+/// 支持 `a-b`、`a-` 和 `-n`（最后 n 个字节）。范围有重叠或相邻时合并成从小到大的顺序，
+/// 否则保持请求中的顺序。
+pub(crate) fn parse(header: &str, len: u64) -> Ranges {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Ignore,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return Ranges::Ignore;
+        }
+
+        let (first, last) = match spec.split_once('-') {
+            Some(pair) => pair,
+            None => return Ranges::Ignore,
+        };
+        let number = |s: &str| -> Option<u64> {
+            if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            s.parse().ok()
+        };
+
+        let range = if first.is_empty() {
+            // 最后 n 个字节
+            let n = match number(last) {
+                Some(n) => n,
+                None => return Ranges::Ignore,
+            };
+            if n == 0 || len == 0 {
+                continue;
+            }
+            ByteRange {
+                start: len.saturating_sub(n),
+                end: len - 1,
+            }
+        } else {
+            let start = match number(first) {
+                Some(start) => start,
+                None => return Ranges::Ignore,
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match number(last) {
+                    Some(end) if end >= start => end,
+                    _ => return Ranges::Ignore,
+                }
+            };
+            if start >= len {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(len - 1),
+            }
+        };
+        ranges.push(range);
+    }
+
+    if count == 0 {
+        return Ranges::Ignore;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    Ranges::Satisfiable(coalesce(ranges))
+}
+
+/// 有重叠或相邻的范围时排序并合并，避免同一段数据被发送多次
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    let mut sorted = ranges.clone();
+    sorted.sort_by_key(|range| range.start);
+    let touching = sorted
+        .windows(2)
+        .any(|pair| pair[1].start <= pair[0].end.saturating_add(1));
+    if !touching {
+        return ranges;
+    }
+
+    ranges.clear();
+    for range in sorted {
+        match ranges.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => ranges.push(range),
+        }
+    }
+    ranges
+}
+
+/// 生成一个不太可能出现在文件内容中的分隔符
+pub(crate) fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!(
+        "a20-{:016x}",
+        nanos ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+    )
+}
+
+/// `multipart/byteranges` 的响应体，边读文件边生成
+pub(crate) struct Multipart {
+    file: File,
+    segments: VecDeque<Segment>,
+}
+
+enum Segment {
+    /// 分隔符和每一段的头部
+    Bytes(Cursor<Vec<u8>>),
+    /// 文件中的一段，第一次读取时才 seek
+    File {
+        start: u64,
+        remaining: u64,
+        seeked: bool,
+    },
+}
+
+impl Multipart {
+    /// 返回响应体和它的总长度
+    pub(crate) fn new(
+        file: File,
+        ranges: &[ByteRange],
+        total: u64,
+        content_type: &str,
+        boundary: &str,
+    ) -> (Multipart, u64) {
+        let mut segments = VecDeque::new();
+        let mut len = 0;
+
+        for range in ranges {
+            let head = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(total)
+            );
+            len += head.len() as u64 + range.len();
+            segments.push_back(Segment::Bytes(Cursor::new(head.into_bytes())));
+            segments.push_back(Segment::File {
+                start: range.start,
+                remaining: range.len(),
+                seeked: false,
+            });
+        }
+
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        len += tail.len() as u64;
+        segments.push_back(Segment::Bytes(Cursor::new(tail.into_bytes())));
+
+        (Multipart { file, segments }, len)
+    }
+}
+
+impl Read for Multipart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Multipart { file, segments } = self;
+
+        while let Some(segment) = segments.front_mut() {
+            let n = match segment {
+                Segment::Bytes(cursor) => cursor.read(buf)?,
+                Segment::File {
+                    start,
+                    remaining,
+                    seeked,
+                } => {
+                    if *remaining == 0 {
+                        0
+                    } else {
+                        if !*seeked {
+                            file.seek(SeekFrom::Start(*start))?;
+                            *seeked = true;
+                        }
+                        let max = buf.len().min(*remaining as usize);
+                        let n = file.read(&mut buf[..max])?;
+                        if n == 0 && max > 0 {
+                            // 文件在发送过程中变短了
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "file shorter than range",
+                            ));
+                        }
+                        *remaining -= n as u64;
+                        n
+                    }
+                }
+            };
+
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            segments.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parses_byte_range_forms() {
+        assert_eq!(
+            parse("bytes=0-499", 1000),
+            Ranges::Satisfiable(vec![range(0, 499)])
+        );
+        assert_eq!(
+            parse("bytes=900-", 1000),
+            Ranges::Satisfiable(vec![range(900, 999)])
+        );
+        assert_eq!(
+            parse("bytes=-100", 1000),
+            Ranges::Satisfiable(vec![range(900, 999)])
+        );
+        // 超出文件的部分被截掉，后缀比文件长时从头开始
+        assert_eq!(
+            parse("bytes=500-5000", 1000),
+            Ranges::Satisfiable(vec![range(500, 999)])
+        );
+        assert_eq!(
+            parse("bytes=-5000", 1000),
+            Ranges::Satisfiable(vec![range(0, 999)])
+        );
+        // 不重叠的范围保持请求的顺序
+        assert_eq!(
+            parse("bytes=500-599, 0-99", 1000),
+            Ranges::Satisfiable(vec![range(500, 599), range(0, 99)])
+        );
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            parse("bytes=500-700,0-99,600-999,100-199", 1000),
+            Ranges::Satisfiable(vec![range(0, 199), range(500, 999)])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_and_ignored_ranges() {
+        assert_eq!(parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        // 能满足的保留，不能满足的丢掉
+        assert_eq!(
+            parse("bytes=2000-3000,0-0", 1000),
+            Ranges::Satisfiable(vec![range(0, 0)])
+        );
+
+        assert_eq!(parse("items=0-1", 1000), Ranges::Ignore);
+        assert_eq!(parse("bytes=5-1", 1000), Ranges::Ignore);
+        assert_eq!(parse("bytes=a-b", 1000), Ranges::Ignore);
+        assert_eq!(parse("bytes=", 1000), Ranges::Ignore);
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&many, 1000), Ranges::Ignore);
+    }
+
+    #[test]
+    fn multipart_body_matches_its_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("digits.txt");
+        fs::write(&path, "0123456789").unwrap();
+
+        let ranges = [range(0, 1), range(7, 9)];
+        let (mut body, len) =
+            Multipart::new(File::open(&path).unwrap(), &ranges, 10, "text/plain", "XYZ");
+        let mut out = String::new();
+        body.read_to_string(&mut out).unwrap();
+
+        assert_eq!(out.len() as u64, len);
+        assert_eq!(
+            out,
+            "\r\n--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n789\
+             \r\n--XYZ--\r\n"
+        );
+    }
+}
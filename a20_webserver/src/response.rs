@@ -0,0 +1,298 @@
+use crate::chunked::{ChunkedBody, ChunkedWriter, ReaderChunks};
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// 响应体
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// 写出时从 reader 中边读边写，长度需要事先知道
+    Reader(Box<dyn Read + Send>, u64),
+    /// 长度事先不知道，以 `Transfer-Encoding: chunked` 一块一块地写出
+    Chunked(Box<dyn ChunkedBody>),
+}
+
+impl Body {
+    /// 响应体的字节数，分块的响应体长度未知，返回 0
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Empty | Body::Chunked(_) => 0,
+            Body::Bytes(b) => b.len() as u64,
+            Body::Reader(_, len) => *len,
+        }
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        matches!(self, Body::Chunked(_))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// HTTP 响应
+///
+/// Content-Length 在写出时根据响应体自动生成，不需要手动设置；
+/// 分块的响应体改为生成 `Transfer-Encoding: chunked`。
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// 生成响应时遇到的内部错误，由服务器写进日志，不会发给客户端
+    pub(crate) error: Option<String>,
+}
+
+impl Response {
+    /// 创建一个没有响应体的响应
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            error: None,
+        }
+    }
+
+    /// 纯文本响应
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text.as_bytes().to_vec())
+    }
+
+    /// HTML 响应
+    pub fn html(status: u16, html: String) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(html.into_bytes())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Response {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    /// 以 reader 作为响应体，写出时再读取，避免把大文件整个读进内存
+    pub fn with_reader<R: Read + Send + 'static>(mut self, reader: R, len: u64) -> Response {
+        self.body = Body::Reader(Box::new(reader), len);
+        self
+    }
+
+    /// 以分块编码发送的响应体，适合边生成边发送、事先不知道长度的输出
+    ///
+    /// HTTP/1.0 的客户端不认识分块编码，这时数据原样写出，写完后关闭连接。
+    pub fn with_chunked<B: ChunkedBody + 'static>(mut self, body: B) -> Response {
+        self.body = Body::Chunked(Box::new(body));
+        self
+    }
+
+    /// 以分块编码发送 reader 中的全部数据，读到 EOF 为止
+    pub fn with_chunked_reader<R: Read + Send + 'static>(self, reader: R) -> Response {
+        self.with_chunked(ReaderChunks { reader })
+    }
+
+    /// 附上一条内部错误，例如 500 的原因，服务器会以 Error 级别写进日志
+    pub fn with_error<E: fmt::Display>(mut self, error: E) -> Response {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// 用 with_error 附上的内部错误
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// 设置响应头，已存在的同名响应头会被替换
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// 按名称（不区分大小写）取响应头
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 状态行和响应头，以空行结尾
+    ///
+    /// chunked 为 false 时分块的响应体不声明长度，由关闭连接表示结束。
+    pub(crate) fn head(&self, chunked: bool) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // 204 和 304 没有响应体，不发送 Content-Length
+        if self.body.is_chunked() {
+            if chunked {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+        } else if self.status != 204 && self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    /// 把响应写到 writer 中，返回写出的响应体字节数
+    ///
+    /// `head_only` 为 true 时（HEAD 请求）只写状态行和响应头。
+    pub fn write_to<W: Write>(self, writer: &mut W, head_only: bool) -> io::Result<u64> {
+        self.write_with(writer, head_only, true)
+    }
+
+    /// 和 write_to 一样，chunked 为 false 时（HTTP/1.0 的客户端）分块的响应体原样写出
+    pub(crate) fn write_with<W: Write>(
+        self,
+        writer: &mut W,
+        head_only: bool,
+        chunked: bool,
+    ) -> io::Result<u64> {
+        writer.write_all(self.head(chunked).as_bytes())?;
+
+        let written = match self.body {
+            _ if head_only => 0,
+            Body::Empty => 0,
+            Body::Bytes(bytes) => {
+                writer.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Body::Reader(reader, len) => {
+                let n = io::copy(&mut reader.take(len), writer)?;
+                // 文件在读取过程中变短了，已经发出的 Content-Length 对不上
+                if n < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body shorter than content-length",
+                    ));
+                }
+                n
+            }
+            Body::Chunked(mut body) if chunked => {
+                let mut out = ChunkedWriter::new(&mut *writer);
+                let n = copy_chunks(body.as_mut(), &mut out)?;
+                out.finish(&body.trailers())?;
+                n
+            }
+            Body::Chunked(mut body) => copy_chunks(body.as_mut(), writer)?,
+        };
+
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+/// 把分块响应体的每一块写到 writer 并立即 flush，返回数据的字节数
+fn copy_chunks<W: Write>(body: &mut dyn ChunkedBody, writer: &mut W) -> io::Result<u64> {
+    let mut buf = Vec::new();
+    let mut written = 0;
+    loop {
+        buf.clear();
+        let more = body.next_chunk(&mut buf)?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        written += buf.len() as u64;
+        if !more {
+            return Ok(written);
+        }
+    }
+}
+
+/// 状态码对应的原因短语
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_status_headers_and_body() {
+        let mut out = Vec::new();
+        let n = Response::text(404, "nope")
+            .write_to(&mut out, false)
+            .unwrap();
+
+        assert_eq!(n, 4);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\nnope"
+        );
+    }
+
+    #[test]
+    fn head_only_keeps_content_length() {
+        let mut out = Vec::new();
+        let n = Response::text(200, "hello")
+            .write_to(&mut out, true)
+            .unwrap();
+
+        assert_eq!(n, 0);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn writes_chunked_bodies_with_trailers() {
+        let mut parts = vec!["ab", "", "cde"].into_iter();
+        let response = Response::new(200).with_chunked(move |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(parts.next().unwrap().as_bytes());
+            Ok(parts.len() > 0)
+        });
+        let mut out = Vec::new();
+        assert_eq!(response.write_to(&mut out, false).unwrap(), 5);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n"
+        );
+
+        // HTTP/1.0 的客户端收到原样的数据
+        let response = Response::new(200).with_chunked_reader(&b"raw data"[..]);
+        let mut out = Vec::new();
+        response.write_with(&mut out, false, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\n\r\nraw data"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use crate::http::{percent_decode, Method, Request};
+use crate::response::Response;
+
+use std::collections::HashMap;
+
+/// 处理请求、生成响应的处理器
+///
+/// 任何 `Fn(&mut Request) -> Response` 的闭包都自动实现了 Handler。
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &mut Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &mut Request) -> Response {
+        self(request)
+    }
+}
+
+/// 路径模式中的一段
+enum Segment {
+    /// 必须完全相同，例如 `posts`
+    Static(String),
+    /// 匹配任意一段，例如 `:id`
+    Param(String),
+    /// 匹配剩下的所有段，例如 `*path`，只能出现在最后
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+}
+
+/// 路径的结构匹配，但参数中有无法解码的 `%` 转义，例如 `%zz`
+struct BadEscape;
+
+impl Route {
+    /// 用路径匹配模式，成功时返回捕获到的参数
+    ///
+    /// 先按结构匹配，再解码参数，这样只有确实匹配的路由才会报告转义错误。
+    fn matches(&self, path: &str) -> Option<Result<HashMap<String, String>, BadEscape>> {
+        let mut parts = path.split('/').filter(|s| !s.is_empty());
+        let mut raw = Vec::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Static(s) => {
+                    if parts.next()? != s {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => raw.push((name, parts.next()?.to_string())),
+                Segment::Wildcard(name) => {
+                    raw.push((name, parts.by_ref().collect::<Vec<_>>().join("/")));
+                }
+            }
+        }
+
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(
+            raw.into_iter()
+                .map(|(name, value)| Ok((name.clone(), percent_decode(&value).ok_or(BadEscape)?)))
+                .collect(),
+        )
+    }
+}
+
+/// 路由器
+///
+/// 按方法和路径模式注册处理器，模式支持 `:name` 参数和结尾的 `*name` 通配。
+/// 多个模式都能匹配时，先注册的优先。
+///
+/// 路径匹配但方法不匹配时返回 405，并在 Allow 响应头中列出可用的方法；
+/// 注册了 GET 的路径也会自动响应 HEAD。参数中有错误的 `%` 转义时返回 400。
+///
+/// ```
+/// use a20_webserver::http::Request;
+/// use a20_webserver::response::Response;
+/// use a20_webserver::router::Router;
+///
+/// let router = Router::new().get("/posts/:id", |req: &mut Request| {
+///     let id = req.param("id").unwrap_or_default().to_string();
+///     Response::text(200, &id)
+/// });
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_: &mut Request| Response::text(404, "404 Not Found")),
+        }
+    }
+
+    /// 注册处理器
+    ///
+    /// # Panics
+    ///
+    /// 通配段不在模式的最后时会 panic。
+    pub fn route<H: Handler>(mut self, method: Method, pattern: &str, handler: H) -> Router {
+        let segments: Vec<Segment> = pattern
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Static(s.to_string())
+                }
+            })
+            .collect();
+
+        let wildcard = segments
+            .iter()
+            .position(|s| matches!(s, Segment::Wildcard(_)));
+        if let Some(i) = wildcard {
+            assert!(
+                i == segments.len() - 1,
+                "wildcard must be the last segment: {}",
+                pattern
+            );
+        }
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// 没有任何模式匹配时使用的处理器，默认返回纯文本的 404
+    pub fn not_found<H: Handler>(mut self, handler: H) -> Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &mut Request) -> Response {
+        let mut allowed: Vec<&Method> = Vec::new();
+
+        for route in &self.routes {
+            let params = match route.matches(&request.path) {
+                Some(Ok(params)) => params,
+                Some(Err(BadEscape)) => return Response::text(400, "400 Bad Request"),
+                None => continue,
+            };
+
+            let head_as_get = request.method == Method::Head && route.method == Method::Get;
+            if route.method == request.method || head_as_get {
+                request.params = params;
+                return route.handler.handle(request);
+            }
+
+            if !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+        }
+
+        if allowed.is_empty() {
+            return self.not_found.handle(request);
+        }
+
+        if allowed.contains(&&Method::Get) && !allowed.contains(&&Method::Head) {
+            allowed.push(&Method::Head);
+        }
+        let allow: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+
+        Response::text(405, "405 Method Not Allowed").with_header("Allow", &allow.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |req: &mut Request| Response::text(200, req.param(name).unwrap_or("-"))
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out[out.find("\r\n\r\n").unwrap() + 4..].to_string()
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let router = Router::new()
+            .get("/posts/:id", echo("id"))
+            .get("/static/*path", echo("path"));
+
+        assert_eq!(body(router.handle(&mut request("GET", "/posts/42"))), "42");
+        assert_eq!(
+            body(router.handle(&mut request("GET", "/posts/a%20b"))),
+            "a b"
+        );
+        assert_eq!(
+            body(router.handle(&mut request("GET", "/static/css/site.css"))),
+            "css/site.css"
+        );
+        assert_eq!(
+            router
+                .handle(&mut request("GET", "/posts/42/comments"))
+                .status,
+            404
+        );
+        assert_eq!(router.handle(&mut request("GET", "/posts")).status, 404);
+    }
+
+    #[test]
+    fn bad_escapes_in_params_are_400() {
+        let router = Router::new()
+            .get("/users/:id", echo("id"))
+            .get("/users/*rest", echo("rest"))
+            .get("/files/*path", echo("path"));
+
+        // 不会因为解码失败而落到后面的路由或 404
+        assert_eq!(router.handle(&mut request("GET", "/users/%zz")).status, 400);
+        assert_eq!(
+            router.handle(&mut request("GET", "/files/a/%2")).status,
+            400
+        );
+        // 结构不匹配的路由不解码
+        assert_eq!(router.handle(&mut request("GET", "/other/%zz")).status, 404);
+    }
+
+    #[test]
+    fn first_registered_route_wins() {
+        let router = Router::new()
+            .get("/posts/new", |_: &mut Request| Response::text(200, "new"))
+            .get("/posts/:id", echo("id"));
+
+        assert_eq!(
+            body(router.handle(&mut request("GET", "/posts/new"))),
+            "new"
+        );
+        assert_eq!(body(router.handle(&mut request("GET", "/posts/7"))), "7");
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allowed_methods() {
+        let router = Router::new()
+            .get("/posts/:id", echo("id"))
+            .delete("/posts/:id", echo("id"));
+
+        let response = router.handle(&mut request("POST", "/posts/1"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("allow"), Some("GET, DELETE, HEAD"));
+
+        assert_eq!(router.handle(&mut request("HEAD", "/posts/1")).status, 200);
+    }
+}
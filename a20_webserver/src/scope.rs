@@ -0,0 +1,249 @@
+use crate::{lock, ExecuteError, ThreadPool};
+
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+/// `ThreadPool::scope` 中提交任务用的作用域
+///
+/// 通过它提交的任务可以借用 scope 外面的局部变量，不需要是 `'static`。
+pub struct Scope<'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    /// 让 'scope 不变（invariant），防止被缩短成比 scope 调用更短的生命周期
+    _marker: PhantomData<&'scope mut &'scope ()>,
+}
+
+/// scope 和它的任务之间共享的状态
+struct ScopeState {
+    /// 还没结束的任务数，任务执行完或者没执行就被丢弃都算结束
+    pending: Mutex<usize>,
+    done: Condvar,
+    /// 第一个 panic 的任务的 payload
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl<'scope> Scope<'scope> {
+    /// 提交一个可以借用 scope 外部数据的任务
+    ///
+    /// 和 `ThreadPool::execute` 一样受队列容量和溢出策略的限制。
+    /// 任务 panic 时 panic 会在 scope 返回时传给调用方。
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.state.pending) += 1;
+
+        let job = ScopedJob {
+            f: Some(f),
+            state: Arc::clone(&self.state),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.run());
+
+        // 任务借用的数据只在 'scope 内有效。scope 返回之前会等所有任务执行完或者被丢弃，
+        // 所以线程池不会在数据失效之后再碰这个任务，可以当作 'static 交给线程池。
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+
+        // 提交失败时任务被丢弃，pending 随之减少
+        self.pool.execute(job)
+    }
+}
+
+/// 包装 scope 中的任务，任务结束或被丢弃时减少 pending
+struct ScopedJob<F> {
+    f: Option<F>,
+    state: Arc<ScopeState>,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(mut self) {
+        if let Some(f) = self.f.take() {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                // 第一个 payload 留给 scope 的调用方，之后照常 panic，
+                // 由线程池像其他任务一样统计并记录日志
+                let payload = {
+                    let mut first = lock(&self.state.panic);
+                    match *first {
+                        None => {
+                            *first = Some(payload);
+                            Box::new("scoped job panicked")
+                        }
+                        Some(_) => payload,
+                    }
+                };
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl<F> Drop for ScopedJob<F> {
+    fn drop(&mut self) {
+        // 没执行的任务要先释放闭包，它借用的数据在 pending 归零之后就可能失效
+        drop(self.f.take());
+
+        let mut pending = lock(&self.state.pending);
+        *pending -= 1;
+        if *pending == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+impl ThreadPool {
+    /// 创建一个作用域，在里面提交的任务可以借用当前栈上的数据
+    ///
+    /// 返回之前会等待作用域中所有的任务结束。任何一个任务 panic 时，
+    /// 等所有任务结束后在调用方重新抛出这个 panic。
+    ///
+    /// 不要在线程池自己的 worker 中调用：worker 全部在等待时作用域里的任务没有线程执行。
+    ///
+    /// ```
+    /// use a20_webserver::ThreadPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let numbers = vec![1, 2, 3, 4];
+    /// let sum = AtomicUsize::new(0);
+    ///
+    /// pool.scope(|s| {
+    ///     for n in &numbers {
+    ///         let sum = &sum;
+    ///         s.execute(move || {
+    ///             sum.fetch_add(*n, Ordering::SeqCst);
+    ///         })
+    ///         .unwrap();
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(sum.into_inner(), 10);
+    /// ```
+    pub fn scope<'scope, F, R>(&'scope self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            _marker: PhantomData,
+        };
+
+        // f 本身 panic 时也要先等已经提交的任务结束，它们可能借用了 f 外面的数据
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+
+        let value = match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        if let Some(payload) = lock(&scope.state.panic).take() {
+            panic::resume_unwind(payload);
+        }
+        value
+    }
+}
+
+impl ScopeState {
+    /// 等待所有任务结束
+    fn wait(&self) {
+        let mut pending = lock(&self.pending);
+        while *pending > 0 {
+            pending = self
+                .done
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OverflowPolicy;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn jobs_can_borrow_and_mutate_stack_data() {
+        let pool = ThreadPool::new(4);
+        let mut chunks = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+
+        pool.scope(|s| {
+            for chunk in chunks.iter_mut() {
+                s.execute(move || {
+                    // 让任务慢一点，确认 scope 确实在等
+                    thread::sleep(Duration::from_millis(20));
+                    chunk.iter_mut().for_each(|n| *n *= 10);
+                })
+                .unwrap();
+            }
+        });
+
+        assert_eq!(chunks, vec![vec![10, 20], vec![30, 40], vec![50, 60]]);
+    }
+
+    #[test]
+    fn job_panics_reach_the_caller_after_all_jobs_finish() {
+        let pool = ThreadPool::new(2);
+        let finished = Mutex::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.execute(|| panic!("scoped boom")).unwrap();
+                s.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    *lock(&finished) += 1;
+                })
+                .unwrap();
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"scoped boom"));
+        assert_eq!(*lock(&finished), 1);
+
+        // 线程池在任务 unwind 结束之后才计数，可能比 scope 返回稍晚一点
+        let counts = || {
+            let stats = pool.stats();
+            (stats.panicked, stats.completed)
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while counts() != (1, 1) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // panic 的任务不会同时算作完成
+        assert_eq!(counts(), (1, 1));
+    }
+
+    #[test]
+    fn dropped_jobs_still_release_the_scope() {
+        let pool = ThreadPool::bounded(1, 1, OverflowPolicy::DropOldest).unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, ready) = mpsc::channel();
+        let ran = Mutex::new(Vec::new());
+
+        pool.scope(|s| {
+            s.execute(move || {
+                started.send(()).unwrap();
+                wait.recv().unwrap();
+            })
+            .unwrap();
+            ready.recv().unwrap();
+
+            // 第一个被第二个挤掉，scope 不会因为它一直等下去
+            s.execute(|| lock(&ran).push(1)).unwrap();
+            s.execute(|| lock(&ran).push(2)).unwrap();
+            release.send(()).unwrap();
+        });
+
+        assert_eq!(*lock(&ran), vec![2]);
+        assert_eq!(pool.dropped_count(), 1);
+    }
+}
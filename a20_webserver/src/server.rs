@@ -0,0 +1,1517 @@
+use crate::access_log::{self, AccessLog};
+use crate::compress::Compression;
+use crate::config::ServerConfig;
+use crate::event_loop;
+use crate::extract::BodyLimits;
+use crate::http::{Limits, Method, ParseError, Request, Version};
+use crate::logger::{self, Logger};
+use crate::middleware::{Middleware, Stack};
+use crate::response::{self, Response};
+use crate::router::Handler;
+use crate::{
+    lock, CancellationToken, ExecuteError, LogFormat, LogLevel, OverflowPolicy, PoolCreationError,
+    ThreadPool, ThreadPoolBuilder,
+};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use std::{mem, thread};
+
+/// 持久连接的设置
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    /// 两个请求之间最多等待多久，超时后关闭连接
+    pub timeout: Duration,
+    /// 一个连接上最多处理多少个请求
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// 防止慢速客户端长期占住 worker 的超时设置
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// 读取请求时，每次等待数据的最长时间
+    pub read: Duration,
+    /// 写出响应时，每次等待客户端接收数据的最长时间
+    pub write: Duration,
+    /// 读完请求行和请求头的总期限
+    ///
+    /// 第一个请求从连接建立时开始计时，之后的请求从第一个字节到达时开始计时。
+    pub header: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+            header: Duration::from_secs(10),
+        }
+    }
+}
+
+/// 连接的处理方式，两种方式使用同一个 `Handler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// 每个连接在整个生命周期内占用线程池中的一个 worker，由阻塞读写处理
+    #[default]
+    Threads,
+    /// 一个 I/O 线程通过 epoll 管理所有连接，只有处理请求时才占用 worker
+    EventLoop,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Threads => "threads",
+            Mode::EventLoop => "event-loop",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 处理一个 tcp 连接，返回处理的请求数
+///
+/// 同一个连接上的请求按顺序逐个读取、处理、响应，直到出现以下情况之一：
+/// 客户端或 handler 要求 `Connection: close`、空闲超过 `timeout`、
+/// 达到 `max_requests`、请求格式有误或连接出错。
+/// 读写超时和请求头的期限使用 `Timeouts` 的默认值，请求头的上限使用 `Limits` 的默认值。
+/// 客户端一次发来的多个请求（pipelining）会留在缓冲区里，按顺序得到响应。
+pub fn handle_connection(
+    stream: TcpStream,
+    handler: &dyn Handler,
+    keep_alive: &KeepAlive,
+) -> usize {
+    let options = Options {
+        keep_alive: keep_alive.clone(),
+        timeouts: Timeouts::default(),
+        limits: Limits::default(),
+        body_limits: BodyLimits::default(),
+        log: logger::stdout_logger(LogFormat::Text),
+        access_log: None,
+    };
+    serve_connection(stream, handler, &options, &Tracker::default())
+}
+
+/// 每个连接都要用到的设置
+#[derive(Clone)]
+pub(crate) struct Options {
+    pub(crate) keep_alive: KeepAlive,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) limits: Limits,
+    pub(crate) body_limits: BodyLimits,
+    pub(crate) log: Logger,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Options {
+    /// 决定处理完这个请求后是否保持连接，并设置响应的 Connection 和 Keep-Alive 头
+    ///
+    /// served 是这个连接上已经处理的请求数（含当前这个）。
+    pub(crate) fn keep_connection(
+        &self,
+        request: &Request,
+        response: &mut Response,
+        served: usize,
+        tracker: &Tracker,
+    ) -> bool {
+        let keep_alive = &self.keep_alive;
+        let handler_closes = response
+            .header("connection")
+            .map(|v| v.eq_ignore_ascii_case("close"))
+            .unwrap_or(false);
+        // HTTP/1.0 的客户端不认识分块编码，只能以关闭连接表示响应体结束
+        let close_delimited = response.body.is_chunked() && request.version == Version::Http10;
+        // 服务器正在关闭时，处理完当前请求就断开
+        let keep = request.keep_alive()
+            && !handler_closes
+            && !close_delimited
+            && served < keep_alive.max_requests
+            && !tracker.is_shutting_down();
+
+        if keep {
+            // HTTP/1.0 的客户端需要明确告知连接会被保持
+            response.set_header("Connection", "keep-alive");
+            // timeout 只能写整秒，向下取整，不足一秒时不写，免得客户端以为连接不能复用
+            let max = format!("max={}", keep_alive.max_requests - served);
+            let value = match keep_alive.timeout.as_secs() {
+                0 => max,
+                secs => format!("timeout={}, {}", secs, max),
+            };
+            response.set_header("Keep-Alive", &value);
+        } else {
+            response.set_header("Connection", "close");
+        }
+        keep
+    }
+
+    /// handler 在响应上附带了内部错误时写进日志
+    pub(crate) fn log_error(&self, request: &Request, response: &Response) {
+        if let Some(error) = response.error() {
+            (self.log)(
+                LogLevel::Error,
+                format_args!("{} {}: {}", request.method, request.path, error),
+            );
+        }
+    }
+
+    /// 记录一条访问日志，没有配置访问日志时什么也不做
+    ///
+    /// received 是收到请求时的墙上时间和单调时间，bytes 是写出的响应体字节数。
+    pub(crate) fn log_access(
+        &self,
+        client: Option<IpAddr>,
+        received: (SystemTime, Instant),
+        request: &Request,
+        status: u16,
+        bytes: u64,
+    ) {
+        if let Some(log) = &self.access_log {
+            log.log(access_log::Entry {
+                client,
+                user: request.user.clone(),
+                time: received.0,
+                method: request.method.to_string(),
+                target: match &request.query {
+                    Some(query) => format!("{}?{}", request.path, query),
+                    None => request.path.clone(),
+                },
+                version: request.version.as_str(),
+                status,
+                bytes,
+                referer: request.header("referer").map(String::from),
+                user_agent: request.header("user-agent").map(String::from),
+                duration: received.1.elapsed(),
+            });
+        }
+    }
+}
+
+/// 无法解析请求时回应给客户端的响应，之后连接会被关闭
+pub(crate) fn error_response(e: &ParseError) -> Response {
+    let status = e.status();
+    let text = format!("{} {}", status, response::reason_phrase(status));
+    Response::text(status, &text).with_header("Connection", "close")
+}
+
+/// handle_connection 的实现，tracker 用来配合 Server 的关闭流程
+fn serve_connection(
+    stream: TcpStream,
+    handler: &dyn Handler,
+    options: &Options,
+    tracker: &Tracker,
+) -> usize {
+    // 登记连接，关闭时由 Server 强制断开；登记之后才检查关闭标志，保证不会漏掉
+    let id = match tracker.register(&stream) {
+        Some(id) => id,
+        None => return 0,
+    };
+    let served = serve_requests(&stream, handler, options, tracker);
+    tracker.unregister(id);
+    served
+}
+
+fn serve_requests(
+    stream: &TcpStream,
+    handler: &dyn Handler,
+    options: &Options,
+    tracker: &Tracker,
+) -> usize {
+    let keep_alive = &options.keep_alive;
+    let timeouts = &options.timeouts;
+    // 写超时防止不接收数据的客户端让 write 一直阻塞
+    if let Err(e) = stream.set_write_timeout(Some(timeouts.write)) {
+        (options.log)(
+            LogLevel::Warn,
+            format_args!("Failed to set write timeout: {}", e),
+        );
+        return 0;
+    }
+
+    let mut reader = BufReader::new(TimedReader {
+        stream,
+        timeout: timeouts.read,
+        deadline: None,
+    });
+    let mut writer = BufWriter::new(stream);
+    let mut served = 0;
+    let client = stream.peer_addr().ok().map(|addr| addr.ip());
+    // 第一个请求的请求头期限从连接建立时开始算
+    let mut started = Instant::now();
+
+    loop {
+        let mut request = match read_request(&mut reader, started + timeouts.header, options) {
+            Ok(request) => request,
+            Err(e) => {
+                // 请求有误、太大或超时时返回对应的 4xx，连接已断开则直接关闭
+                if e.is_bad_request() {
+                    let _ = error_response(&e).write_to(&mut writer, false);
+                }
+                return served;
+            }
+        };
+        served += 1;
+        tracker.begin_request();
+        let received = (SystemTime::now(), Instant::now());
+
+        let head_only = request.method == Method::Head;
+        let mut response = handler.handle(&mut request);
+        options.log_error(&request, &response);
+        let keep = options.keep_connection(&request, &mut response, served, tracker);
+
+        // 返回数据
+        let status = response.status;
+        let chunked = request.version == Version::Http11;
+        let result = response.write_with(&mut writer, head_only, chunked);
+        tracker.end_request(result.is_ok());
+
+        options.log_access(
+            client,
+            received,
+            &request,
+            status,
+            *result.as_ref().unwrap_or(&0),
+        );
+        if let Err(e) = result {
+            (options.log)(
+                LogLevel::Warn,
+                format_args!("Failed to write response: {}", e),
+            );
+            return served;
+        }
+
+        if !keep {
+            return served;
+        }
+
+        // 空闲等待下一个请求，超时或连接关闭时不必回应
+        reader.get_mut().timeout = keep_alive.timeout;
+        let next = reader.fill_buf().map(|buf| !buf.is_empty());
+        reader.get_mut().timeout = timeouts.read;
+        if !next.unwrap_or(false) {
+            return served;
+        }
+        started = Instant::now();
+    }
+}
+
+/// 读取一个请求，请求行和请求头必须在 deadline 之前读完
+fn read_request(
+    reader: &mut BufReader<TimedReader>,
+    deadline: Instant,
+    options: &Options,
+) -> Result<Request, ParseError> {
+    reader.get_mut().deadline = Some(deadline);
+    let head = Request::read_head(reader, &options.limits);
+    reader.get_mut().deadline = None;
+
+    let mut request = head?;
+    request.body_limits = options.body_limits;
+    request.read_body_streaming(reader, &options.limits)?;
+    Ok(request)
+}
+
+/// 读取时按期限缩短 socket 的读超时，到期后返回 `TimedOut`
+struct TimedReader<'a> {
+    stream: &'a TcpStream,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeout = self.timeout;
+        if let Some(deadline) = self.deadline {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => timeout = timeout.min(left),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request head deadline exceeded",
+                    ))
+                }
+            }
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// 每个客户端 IP 当前的连接数
+#[derive(Default)]
+pub(crate) struct PerIp {
+    /// 0 表示不限制
+    max: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PerIp {
+    /// 占用一个名额，这个 IP 的连接数已经达到上限时返回 None
+    pub(crate) fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let mut counts = lock(&self.counts);
+        let count = counts.entry(ip).or_insert(0);
+        if self.max > 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            per_ip: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// 一个连接占用的名额，连接处理完或被丢弃时 drop 归还
+pub(crate) struct IpSlot {
+    per_ip: Arc<PerIp>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = lock(&self.per_ip.counts);
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// 连接和请求的登记簿，用于优雅关闭
+#[derive(Default)]
+pub(crate) struct Tracker {
+    shutting_down: AtomicBool,
+    next_id: AtomicUsize,
+    /// 每个连接的一个克隆，用于在关闭时强制断开
+    connections: Mutex<HashMap<usize, TcpStream>>,
+    /// 正在处理中的请求数
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+    /// 开始关闭之后完成的请求数
+    drained: AtomicUsize,
+    /// 开始关闭之后被放弃的请求或连接数
+    aborted: AtomicUsize,
+}
+
+impl Tracker {
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// 登记连接，已经在关闭时返回 None，这个连接会被直接放弃
+    fn register(&self, stream: &TcpStream) -> Option<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        if let Ok(clone) = stream.try_clone() {
+            lock(&self.connections).insert(id, clone);
+        }
+
+        if self.is_shutting_down() {
+            self.unregister(id);
+            self.aborted.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(id)
+    }
+
+    fn unregister(&self, id: usize) {
+        lock(&self.connections).remove(&id);
+    }
+
+    pub(crate) fn begin_request(&self) {
+        *lock(&self.in_flight) += 1;
+    }
+
+    pub(crate) fn end_request(&self, completed: bool) {
+        let mut in_flight = lock(&self.in_flight);
+        *in_flight -= 1;
+
+        if self.is_shutting_down() {
+            let counter = if completed {
+                &self.drained
+            } else {
+                &self.aborted
+            };
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        if *in_flight == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    /// 开始关闭：关掉所有连接的读端
+    ///
+    /// 空闲的连接会立即读到 EOF 并退出，正在处理的请求仍然可以写出响应。
+    /// 返回 false 表示之前已经开始关闭。
+    fn begin_shutdown(&self) -> bool {
+        if self.shutting_down.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        for stream in lock(&self.connections).values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        true
+    }
+
+    /// 等待正在处理的请求全部完成，超时返回 false
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = lock(&self.in_flight);
+
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            in_flight = self
+                .idle
+                .wait_timeout(in_flight, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    /// 强制断开所有剩余的连接，还没写完的响应会失败
+    fn abort_all(&self) {
+        for stream in lock(&self.connections).values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// 关闭的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// 开始关闭时正在处理、并在期限内完成的请求数
+    pub drained: usize,
+    /// 超过期限被强制断开的请求，以及已接受但还没开始处理的连接数
+    pub aborted: usize,
+}
+
+/// 用于从其他线程关闭 Server 的句柄，可以随意克隆
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tracker: Arc<Tracker>,
+    addrs: Vec<SocketAddr>,
+    /// 创建句柄时服务器使用的日志
+    log: Logger,
+}
+
+impl ShutdownHandle {
+    /// 通知 Server 停止接受新连接并开始关闭，不会等待关闭完成
+    pub fn shutdown(&self) {
+        if !self.tracker.begin_shutdown() {
+            return;
+        }
+
+        // accept 是阻塞的，连一下每个监听的地址把它们唤醒
+        for &addr in &self.addrs {
+            let mut addr = addr;
+            if addr.ip().is_unspecified() {
+                match addr {
+                    SocketAddr::V4(_) => addr.set_ip([127, 0, 0, 1].into()),
+                    SocketAddr::V6(_) => addr.set_ip(std::net::Ipv6Addr::LOCALHOST.into()),
+                }
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.tracker.is_shutting_down()
+    }
+}
+
+/// 基于线程池的 HTTP 服务器
+///
+/// 可以同时监听多个地址。默认的 `Mode::Threads` 下每个地址由一个线程接受连接，
+/// 每个连接交给线程池中的一个 worker 处理；`Mode::EventLoop` 下由一个 I/O 线程
+/// 管理所有地址和连接，只把读完的请求交给 worker。调用 `ShutdownHandle::shutdown` 之后，
+/// 服务器停止接受新连接，等待正在处理的请求在 `shutdown_timeout` 内完成，
+/// 超过期限时断开剩余连接，取消 `cancellation_token`，丢弃还没开始处理的连接，
+/// 然后 drop 线程池，由线程池的 Drop 等待所有 worker 退出。
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    handler: Arc<dyn Handler>,
+    mode: Mode,
+    pool: ThreadPoolBuilder,
+    keep_alive: KeepAlive,
+    timeouts: Timeouts,
+    limits: Limits,
+    body_limits: BodyLimits,
+    max_connections_per_ip: usize,
+    compression: Option<Compression>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    access_log: Option<Arc<AccessLog>>,
+    shutdown_timeout: Duration,
+    tracker: Arc<Tracker>,
+    token: CancellationToken,
+}
+
+impl Server {
+    /// 绑定地址，默认 8 个 worker、最多 128 个排队的连接、请求体最大 1MB、关闭期限 30 秒
+    pub fn bind<A: ToSocketAddrs, H: Handler>(addr: A, handler: H) -> io::Result<Server> {
+        Ok(Server::with_listeners(
+            vec![TcpListener::bind(addr)?],
+            handler,
+        ))
+    }
+
+    /// 同时绑定多个地址，任何一个失败都返回错误
+    pub fn bind_all<H: Handler>(addrs: &[SocketAddr], handler: H) -> io::Result<Server> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+        let listeners = addrs
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Server::with_listeners(listeners, handler))
+    }
+
+    /// 按配置绑定地址并设置 worker 数、超时、请求的各项上限、压缩、日志格式和访问日志
+    ///
+    /// 文档根目录不归 Server 管，由调用方用来创建 handler。
+    pub fn from_config<H: Handler>(config: &ServerConfig, handler: H) -> io::Result<Server> {
+        let keep_alive = KeepAlive {
+            timeout: config.keep_alive_timeout,
+            ..KeepAlive::default()
+        };
+        let mut server = Server::bind_all(&config.listen, handler)?
+            .mode(config.mode)
+            .workers(config.workers)
+            .keep_alive(keep_alive)
+            .shutdown_timeout(config.shutdown_timeout)
+            .timeouts(Timeouts {
+                read: config.read_timeout,
+                write: config.write_timeout,
+                header: config.header_timeout,
+            })
+            .limits(Limits {
+                max_headers: config.max_headers,
+                max_header_size: config.max_header_size,
+                max_body_size: config.max_request_size,
+            })
+            .max_connections_per_ip(config.max_connections_per_ip)
+            .log_format(config.log_format);
+
+        if config.compression {
+            server = server.compression(Compression::new().min_size(config.compression_min_size));
+        }
+        if let Some(target) = &config.access_log {
+            let log = AccessLog::new(
+                target.clone(),
+                config.access_log_format,
+                config.access_log_rotation,
+            )?;
+            server = server.access_log(log);
+        }
+        Ok(server)
+    }
+
+    fn with_listeners<H: Handler>(listeners: Vec<TcpListener>, handler: H) -> Server {
+        Server {
+            listeners,
+            handler: Arc::new(handler),
+            mode: Mode::Threads,
+            pool: ThreadPool::builder()
+                .threads(8)
+                .name("http-worker")
+                .queue_capacity(128),
+            keep_alive: KeepAlive::default(),
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            body_limits: BodyLimits::default(),
+            max_connections_per_ip: 0,
+            compression: None,
+            middlewares: Vec::new(),
+            access_log: None,
+            shutdown_timeout: Duration::from_secs(30),
+            tracker: Arc::new(Tracker::default()),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// 连接的处理方式，默认为 `Mode::Threads`
+    pub fn mode(mut self, mode: Mode) -> Server {
+        self.mode = mode;
+        self
+    }
+
+    /// 固定的 worker 数
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.pool = self.pool.threads(workers);
+        self
+    }
+
+    /// 等待 worker 的连接最多有多少个，超出的连接直接得到 503
+    pub fn queue_capacity(mut self, capacity: usize) -> Server {
+        self.pool = self.pool.queue_capacity(capacity);
+        self
+    }
+
+    /// 完整地设置线程池，例如让 worker 数在一个范围内伸缩
+    ///
+    /// 溢出策略总是 `OverflowPolicy::Reject`，这样队列满时才能回应 503。
+    /// 线程池的 logger 同时也是服务器的日志。
+    pub fn pool(mut self, pool: ThreadPoolBuilder) -> Server {
+        self.pool = pool;
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Server {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// 请求体的最大字节数，Content-Length 超过它的请求得到 413
+    ///
+    /// 不包括边读边解析的 multipart 请求体，它们由 `body_limits` 限制。
+    pub fn max_request_size(mut self, bytes: usize) -> Server {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    /// 读写超时和请求头的期限，超过期限的请求得到 408
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Server {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// 请求头个数、请求头大小和请求体大小的上限，超过时得到 431 或 413
+    ///
+    /// 会替换掉 `max_request_size` 设置的值。
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    /// 表单、JSON 和 multipart 请求体的上限，也是 `Request::extract` 使用的上限
+    ///
+    /// multipart 请求体在读取时就边读边解析，文件直接写进临时文件，
+    /// 只受这里的 `max_multipart_size` 限制，不受 `max_request_size` 限制。
+    pub fn body_limits(mut self, limits: BodyLimits) -> Server {
+        self.body_limits = limits;
+        self
+    }
+
+    /// 同一个 IP 最多同时保持多少个连接，超出的连接直接得到 429，0 表示不限制
+    ///
+    /// 防止少数慢速客户端占满所有 worker。
+    pub fn max_connections_per_ip(mut self, max: usize) -> Server {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    /// 按请求的 `Accept-Encoding` 压缩响应，默认不压缩
+    pub fn compression(mut self, compression: Compression) -> Server {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// 在所有请求外面套一层中间件
+    ///
+    /// 先加入的在外层，压缩总是在所有中间件之外，处理的是最终的响应。
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Server {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// 记录每个请求的访问日志，默认不记录
+    pub fn access_log(mut self, log: AccessLog) -> Server {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// 服务器和线程池的日志格式，会替换掉 `pool` 中设置的 logger
+    pub fn log_format(mut self, format: LogFormat) -> Server {
+        self.pool = self.pool.log_format(format);
+        self
+    }
+
+    /// 关闭时等待正在处理的请求完成的最长时间
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// 第一个监听的地址
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle {
+            tracker: Arc::clone(&self.tracker),
+            addrs: self.local_addrs()?,
+            log: Arc::clone(&self.pool.logger),
+        })
+    }
+
+    /// 关闭超时时取消的信号
+    ///
+    /// 耗时长的 handler 应该持有它的克隆并用它等待，而不是直接 sleep，
+    /// 否则关闭要等到它们自己结束。
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Server {
+        self.token = token;
+        self
+    }
+
+    /// 接受并处理连接，直到被关闭；无法创建线程池时返回错误
+    pub fn run(mut self) -> Result<ShutdownReport, PoolCreationError> {
+        let pool = self
+            .pool
+            .clone()
+            .overflow_policy(OverflowPolicy::Reject)
+            .cancellation_token(self.token.clone())
+            .build()?;
+        let options = Options {
+            keep_alive: self.keep_alive.clone(),
+            timeouts: self.timeouts.clone(),
+            limits: self.limits,
+            body_limits: self.body_limits,
+            log: Arc::clone(&self.pool.logger),
+            access_log: self.access_log.clone(),
+        };
+        let log = &*options.log;
+        let per_ip = Arc::new(PerIp {
+            max: self.max_connections_per_ip,
+            ..PerIp::default()
+        });
+        let listeners = mem::take(&mut self.listeners);
+        // 中间件和 handler 一样在 worker 中运行，两种模式都不会阻塞接收连接的线程
+        let mut middlewares = mem::take(&mut self.middlewares);
+        if let Some(compression) = self.compression.take() {
+            middlewares.insert(0, Box::new(compression));
+        }
+        if !middlewares.is_empty() {
+            self.handler = Arc::new(Stack {
+                middlewares,
+                handler: Arc::clone(&self.handler),
+            });
+        }
+
+        match self.mode {
+            Mode::Threads => self.serve_threads(listeners, &pool, &options, &per_ip),
+            Mode::EventLoop => {
+                let result = event_loop::serve(
+                    listeners,
+                    Arc::clone(&self.handler),
+                    &pool,
+                    &options,
+                    &per_ip,
+                    &self.tracker,
+                    self.shutdown_timeout,
+                );
+                if let Err(e) = result {
+                    log(LogLevel::Error, format_args!("Event loop failed: {}", e));
+                }
+            }
+        }
+
+        // 线程池的 Drop 会等待所有 worker 退出，之后计数不会再变化
+        drop(pool);
+
+        let report = ShutdownReport {
+            drained: self.tracker.drained.load(Ordering::SeqCst),
+            aborted: self.tracker.aborted.load(Ordering::SeqCst),
+        };
+        log(
+            LogLevel::Info,
+            format_args!(
+                "Shutdown complete: {} requests drained, {} aborted.",
+                report.drained, report.aborted
+            ),
+        );
+        Ok(report)
+    }
+
+    /// `Mode::Threads`：接受连接直到开始关闭，然后等待正在处理的请求完成
+    fn serve_threads(
+        &self,
+        listeners: Vec<TcpListener>,
+        pool: &ThreadPool,
+        options: &Options,
+        per_ip: &Arc<PerIp>,
+    ) {
+        let log = &*options.log;
+
+        // 第一个地址在当前线程上接受连接，其余的各用一个线程
+        thread::scope(|s| {
+            for listener in &listeners[1..] {
+                s.spawn(move || self.accept(listener, pool, options, per_ip));
+            }
+            self.accept(&listeners[0], pool, options, per_ip);
+        });
+        drop(listeners);
+
+        log(
+            LogLevel::Info,
+            format_args!("Shutting down, waiting for in-flight requests."),
+        );
+        if !self.tracker.wait_idle(self.shutdown_timeout) {
+            log(
+                LogLevel::Warn,
+                format_args!("Shutdown timeout reached, aborting remaining connections."),
+            );
+            self.tracker.abort_all();
+            // 通知还在执行的 handler 放弃，排队中的连接直接丢弃
+            let discarded = pool.shutdown_now();
+            self.tracker.aborted.fetch_add(discarded, Ordering::SeqCst);
+        }
+    }
+
+    /// 在一个地址上接受连接并交给线程池，直到开始关闭
+    fn accept(
+        &self,
+        listener: &TcpListener,
+        pool: &ThreadPool,
+        options: &Options,
+        per_ip: &Arc<PerIp>,
+    ) {
+        let log = &*options.log;
+
+        for stream in listener.incoming() {
+            // 唤醒 accept 的那个连接也在这里被丢弃
+            if self.tracker.is_shutting_down() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log(
+                        LogLevel::Warn,
+                        format_args!("Failed to accept connection: {}", e),
+                    );
+                    continue;
+                }
+            };
+
+            // 同一个 IP 的连接太多时直接拒绝，名额随任务一起在连接处理完或被丢弃时归还
+            let slot = match stream.peer_addr() {
+                Ok(addr) => per_ip.acquire(addr.ip()),
+                Err(_) => continue,
+            };
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    let mut stream = stream;
+                    let _ = Response::text(429, "429 Too Many Requests")
+                        .with_header("Retry-After", "1")
+                        .with_header("Connection", "close")
+                        .write_to(&mut stream, false);
+                    continue;
+                }
+            };
+
+            let handler = Arc::clone(&self.handler);
+            let tracker = Arc::clone(&self.tracker);
+            let options = options.clone();
+
+            // 使用线程池处理请求过来的stream，同一个连接上的请求都由这个worker处理
+            // 队列满时连接已经随任务一起被丢弃，先留一个克隆用来回应503
+            let overflow = stream.try_clone();
+            let result = pool.execute(move || {
+                let _slot = slot;
+                serve_connection(stream, &*handler, &options, &tracker);
+            });
+
+            match result {
+                Ok(()) => {}
+                Err(ExecuteError::QueueFull) => {
+                    if let Ok(mut stream) = overflow {
+                        let _ = Response::text(503, "503 Service Unavailable")
+                            .with_header("Retry-After", "1")
+                            .with_header("Connection", "close")
+                            .write_to(&mut stream, false);
+                    }
+                }
+                Err(e) => log(
+                    LogLevel::Warn,
+                    format_args!("Failed to dispatch connection: {}", e),
+                ),
+            }
+        }
+    }
+}
+
+/// 收到 SIGINT 或 SIGTERM 时关闭 Server
+///
+/// 信号处理函数里只给一个计数加一，由后台线程轮询，计数比调用时变大了才调用 `shutdown`，
+/// 所以之前收到的信号不会影响之后启动的 Server，同时在等待的每个 Server 都会关闭。
+/// 收到信号的消息写进服务器的日志，所以应该在设置好日志之后再创建 handle。
+#[cfg(unix)]
+pub fn shutdown_on_signals(handle: ShutdownHandle) {
+    use std::thread;
+
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn on_signal(_: libc::c_int) {
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let seen = SIGNALS.load(Ordering::SeqCst);
+
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    thread::spawn(move || {
+        while !handle.is_shutting_down() {
+            if SIGNALS.load(Ordering::SeqCst) != seen {
+                (handle.log)(
+                    LogLevel::Info,
+                    format_args!("Received signal, shutting down."),
+                );
+                handle.shutdown();
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::Multipart;
+    use crate::middleware::Next;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    /// 启动一个只处理一个连接的服务端，把 raw 发过去，返回收到的全部数据和处理的请求数
+    fn exchange(raw: &'static str, keep_alive: KeepAlive) -> (String, usize) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let handler = |req: &mut Request| Response::text(200, &req.path);
+            handle_connection(stream, &handler, &keep_alive)
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+
+        (out, server.join().unwrap())
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let raw = "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let (out, served) = exchange(raw, KeepAlive::default());
+
+        assert_eq!(served, 3);
+        let a = out.find("\r\n\r\n/a").unwrap();
+        let b = out.find("\r\n\r\n/b").unwrap();
+        let c = out.find("\r\n\r\n/c").unwrap();
+        assert!(a < b && b < c);
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 2\r\n\r\n/c"));
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let keep_alive = KeepAlive {
+            max_requests: 2,
+            ..KeepAlive::default()
+        };
+        let (out, served) = exchange("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n", keep_alive);
+
+        // 客户端没有要求关闭，第二个响应之后由服务端主动关闭
+        assert_eq!(served, 2);
+        assert!(out.contains("Keep-Alive: timeout=5, max=1\r\n"));
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 2\r\n\r\n/b"));
+    }
+
+    #[test]
+    fn closes_after_idle_timeout() {
+        let keep_alive = KeepAlive {
+            timeout: Duration::from_millis(100),
+            ..KeepAlive::default()
+        };
+        let (out, served) = exchange("GET /a HTTP/1.1\r\n\r\n", keep_alive);
+
+        assert_eq!(served, 1);
+        assert!(out.contains("Keep-Alive: max=99\r\n"));
+    }
+
+    #[test]
+    fn shutdown_drains_in_flight_requests() {
+        let handler = |req: &mut Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(200, &req.path)
+        };
+        let server = Server::bind("127.0.0.1:0", handler).unwrap().workers(2);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+
+        // 正在处理的请求照常完成，并且连接随后被关闭
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.contains("Connection: close"));
+        assert!(out.ends_with("/slow"));
+
+        let report = running.join().unwrap().unwrap();
+        assert_eq!(
+            report,
+            ShutdownReport {
+                drained: 1,
+                aborted: 0
+            }
+        );
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn shutdown_aborts_requests_past_deadline() {
+        let handler = |_: &mut Request| {
+            thread::sleep(Duration::from_millis(500));
+            Response::text(200, "late")
+        };
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .shutdown_timeout(Duration::from_millis(50));
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+
+        let report = running.join().unwrap().unwrap();
+        assert_eq!(
+            report,
+            ShutdownReport {
+                drained: 0,
+                aborted: 1
+            }
+        );
+    }
+
+    #[test]
+    fn overloaded_server_answers_503() {
+        let handler = |_: &mut Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(200, "slow")
+        };
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .workers(1)
+            .queue_capacity(0);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // 唯一的 worker 正忙，队列容量为 0
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut out = String::new();
+        rejected.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn listens_on_every_configured_address() {
+        let config = ServerConfig {
+            listen: vec![
+                "127.0.0.1:0".parse().unwrap(),
+                "127.0.0.1:0".parse().unwrap(),
+            ],
+            workers: 2,
+            max_request_size: 4,
+            ..ServerConfig::default()
+        };
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::from_config(&config, handler).unwrap();
+        let addrs = server.local_addrs().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        for addr in &addrs {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /hi HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut out = String::new();
+            client.read_to_string(&mut out).unwrap();
+            assert!(out.ends_with("/hi"), "{}", out);
+        }
+
+        // 请求体超过 max_request_size
+        let mut client = TcpStream::connect(addrs[1]).unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(addrs.iter().all(|addr| TcpStream::connect(addr).is_err()));
+    }
+
+    #[test]
+    fn writes_an_access_log_line_per_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::new(
+            access_log::LogTarget::File(path.clone()),
+            access_log::AccessLogFormat::Combined,
+            access_log::Rotation::default(),
+        )
+        .unwrap();
+
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .access_log(log);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /a?b=c HTTP/1.1\r\nUser-Agent: test\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        client.read_to_string(&mut String::new()).unwrap();
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+
+        // run 返回时访问日志已经写完
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("127.0.0.1 - - ["), "{}", text);
+        assert!(
+            text.ends_with("] \"GET /a?b=c HTTP/1.1\" 200 2 \"-\" \"test\"\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn middleware_wraps_every_request() {
+        for mode in [Mode::Threads, Mode::EventLoop].iter() {
+            let handler = |req: &mut Request| Response::text(200, &req.path);
+            let server = Server::bind("127.0.0.1:0", handler)
+                .unwrap()
+                .mode(*mode)
+                .middleware(|req: &mut Request, next: Next| {
+                    if req.header("authorization").is_none() {
+                        return Response::text(401, "401 Unauthorized");
+                    }
+                    let mut response = next.run(req);
+                    response.set_header("X-Served-By", "a20");
+                    response
+                });
+            let addr = server.local_addr().unwrap();
+            let handle = server.shutdown_handle().unwrap();
+            let running = thread::spawn(move || server.run());
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nAuthorization: x\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut out = String::new();
+            client.read_to_string(&mut out).unwrap();
+            assert!(out.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", out);
+            assert!(out.contains("X-Served-By: a20\r\n"), "{}", out);
+            assert!(out.ends_with("\r\n\r\n/b"), "{}", out);
+
+            handle.shutdown();
+            running.join().unwrap().unwrap();
+        }
+    }
+
+    /// 上传一个 len 字节的文件的请求，chunked 时以 4KB 一块发送
+    fn upload_request(len: usize, chunked: bool) -> Vec<u8> {
+        let mut body =
+            b"--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a\"\r\n\r\n".to_vec();
+        body.extend((0..len).map(|i| (i % 251) as u8));
+        body.extend_from_slice(b"\r\n--b--\r\n");
+
+        let mut raw = b"POST /up HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\nConnection: close\r\n".to_vec();
+        if !chunked {
+            raw.extend(format!("Content-Length: {}\r\n\r\n", body.len()).bytes());
+            raw.extend(body);
+            return raw;
+        }
+        raw.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+        for chunk in body.chunks(4096) {
+            raw.extend(format!("{:x}\r\n", chunk.len()).bytes());
+            raw.extend_from_slice(chunk);
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"0\r\n\r\n");
+        raw
+    }
+
+    #[test]
+    fn streams_multipart_uploads_to_temp_files() {
+        for mode in [Mode::Threads, Mode::EventLoop].iter() {
+            let handler = |req: &mut Request| {
+                let multipart: Multipart = match req.extract() {
+                    Ok(multipart) => multipart,
+                    Err(e) => return e.into(),
+                };
+                let mut content = Vec::new();
+                let file = multipart.file("f").unwrap();
+                file.open().unwrap().read_to_end(&mut content).unwrap();
+                let expected: Vec<u8> = (0..content.len()).map(|i| (i % 251) as u8).collect();
+                let same = content == expected && req.body.is_empty();
+                Response::text(200, &format!("{} {}", file.size(), same))
+            };
+            // 请求体不会整个读进内存，所以不受 max_request_size 的限制
+            let server = Server::bind("127.0.0.1:0", handler)
+                .unwrap()
+                .mode(*mode)
+                .max_request_size(1024)
+                .body_limits(BodyLimits {
+                    max_file_size: 200 * 1024,
+                    ..BodyLimits::default()
+                });
+            let addr = server.local_addr().unwrap();
+            let handle = server.shutdown_handle().unwrap();
+            let running = thread::spawn(move || server.run());
+
+            for chunked in [false, true].iter() {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .write_all(&upload_request(100 * 1024, *chunked))
+                    .unwrap();
+                let mut out = String::new();
+                client.read_to_string(&mut out).unwrap();
+                assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{}", out);
+                assert!(out.ends_with("\r\n\r\n102400 true"), "{}", out);
+
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .write_all(&upload_request(200 * 1024 + 1, *chunked))
+                    .unwrap();
+                let mut out = String::new();
+                client.read_to_string(&mut out).unwrap();
+                assert!(
+                    out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+                    "{}",
+                    out
+                );
+            }
+
+            handle.shutdown();
+            running.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn handler_errors_go_to_the_log() {
+        for mode in [Mode::Threads, Mode::EventLoop].iter() {
+            let logged = Arc::new(Mutex::new(Vec::new()));
+            let captured = Arc::clone(&logged);
+            let handler = |_: &mut Request| {
+                Response::text(500, "500 Internal Server Error").with_error("disk on fire")
+            };
+            let server = Server::bind("127.0.0.1:0", handler)
+                .unwrap()
+                .mode(*mode)
+                .pool(ThreadPool::builder().threads(2).logger(move |level, args| {
+                    if level == LogLevel::Error {
+                        lock(&captured).push(args.to_string());
+                    }
+                }));
+            let addr = server.local_addr().unwrap();
+            let handle = server.shutdown_handle().unwrap();
+            let running = thread::spawn(move || server.run());
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /data HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut out = String::new();
+            client.read_to_string(&mut out).unwrap();
+            // 错误只进日志，不发给客户端
+            assert!(
+                out.ends_with("\r\n\r\n500 Internal Server Error"),
+                "{}",
+                out
+            );
+
+            handle.shutdown();
+            running.join().unwrap().unwrap();
+            assert_eq!(*lock(&logged), ["GET /data: disk on fire"]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_are_logged_through_the_logger() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&logged);
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::bind("127.0.0.1:0", handler).unwrap().pool(
+            ThreadPool::builder().threads(1).logger(move |_, args| {
+                lock(&captured).push(args.to_string());
+            }),
+        );
+        shutdown_on_signals(server.shutdown_handle().unwrap());
+        let running = thread::spawn(move || server.run());
+
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        running.join().unwrap().unwrap();
+        assert!(lock(&logged)
+            .iter()
+            .any(|message| message == "Received signal, shutting down."));
+
+        // 之前的信号不会让之后启动的 Server 立即关闭
+        let server = Server::bind("127.0.0.1:0", handler).unwrap();
+        let addr = server.local_addr().unwrap();
+        shutdown_on_signals(server.shutdown_handle().unwrap());
+        let running = thread::spawn(move || server.run());
+        thread::sleep(Duration::from_millis(300));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK"), "{}", out);
+
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn slow_clients_get_408() {
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .timeouts(Timeouts {
+                header: Duration::from_millis(200),
+                ..Timeouts::default()
+            });
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        // 一点一点地发请求头，每次都没有超过读超时，但总时间超过了期限
+        let mut client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        for chunk in ["GET / HTTP/1.1\r\n", "Host: a\r\n", "X-Slow: 1\r\n"].iter() {
+            client.write_all(chunk.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(
+            out.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            out
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 请求头太多
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut raw = String::from("GET / HTTP/1.1\r\n");
+        for i in 0..=Limits::default().max_headers {
+            raw.push_str(&format!("X-{}: 1\r\n", i));
+        }
+        raw.push_str("\r\n");
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn caps_concurrent_connections_per_ip() {
+        let handler = |req: &mut Request| Response::text(200, &req.path);
+        let server = Server::bind("127.0.0.1:0", handler)
+            .unwrap()
+            .workers(4)
+            .max_connections_per_ip(2);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        // 两个什么都不发的连接占满了名额
+        let idle: Vec<_> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut out = String::new();
+        rejected.read_to_string(&mut out).unwrap();
+        assert!(
+            out.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{}",
+            out
+        );
+
+        // 连接关闭后名额归还
+        drop(idle);
+        thread::sleep(Duration::from_millis(100));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /ok HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("/ok"), "{}", out);
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}
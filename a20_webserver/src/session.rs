@@ -0,0 +1,333 @@
+//! 以 HMAC 签名的 cookie 保存的登录会话
+//!
+//! 会话的内容（ID、用户名和过期时间）全部放在 cookie 里，服务器只保存密钥，
+//! 签名保证客户端无法伪造或修改。退出登录的会话 ID 记在内存中直到过期，
+//! 这样即使旧 cookie 被重放也不再有效；服务器重启后这份记录会丢失。
+//!
+//! ```no_run
+//! use a20_webserver::http::Request;
+//! use a20_webserver::middleware::Stack;
+//! use a20_webserver::response::Response;
+//! use a20_webserver::router::Router;
+//! use a20_webserver::session::Sessions;
+//! use std::sync::Arc;
+//!
+//! let sessions = Arc::new(Sessions::new(b"a long random secret key, at least 32 bytes"));
+//! let login = Arc::clone(&sessions);
+//! let logout = Arc::clone(&sessions);
+//! let router = Router::new()
+//!     .post("/login", move |_: &mut Request| {
+//!         // 检查用户名和密码之后
+//!         let mut response = Response::new(303).with_header("Location", "/");
+//!         login.login(&mut response, "ann");
+//!         response
+//!     })
+//!     .post("/logout", move |req: &mut Request| {
+//!         let mut response = Response::new(303).with_header("Location", "/login");
+//!         logout.logout(req, &mut response);
+//!         response
+//!     })
+//!     .get(
+//!         "/",
+//!         Stack::new(|req: &mut Request| Response::text(200, req.user.as_deref().unwrap_or("")))
+//!             .with(sessions.require().redirect_to("/login")),
+//!     );
+//! ```
+
+use crate::http::Request;
+use crate::lock;
+use crate::middleware::{Middleware, Next};
+use crate::response::Response;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 一个有效的会话
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// 随机生成，退出登录时用来吊销
+    pub id: String,
+    pub user: String,
+    /// 精确到秒
+    pub expires: SystemTime,
+}
+
+/// 会话的签发、验证和吊销
+///
+/// 默认 cookie 名为 `session`，有效期 12 小时，带 `HttpOnly` 和 `SameSite=Lax`。
+pub struct Sessions {
+    key: Vec<u8>,
+    cookie: String,
+    max_age: Duration,
+    secure: bool,
+    /// 已经退出登录的会话 ID 和它们本来的过期时间
+    revoked: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Sessions {
+    /// key 是签名用的密钥，应该是至少 32 字节的随机数据，并在重启之间保持不变
+    pub fn new(key: &[u8]) -> Sessions {
+        Sessions {
+            key: key.to_vec(),
+            cookie: "session".to_string(),
+            max_age: Duration::from_secs(12 * 60 * 60),
+            secure: false,
+            revoked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Sessions {
+        self.cookie = name.to_string();
+        self
+    }
+
+    /// 登录之后多久过期
+    pub fn max_age(mut self, max_age: Duration) -> Sessions {
+        self.max_age = max_age;
+        self
+    }
+
+    /// 是否给 cookie 加上 `Secure`，只通过 HTTPS 发送
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    /// 为 user 签发新的会话，在响应中设置 cookie
+    pub fn login(&self, response: &mut Response, user: &str) -> Session {
+        let mut id = [0; 16];
+        getrandom::getrandom(&mut id).expect("failed to read random bytes from the OS");
+        let expires = UNIX_EPOCH + Duration::from_secs(unix_secs(SystemTime::now() + self.max_age));
+        let session = Session {
+            id: URL_SAFE_NO_PAD.encode(id),
+            user: user.to_string(),
+            expires,
+        };
+
+        let payload = format!(
+            "{}.{}.{}",
+            session.id,
+            unix_secs(expires),
+            URL_SAFE_NO_PAD.encode(user)
+        );
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&payload).finalize().into_bytes());
+        self.set_cookie(
+            response,
+            &format!("{}.{}", payload, signature),
+            self.max_age.as_secs(),
+        );
+        session
+    }
+
+    /// 请求中带有的有效会话：签名正确、没有过期、没有退出登录
+    pub fn session(&self, request: &Request) -> Option<Session> {
+        let value = cookie(request, &self.cookie)?;
+        let (payload, signature) = value.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        // verify_slice 的比较耗时不随内容变化
+        self.sign(payload).verify_slice(&signature).ok()?;
+
+        let mut parts = payload.split('.');
+        let id = parts.next()?;
+        let expires = UNIX_EPOCH + Duration::from_secs(parts.next()?.parse().ok()?);
+        let user = String::from_utf8(URL_SAFE_NO_PAD.decode(parts.next()?).ok()?).ok()?;
+        if parts.next().is_some() || expires <= SystemTime::now() {
+            return None;
+        }
+        if lock(&self.revoked).contains_key(id) {
+            return None;
+        }
+
+        Some(Session {
+            id: id.to_string(),
+            user,
+            expires,
+        })
+    }
+
+    /// 吊销请求中的会话，并在响应中清除 cookie
+    pub fn logout(&self, request: &Request, response: &mut Response) {
+        if let Some(session) = self.session(request) {
+            let now = SystemTime::now();
+            let mut revoked = lock(&self.revoked);
+            // 过期的会话本来就无效，不需要再记着
+            revoked.retain(|_, expires| *expires > now);
+            revoked.insert(session.id, session.expires);
+        }
+        self.set_cookie(response, "", 0);
+    }
+
+    /// 要求请求带有有效会话的中间件，可以用在需要登录的路由上
+    pub fn require(self: &Arc<Self>) -> RequireSession {
+        RequireSession {
+            sessions: Arc::clone(self),
+            login_url: None,
+        }
+    }
+
+    fn sign(&self, payload: &str) -> Hmac<Sha256> {
+        // HMAC 接受任意长度的密钥
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key length works");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn set_cookie(&self, response: &mut Response, value: &str, max_age: u64) {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.cookie, value, max_age
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        // 不能用 set_header，同一个响应可能设置多个 cookie
+        response.headers.push(("Set-Cookie".to_string(), cookie));
+    }
+}
+
+/// 由 [`Sessions::require`] 创建的中间件
+///
+/// 会话有效时把用户名放进 `Request::user`，否则回应 401，
+/// 或者用 `redirect_to` 设置的登录页以 303 跳转。
+pub struct RequireSession {
+    sessions: Arc<Sessions>,
+    login_url: Option<String>,
+}
+
+impl RequireSession {
+    pub fn redirect_to(mut self, login_url: &str) -> RequireSession {
+        self.login_url = Some(login_url.to_string());
+        self
+    }
+}
+
+impl Middleware for RequireSession {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        match self.sessions.session(request) {
+            Some(session) => {
+                request.user = Some(session.user);
+                next.run(request)
+            }
+            None => match &self.login_url {
+                Some(url) => Response::new(303).with_header("Location", url),
+                None => Response::text(401, "401 Unauthorized"),
+            },
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 从 Cookie 请求头中取出名为 name 的值
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .header("cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Stack;
+    use crate::router::Handler;
+
+    fn get(cookie: Option<&str>) -> Request {
+        let mut raw = "GET / HTTP/1.1\r\n".to_string();
+        if let Some(value) = cookie {
+            raw.push_str(&format!("Cookie: theme=dark; {}\r\n", value));
+        }
+        raw.push_str("\r\n");
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    /// 登录并返回浏览器之后会发回的 `name=value`
+    fn login(sessions: &Sessions, user: &str) -> String {
+        let mut response = Response::new(200);
+        sessions.login(&mut response, user);
+        let set_cookie = response.header("set-cookie").unwrap();
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn signs_and_verifies_sessions() {
+        let sessions = Sessions::new(b"0123456789abcdef0123456789abcdef").secure(true);
+        let mut response = Response::new(200);
+        let session = sessions.login(&mut response, "ann.b");
+        let set_cookie = response.header("set-cookie").unwrap();
+        assert!(set_cookie.starts_with("session="));
+        assert!(set_cookie.ends_with("; Path=/; Max-Age=43200; HttpOnly; SameSite=Lax; Secure"));
+
+        let cookie = login(&sessions, "ann.b");
+        let found = sessions.session(&get(Some(&cookie))).unwrap();
+        assert_eq!(found.user, "ann.b");
+        assert_eq!(found.expires, session.expires);
+        assert_ne!(found.id, session.id);
+
+        // 改动任何一部分或换了密钥都会使签名失效
+        let (payload, signature) = cookie.rsplit_once('.').unwrap();
+        let forged = payload.replace(
+            &URL_SAFE_NO_PAD.encode("ann.b"),
+            &URL_SAFE_NO_PAD.encode("root"),
+        );
+        assert!(sessions
+            .session(&get(Some(&format!("{}.{}", forged, signature))))
+            .is_none());
+        let other = Sessions::new(b"another key");
+        assert!(other.session(&get(Some(&cookie))).is_none());
+        assert!(sessions.session(&get(Some("session=garbage"))).is_none());
+        assert!(sessions.session(&get(None)).is_none());
+    }
+
+    #[test]
+    fn sessions_expire_and_can_be_logged_out() {
+        let expired = Sessions::new(b"key").max_age(Duration::from_secs(0));
+        let cookie = login(&expired, "ann");
+        assert!(expired.session(&get(Some(&cookie))).is_none());
+
+        let sessions = Sessions::new(b"key").cookie_name("sid");
+        let cookie = login(&sessions, "ann");
+        assert!(cookie.starts_with("sid="));
+        let other = login(&sessions, "ann");
+        let request = get(Some(&cookie));
+        assert!(sessions.session(&request).is_some());
+
+        let mut response = Response::new(303);
+        sessions.logout(&request, &mut response);
+        assert_eq!(
+            response.header("set-cookie"),
+            Some("sid=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax")
+        );
+        // 旧 cookie 被重放也不再有效，同一用户的其他会话不受影响
+        assert!(sessions.session(&request).is_none());
+        assert!(sessions.session(&get(Some(&other))).is_some());
+    }
+
+    #[test]
+    fn requires_a_session_per_route() {
+        let sessions = Arc::new(Sessions::new(b"key"));
+        let handler = |req: &mut Request| Response::text(200, req.user.as_deref().unwrap_or(""));
+        let api = Stack::new(handler).with(sessions.require());
+        let page = Stack::new(handler).with(sessions.require().redirect_to("/login"));
+
+        assert_eq!(api.handle(&mut get(None)).status, 401);
+        let response = page.handle(&mut get(None));
+        assert_eq!(response.status, 303);
+        assert_eq!(response.header("location"), Some("/login"));
+
+        let mut request = get(Some(&login(&sessions, "ann")));
+        assert_eq!(page.handle(&mut request).status, 200);
+        assert_eq!(request.user.as_deref(), Some("ann"));
+    }
+}
@@ -0,0 +1,587 @@
+use crate::compress::{self, Encoding};
+use crate::date::{http_date, parse_http_date};
+use crate::http::{percent_decode, Method, Request};
+use crate::range::{self, Multipart, Ranges};
+use crate::response::Response;
+use crate::router::Handler;
+
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 从文档根目录提供静态文件
+///
+/// 请求路径先经过 URL 解码，含有 `..` 的路径直接返回 403；
+/// 通过符号链接指向根目录之外的文件同样返回 403。
+/// 文件内容在写出响应时才边读边发，不会整个读进内存。
+///
+/// 响应带有 `ETag` 和 `Last-Modified`，通过 [`serve_request`](StaticFiles::serve_request)
+/// 处理请求时还会按条件请求头返回 304，按 `Range` 返回 206 或 416。
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    not_found_page: Option<String>,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    /// 以 root 作为文档根目录，root 必须是已存在的目录
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+
+        Ok(StaticFiles {
+            root,
+            index: "index.html".to_string(),
+            not_found_page: None,
+            precompressed: false,
+        })
+    }
+
+    /// 请求目录时返回的文件名，默认为 index.html
+    pub fn with_index(mut self, name: &str) -> StaticFiles {
+        self.index = name.to_string();
+        self
+    }
+
+    /// 文件不存在时，以 404 状态返回根目录下的这个页面
+    pub fn with_not_found_page(mut self, name: &str) -> StaticFiles {
+        self.not_found_page = Some(name.to_string());
+        self
+    }
+
+    /// 客户端接受 gzip 时，如果同一目录下有加上 `.gz` 后缀的文件，改为发送它
+    ///
+    /// 例如 `app.js` 旁边有 `app.js.gz` 时，以 `Content-Encoding: gzip` 发送后者，
+    /// Content-Type 仍按原文件名推断。默认关闭。
+    pub fn with_precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// 返回文档根目录下 path 对应的文件，path 是已经解码的相对路径
+    pub fn serve(&self, path: &str) -> Response {
+        self.respond(path, None)
+    }
+
+    /// 与 serve 相同，但会处理请求中的 `If-None-Match`、`If-Modified-Since`、
+    /// `Range` 和 `If-Range`
+    pub fn serve_request(&self, path: &str, request: &Request) -> Response {
+        self.respond(path, Some(request))
+    }
+
+    fn respond(&self, path: &str, request: Option<&Request>) -> Response {
+        let file = match self.resolve(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.not_found(),
+            Err(e) => return error_response(&e),
+        };
+        let content_type = mime_type(&file);
+
+        let gzip = if self.precompressed {
+            self.gzip_sibling(&file)
+        } else {
+            None
+        };
+        let accepts_gzip = request
+            .and_then(|request| request.header("accept-encoding"))
+            .and_then(|header| compress::negotiate(header, &[Encoding::Gzip]))
+            .is_some();
+        let result = match &gzip {
+            Some(gzip) if accepts_gzip => {
+                open_conditional(gzip, content_type, request).map(|mut response| {
+                    if response.status == 200 || response.status == 206 {
+                        response.set_header("Content-Encoding", "gzip");
+                    }
+                    response
+                })
+            }
+            _ => open_conditional(&file, content_type, request),
+        };
+
+        let mut response = result.unwrap_or_else(|e| error_response(&e));
+        if gzip.is_some() {
+            compress::add_vary(&mut response, "Accept-Encoding");
+        }
+        response
+    }
+
+    /// 与 file 同目录、加上 `.gz` 后缀的文件，同样不能通过符号链接跑到根目录之外
+    fn gzip_sibling(&self, file: &Path) -> Option<PathBuf> {
+        let mut name = file.as_os_str().to_owned();
+        name.push(".gz");
+        let gzip = PathBuf::from(name).canonicalize().ok()?;
+        if gzip.starts_with(&self.root) && gzip.is_file() {
+            Some(gzip)
+        } else {
+            None
+        }
+    }
+
+    /// 把请求路径映射到根目录下的真实文件
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let mut file = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => file.push(part),
+                Component::CurDir | Component::RootDir => {}
+                // ".." 以及 Windows 下的盘符前缀都不允许出现
+                Component::ParentDir | Component::Prefix(_) => return Err(forbidden()),
+            }
+        }
+
+        // canonicalize 会展开符号链接，展开后必须仍在根目录下
+        let mut file = file.canonicalize()?;
+        if !file.starts_with(&self.root) {
+            return Err(forbidden());
+        }
+
+        if file.is_dir() {
+            file.push(&self.index);
+            file = file.canonicalize()?;
+            if !file.starts_with(&self.root) {
+                return Err(forbidden());
+            }
+        }
+
+        Ok(file)
+    }
+
+    fn not_found(&self) -> Response {
+        let page = self
+            .not_found_page
+            .as_ref()
+            .and_then(|name| self.resolve(name).ok())
+            .and_then(|file| open(&file, 404).ok());
+
+        page.unwrap_or_else(|| Response::text(404, "404 Not Found"))
+    }
+}
+
+impl Handler for StaticFiles {
+    /// 优先使用路由捕获的 `path` 参数，没有时使用整个请求路径
+    fn handle(&self, request: &mut Request) -> Response {
+        let path = match request.param("path") {
+            Some(path) => path.to_string(),
+            None => match percent_decode(&request.path) {
+                Some(path) => path,
+                None => return Response::text(400, "400 Bad Request"),
+            },
+        };
+
+        self.serve_request(&path, request)
+    }
+}
+
+/// 文件的校验器，用于条件请求
+struct Validators {
+    etag: String,
+    /// 精确到秒，与 HTTP 日期的精度一致
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn new(metadata: &Metadata) -> Validators {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        let nanos = modified.map(|d| d.as_nanos()).unwrap_or(0);
+
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", nanos, metadata.len()),
+            last_modified: modified.map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs())),
+        }
+    }
+
+    fn apply(&self, mut response: Response) -> Response {
+        response.set_header("ETag", &self.etag);
+        if let Some(time) = self.last_modified {
+            response.set_header("Last-Modified", &http_date(time));
+        }
+        response
+    }
+
+    /// 客户端缓存的版本是否仍然有效
+    ///
+    /// 有 If-None-Match 时忽略 If-Modified-Since，比较 ETag 时使用弱比较。
+    fn not_modified(&self, request: &Request) -> bool {
+        if let Some(tags) = request.header("if-none-match") {
+            let etag = weak(&self.etag);
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak(tag) == etag);
+        }
+
+        match (self.last_modified, request.header("if-modified-since")) {
+            (Some(modified), Some(since)) => {
+                parse_http_date(since).is_some_and(|since| modified <= since)
+            }
+            _ => false,
+        }
+    }
+
+    /// If-Range 是否与当前文件一致，不一致时应忽略 Range 返回整个文件
+    ///
+    /// 这里要求强比较：弱 ETag 不匹配，日期必须完全相同。
+    fn if_range(&self, request: &Request) -> bool {
+        match request.header("if-range").map(str::trim) {
+            None => true,
+            Some(tag) if tag.starts_with('"') => tag == self.etag,
+            Some(date) => {
+                self.last_modified.is_some() && parse_http_date(date) == self.last_modified
+            }
+        }
+    }
+}
+
+/// 去掉弱 ETag 的 `W/` 前缀
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// 打开文件，按请求中的条件请求头和 Range 生成 200、206、304 或 416 响应
+fn open_conditional(
+    path: &Path,
+    content_type: &str,
+    request: Option<&Request>,
+) -> io::Result<Response> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(forbidden());
+    }
+
+    let len = metadata.len();
+    let validators = Validators::new(&metadata);
+    let full = |file: File| {
+        validators.apply(
+            Response::new(200)
+                .with_header("Content-Type", content_type)
+                .with_header("Accept-Ranges", "bytes")
+                .with_reader(file, len),
+        )
+    };
+
+    // 条件请求只对 GET 和 HEAD 有意义
+    let request = match request {
+        Some(request) if request.method == Method::Get || request.method == Method::Head => request,
+        _ => return Ok(full(file)),
+    };
+    if validators.not_modified(request) {
+        return Ok(validators.apply(Response::new(304)));
+    }
+
+    let ranges = match request.header("range") {
+        Some(header) if request.method == Method::Get && validators.if_range(request) => {
+            range::parse(header, len)
+        }
+        _ => Ranges::Ignore,
+    };
+
+    match ranges {
+        Ranges::Ignore => Ok(full(file)),
+        Ranges::Unsatisfiable => Ok(Response::text(416, "416 Range Not Satisfiable")
+            .with_header("Content-Range", &format!("bytes */{}", len))),
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            file.seek(SeekFrom::Start(range.start))?;
+            Ok(validators.apply(
+                Response::new(206)
+                    .with_header("Content-Type", content_type)
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_header("Content-Range", &range.content_range(len))
+                    .with_reader(file, range.len()),
+            ))
+        }
+        Ranges::Satisfiable(ranges) => {
+            let boundary = range::boundary();
+            let (body, body_len) = Multipart::new(file, &ranges, len, content_type, &boundary);
+            Ok(validators.apply(
+                Response::new(206)
+                    .with_header(
+                        "Content-Type",
+                        &format!("multipart/byteranges; boundary={}", boundary),
+                    )
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_reader(body, body_len),
+            ))
+        }
+    }
+}
+
+/// 打开文件，生成以文件内容为响应体的响应
+fn open(path: &Path, status: u16) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(forbidden());
+    }
+
+    Ok(Response::new(status)
+        .with_header("Content-Type", mime_type(path))
+        .with_reader(file, metadata.len()))
+}
+
+fn forbidden() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "outside of document root")
+}
+
+fn error_response(e: &io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::NotFound => Response::text(404, "404 Not Found"),
+        io::ErrorKind::PermissionDenied => Response::text(403, "403 Forbidden"),
+        _ => Response::text(500, "500 Internal Server Error")
+            .with_error(format_args!("Failed to serve static file: {}", e)),
+    }
+}
+
+/// 根据扩展名推断 Content-Type，未知的类型当作二进制流
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("mp4") => "video/mp4",
+        Some("mp3") => "audio/mpeg",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// 在临时目录下建一个文档根目录，以及一个根目录之外的文件
+    ///
+    /// 返回的 TempDir 被 drop 时删除整个目录。
+    fn setup() -> (TempDir, StaticFiles) {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        fs::create_dir_all(base.join("root/docs")).unwrap();
+        fs::write(base.join("root/index.html"), "<h1>index</h1>").unwrap();
+        fs::write(base.join("root/docs/a.css"), "body{}").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        let files = StaticFiles::new(base.join("root")).unwrap();
+        (dir, files)
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let (_dir, files) = setup();
+
+        let response = files.serve("docs/a.css");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.body.len(), 6);
+
+        let response = files.serve("");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        assert_eq!(files.serve("missing.html").status, 404);
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let (_dir, files) = setup();
+
+        assert_eq!(files.serve("../secret.txt").status, 403);
+        assert_eq!(files.serve("docs/../../secret.txt").status, 403);
+        // 绝对路径也只会在根目录下查找
+        assert_eq!(files.serve("/docs/a.css").status, 200);
+    }
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn conditional_requests_return_304() {
+        let (_dir, files) = setup();
+
+        let response = files.serve("docs/a.css");
+        let etag = response.header("etag").unwrap().to_string();
+        let modified = response.header("last-modified").unwrap().to_string();
+        assert!(etag.starts_with('"'));
+
+        let request = get("/docs/a.css", &[("If-None-Match", &etag)]);
+        let response = files.serve_request("docs/a.css", &request);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("etag"), Some(etag.as_str()));
+        assert!(response.body.is_empty());
+
+        let weak = format!("\"other\", W/{}", etag);
+        let request = get("/docs/a.css", &[("If-None-Match", &weak)]);
+        assert_eq!(files.serve_request("docs/a.css", &request).status, 304);
+
+        let request = get("/docs/a.css", &[("If-Modified-Since", &modified)]);
+        assert_eq!(files.serve_request("docs/a.css", &request).status, 304);
+
+        // If-None-Match 不匹配时不再看 If-Modified-Since
+        let request = get(
+            "/docs/a.css",
+            &[
+                ("If-None-Match", "\"other\""),
+                ("If-Modified-Since", &modified),
+            ],
+        );
+        assert_eq!(files.serve_request("docs/a.css", &request).status, 200);
+
+        let request = get(
+            "/docs/a.css",
+            &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")],
+        );
+        assert_eq!(files.serve_request("docs/a.css", &request).status, 200);
+    }
+
+    #[test]
+    fn range_requests() {
+        let (dir, files) = setup();
+        fs::write(dir.path().join("root/digits.txt"), "0123456789").unwrap();
+
+        let request = get("/digits.txt", &[("Range", "bytes=2-4")]);
+        let response = files.serve_request("digits.txt", &request);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(body(response), "234");
+
+        let request = get("/digits.txt", &[("Range", "bytes=-2")]);
+        assert_eq!(body(files.serve_request("digits.txt", &request)), "89");
+
+        let request = get("/digits.txt", &[("Range", "bytes=0-0,8-")]);
+        let response = files.serve_request("digits.txt", &request);
+        assert_eq!(response.status, 206);
+        let content_type = response.header("content-type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let body = body(response);
+        assert!(body.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+        let request = get("/digits.txt", &[("Range", "bytes=10-")]);
+        let response = files.serve_request("digits.txt", &request);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+
+        // If-Range 与当前文件不一致时返回整个文件
+        let request = get(
+            "/digits.txt",
+            &[("Range", "bytes=2-4"), ("If-Range", "\"stale\"")],
+        );
+        let response = files.serve_request("digits.txt", &request);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("accept-ranges"), Some("bytes"));
+        assert_eq!(response.body.len(), 10);
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let (dir, files) = setup();
+        let files = files.with_precompressed(true);
+        // 内容是否真的是 gzip 无关紧要，只看发的是哪个文件
+        fs::write(dir.path().join("root/docs/a.css.gz"), "gzipped").unwrap();
+
+        let request = get("/docs/a.css", &[("Accept-Encoding", "br, gzip")]);
+        let response = files.serve_request("docs/a.css", &request);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        let etag = response.header("etag").unwrap().to_string();
+        assert_eq!(body(response), "gzipped");
+
+        // 压缩版本的 ETag 与原文件不同，条件请求按它判断
+        let request = get(
+            "/docs/a.css",
+            &[("Accept-Encoding", "gzip"), ("If-None-Match", &etag)],
+        );
+        let response = files.serve_request("docs/a.css", &request);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.header("content-encoding"), None);
+
+        for accept in ["gzip;q=0", "deflate"].iter() {
+            let request = get("/docs/a.css", &[("Accept-Encoding", accept)]);
+            let response = files.serve_request("docs/a.css", &request);
+            assert_eq!(response.header("content-encoding"), None);
+            assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+            assert_ne!(response.header("etag"), Some(etag.as_str()));
+            assert_eq!(body(response), "body{}");
+        }
+
+        // 没有 .gz 文件或没有开启时照常发送
+        let request = get("/index.html", &[("Accept-Encoding", "gzip")]);
+        let response = files.serve_request("index.html", &request);
+        assert_eq!(response.header("vary"), None);
+        assert_eq!(body(response), "<h1>index</h1>");
+        let files = files.with_precompressed(false);
+        let request = get("/docs/a.css", &[("Accept-Encoding", "gzip")]);
+        assert_eq!(body(files.serve_request("docs/a.css", &request)), "body{}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escape() {
+        let (dir, files) = setup();
+        std::os::unix::fs::symlink(
+            dir.path().join("secret.txt"),
+            dir.path().join("root/link.txt"),
+        )
+        .unwrap();
+
+        assert_eq!(files.serve("link.txt").status, 403);
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// 直方图的桶数，第 i 个桶记录 [2^(i-1), 2^i) 微秒的延迟，最后一个桶兜底
+const BUCKETS: usize = 40;
+
+/// `ThreadPool::stats` 返回的快照
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// 当前存活的 worker 数
+    pub threads: usize,
+    /// 队列中等待执行的任务数
+    pub queued: usize,
+    /// 正在执行任务的 worker 数
+    pub active: usize,
+    /// 正常执行完的任务数
+    pub completed: u64,
+    /// panic 的任务数
+    pub panicked: usize,
+    /// 因 `OverflowPolicy::DropOldest` 被丢弃的任务数
+    pub dropped: usize,
+    /// 因线程意外退出而重新创建的 worker 数
+    pub respawned: usize,
+    /// 各个存活的 worker 的统计，按 id 排序
+    pub workers: Vec<WorkerStats>,
+    /// 任务从提交到执行完的延迟
+    pub latency: Percentiles,
+}
+
+/// 单个 worker 的统计
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub id: usize,
+    /// 执行过的任务数
+    pub jobs: u64,
+    /// 执行任务花费的总时间
+    pub busy: Duration,
+}
+
+/// 延迟的分位数
+///
+/// 分位数按 2 的幂次分桶统计，取值为所在桶的上界，不会超过 max。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    /// 统计到的任务数
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// worker 自己更新的计数，线程被替换时由新线程接着使用
+#[derive(Default)]
+pub(crate) struct WorkerMetrics {
+    jobs: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl WorkerMetrics {
+    pub(crate) fn record(&self, busy: Duration) {
+        self.jobs.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, id: usize) -> WorkerStats {
+        WorkerStats {
+            id,
+            jobs: self.jobs.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 不加锁的延迟直方图
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    max_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        // 0 微秒落在第 0 个桶，[2^(i-1), 2^i) 落在第 i 个桶
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn percentiles(&self) -> Percentiles {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        let max = Duration::from_micros(self.max_micros.load(Ordering::Relaxed));
+
+        let percentile = |p: u64| {
+            if count == 0 {
+                return Duration::from_micros(0);
+            }
+            // 第 rank 个样本所在的桶
+            let rank = (count * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (i, n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Duration::from_micros(1u64 << i).min(max);
+                }
+            }
+            max
+        };
+
+        Percentiles {
+            count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_come_from_bucket_upper_bounds() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentiles(), Percentiles::default());
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(5));
+        }
+        histogram.record(Duration::from_millis(40));
+
+        let p = histogram.percentiles();
+        assert_eq!(p.count, 100);
+        // 100µs 落在 [64, 128) 的桶
+        assert_eq!(p.p50, Duration::from_micros(128));
+        assert_eq!(p.p90, Duration::from_micros(128));
+        // 5ms 落在 [4096, 8192) 的桶
+        assert_eq!(p.p99, Duration::from_micros(8192));
+        assert_eq!(p.max, Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{lock, submit, ExecuteError, Job, LogLevel, Shared, ThreadPool};
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 延迟任务或周期任务的句柄，用来取消任务
+///
+/// 丢弃句柄不会取消任务。
+pub struct ScheduledHandle {
+    task: Arc<Scheduled>,
+    state: Arc<TimerState>,
+}
+
+impl ScheduledHandle {
+    /// 取消任务，之后任务不会再开始执行，正在执行的这一次不受影响
+    pub fn cancel(&self) {
+        self.task.cancel();
+        lock(&self.state.inner)
+            .heap
+            .retain(|entry| !Arc::ptr_eq(&entry.task, &self.task));
+    }
+
+    /// 任务是否已经被取消，线程池 drop 时所有没执行的任务都会被取消
+    pub fn is_cancelled(&self) -> bool {
+        self.task.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// 一个定时任务
+struct Scheduled {
+    cancelled: AtomicBool,
+    kind: Kind,
+}
+
+impl Scheduled {
+    /// 标记为取消，一次性任务的闭包随即释放，它持有的资源不用等句柄被丢弃
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Kind::Once(f) = &self.kind {
+            drop(lock(f).take());
+        }
+    }
+}
+
+enum Kind {
+    Once(Mutex<Option<Box<dyn FnOnce() + Send + 'static>>>),
+    FixedRate {
+        f: Box<dyn Fn() + Send + Sync + 'static>,
+        period: Duration,
+    },
+}
+
+/// 定时器堆里的一项，时间早的先出堆，时间相同时按加入的顺序
+struct Entry {
+    at: Instant,
+    seq: u64,
+    task: Arc<Scheduled>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    /// BinaryHeap 是大顶堆，这里反过来比较
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+struct Inner {
+    heap: BinaryHeap<Entry>,
+    next_seq: u64,
+}
+
+/// 定时器线程和句柄共享的状态
+struct TimerState {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+impl TimerState {
+    /// 加入定时器堆，定时器已经停止时取消任务
+    fn schedule(&self, task: Arc<Scheduled>, at: Instant) -> Result<(), ExecuteError> {
+        let mut inner = lock(&self.inner);
+        if self.stopped.load(Ordering::SeqCst) {
+            task.cancel();
+            return Err(ExecuteError::ShuttingDown);
+        }
+        if task.cancelled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(Entry { at, seq, task });
+        drop(inner);
+        self.changed.notify_one();
+        Ok(())
+    }
+}
+
+/// 线程池的定时器，用一个线程等待到期的任务，到期后交给 worker 执行
+pub(crate) struct Timer {
+    state: Arc<TimerState>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Timer {
+    pub(crate) fn start(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state = Arc::new(TimerState {
+            inner: Mutex::new(Inner {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            changed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let thread = {
+            let state = Arc::clone(&state);
+            thread::Builder::new()
+                .name(format!("{}-timer", shared.config.name))
+                .spawn(move || run(&shared, &state))?
+        };
+
+        Ok(Timer {
+            state,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    fn schedule(&self, kind: Kind, at: Instant) -> Result<ScheduledHandle, ExecuteError> {
+        let task = Arc::new(Scheduled {
+            cancelled: AtomicBool::new(false),
+            kind,
+        });
+        self.state.schedule(Arc::clone(&task), at)?;
+
+        Ok(ScheduledHandle {
+            task,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    /// 取消所有还没执行的任务，并等待定时器线程退出，可以重复调用
+    pub(crate) fn stop(&self) {
+        {
+            let mut inner = lock(&self.state.inner);
+            self.state.stopped.store(true, Ordering::SeqCst);
+            for entry in inner.heap.drain() {
+                entry.task.cancel();
+            }
+        }
+        self.state.changed.notify_all();
+
+        let thread = lock(&self.thread).take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 定时器线程的主循环
+fn run(shared: &Arc<Shared>, state: &Arc<TimerState>) {
+    let mut inner = lock(&state.inner);
+
+    while !state.stopped.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        let next = inner.heap.peek().map(|entry| entry.at);
+
+        inner = match next {
+            None => state
+                .changed
+                .wait(inner)
+                .unwrap_or_else(PoisonError::into_inner),
+            Some(at) if at > now => {
+                state
+                    .changed
+                    .wait_timeout(inner, at - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+            Some(_) => {
+                let entry = inner.heap.pop().unwrap();
+                // 交给线程池时可能阻塞，不能拿着锁
+                drop(inner);
+                fire(shared, state, entry);
+                lock(&state.inner)
+            }
+        };
+    }
+}
+
+/// 把到期的任务交给线程池
+fn fire(shared: &Arc<Shared>, state: &Arc<TimerState>, entry: Entry) {
+    if entry.task.cancelled.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let task = Arc::clone(&entry.task);
+    let job = {
+        let state = Arc::clone(state);
+        let at = entry.at;
+        Job::new(move || run_scheduled(&state, &task, at))
+    };
+
+    match submit(shared, job) {
+        Ok(_) => {}
+        // 线程池正在关闭，定时器也马上会停
+        Err(ExecuteError::ShuttingDown) => {}
+        Err(e) => {
+            shared.log(
+                LogLevel::Warn,
+                format_args!("Failed to submit scheduled job: {}", e),
+            );
+            // 周期任务这一次没能执行，下一个周期照常
+            if let Kind::FixedRate { period, .. } = entry.task.kind {
+                let _ = state.schedule(entry.task, next_tick(entry.at, period));
+            }
+        }
+    }
+}
+
+/// 在 worker 上执行定时任务，周期任务执行完后安排下一次
+fn run_scheduled(state: &TimerState, task: &Arc<Scheduled>, at: Instant) {
+    // 在队列里等待期间可能被取消，或者线程池已经在关闭
+    if task.cancelled.load(Ordering::SeqCst) || state.stopped.load(Ordering::SeqCst) {
+        return;
+    }
+
+    match &task.kind {
+        Kind::Once(f) => {
+            if let Some(f) = lock(f).take() {
+                f();
+            }
+        }
+        Kind::FixedRate { f, period } => {
+            // 任务 panic 也要安排下一次，之后再把 panic 交给线程池记录
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let _ = state.schedule(Arc::clone(task), next_tick(at, *period));
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// 下一次执行的时间，执行太慢错过的周期直接跳过，不会连续补执行
+fn next_tick(at: Instant, period: Duration) -> Instant {
+    let next = at + period;
+    let now = Instant::now();
+    if next >= now {
+        return next;
+    }
+
+    let missed = (now - next).as_nanos() / period.as_nanos() + 1;
+    next + period * missed as u32
+}
+
+impl ThreadPool {
+    /// 延迟 delay 之后执行任务
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<ScheduledHandle, ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let kind = Kind::Once(Mutex::new(Some(Box::new(f))));
+        self.timer.schedule(kind, Instant::now() + delay)
+    }
+
+    /// 每隔 period 执行一次任务，第一次在 period 之后执行
+    ///
+    /// 同一个任务不会同时执行多次：上一次执行完才会安排下一次，错过的周期直接跳过。
+    /// 任务 panic 不会停止之后的执行，需要停止时通过返回的句柄取消。
+    ///
+    /// # Panics
+    ///
+    /// period 为 0 时会 panic。
+    pub fn execute_at_fixed_rate<F>(
+        &self,
+        period: Duration,
+        f: F,
+    ) -> Result<ScheduledHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(period > Duration::from_secs(0), "period must be non-zero");
+
+        let kind = Kind::FixedRate {
+            f: Box::new(f),
+            period,
+        };
+        self.timer.schedule(kind, Instant::now() + period)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Priority;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    #[test]
+    fn delayed_jobs_run_in_deadline_order() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+
+        for (delay, value) in [(60, 3), (20, 1), (40, 2)] {
+            let tx = tx.clone();
+            pool.execute_after(Duration::from_millis(delay), move || {
+                tx.send(value).unwrap()
+            })
+            .unwrap();
+        }
+
+        let order: Vec<i32> = rx.iter().take(3).collect();
+        assert_eq!(order, vec![1, 2, 3]);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_run() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        let handle = pool
+            .execute_after(Duration::from_millis(30), move || tx.send(()).unwrap())
+            .unwrap();
+        handle.cancel();
+
+        assert!(handle.is_cancelled());
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn fixed_rate_jobs_repeat_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel();
+
+        let handle = {
+            let count = Arc::clone(&count);
+            let tx = Mutex::new(tx);
+            pool.execute_at_fixed_rate(Duration::from_millis(10), move || {
+                let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                if n == 2 {
+                    panic!("still rescheduled");
+                }
+                if n == 3 {
+                    lock(&tx).send(()).unwrap();
+                }
+            })
+            .unwrap()
+        };
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.cancel();
+        let seen = count.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        // 取消时可能正好有一次在执行
+        assert!(count.load(Ordering::SeqCst) <= seen + 1);
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn dropping_the_pool_cancels_scheduled_jobs() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        let handle = pool
+            .execute_after(Duration::from_secs(60), move || tx.send(()).unwrap())
+            .unwrap();
+
+        drop(pool);
+
+        assert!(handle.is_cancelled());
+        // 任务连同 sender 一起被释放
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn high_priority_jobs_run_before_queued_normal_jobs() {
+        let pool = ThreadPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(move || wait.recv().unwrap()).unwrap();
+        for (priority, value) in [
+            (Priority::Low, "low"),
+            (Priority::Normal, "normal"),
+            (Priority::High, "high"),
+        ] {
+            let tx = tx.clone();
+            pool.execute_with_priority(priority, move || tx.send(value).unwrap())
+                .unwrap();
+        }
+        release.send(()).unwrap();
+
+        let order: Vec<&str> = rx.iter().take(3).collect();
+        assert_eq!(order, vec!["high", "normal", "low"]);
+    }
+}